@@ -0,0 +1,84 @@
+use crate::geometry::{Geometry, Ray, Intersection};
+use crate::kdtree::{KDNode, SplitMethod, build_kdtree};
+use crate::bvh::Bvh;
+
+use serde::{Serialize, Deserialize};
+
+/// A spatial index that finds what a ray hits without testing every primitive
+pub trait Accelerator {
+    /// Closest geometry hit by the ray and the distance to it
+    fn intersect(&self, ray: &Ray, geometry: &[Geometry]) -> Option<(usize, Intersection)>;
+
+    /// Whether anything is hit closer than max_dist, stops at the first blocker found
+    fn occluded(&self, ray: &Ray, geometry: &[Geometry], max_dist: f32) -> bool;
+}
+
+/// Which acceleration structure to build for a world
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum AccelMethod {
+    /// Test every primitive
+    Linear,
+    KdTree(SplitMethod),
+    Bvh
+}
+
+impl Default for AccelMethod {
+    fn default() -> Self {
+        AccelMethod::KdTree(SplitMethod::default())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Accel {
+    KdTree(KDNode),
+    Bvh(Bvh)
+}
+
+impl Accel {
+    /// Build the chosen structure over the geometry, None for a linear scan
+    pub fn build(geometry: &[Geometry], method: AccelMethod) -> Option<Self> {
+        match method {
+            AccelMethod::Linear => None,
+            AccelMethod::KdTree(split) => Some(Accel::KdTree(build_kdtree(geometry, split))),
+            AccelMethod::Bvh => Some(Accel::Bvh(Bvh::build(geometry)))
+        }
+    }
+
+    pub fn accelerator(&self) -> &dyn Accelerator {
+        match self {
+            Accel::KdTree(k) => k,
+            Accel::Bvh(b) => b
+        }
+    }
+}
+
+/// Triangles and spheres up to size across scattered through [-10, 10]^3, for
+/// checking the structures against each other
+#[cfg(test)]
+pub(crate) fn random_scene(n: usize, size: f32, seed: u64) -> Vec<Geometry> {
+    use crate::vector::Vec3;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let point = |rng: &mut StdRng, s: f32| Vec3::new(rng.gen_range(-s..s), rng.gen_range(-s..s), rng.gen_range(-s..s));
+
+    (0..n)
+        .map(|i| {
+            let c = point(&mut rng, 10.0);
+            if i % 3 == 0 {
+                Geometry::new_sphere(c, rng.gen_range(0.05..0.5) * size)
+            } else {
+                Geometry::new_triangle(c + point(&mut rng, size), c + point(&mut rng, size), c + point(&mut rng, size))
+            }
+        })
+        .collect()
+}
+
+/// Closest hit found by testing every primitive, with its distance
+#[cfg(test)]
+pub(crate) fn linear_hit(ray: &Ray, gs: &[Geometry]) -> Option<(usize, f32)> {
+    (0..gs.len())
+        .filter_map(|i| gs[i].hit(ray).map(|h| (i, h.dist)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+}
@@ -1,8 +1,13 @@
-use gi_tracer::kdtree::build_kdtree;
+use gi_tracer::accel::AccelMethod;
 use gi_tracer::world::World;
+use gi_tracer::geometry::Geometry;
 use gi_tracer::vector::Vec3;
+use gi_tracer::image::write_ppm;
 use gi_tracer::camera::Camera;
-use gi_tracer::material::{Material, Color, Light, Texture};
+use gi_tracer::integrator::Integrator;
+use gi_tracer::material::{Material, Color, Texture};
+use gi_tracer::light::Light;
+use gi_tracer::bsdf::Phong;
 use gi_tracer::ply::load_ply;
 
 use rayon::prelude::*;
@@ -21,33 +26,17 @@ const WIDTH: usize = 800;
 const HEIGHT: usize = 800;
 const SAMPLES: usize = 100;
 
-fn output_ppm(img: &Vec<Vec3<f32>>, w: usize, h: usize) {
-    // Header
-    println!("P3");
-    println!("{w} {h}");
-    println!("255");
-
-    // Now print data
-    for row in 0..h {
-        for col in 0..w {
-            let color = img[row*w+col] * 255.0;
-            println!("{} {} {}", color.x as u8, color.y as u8, color.z as u8);
-        }
-    }
-}
-
-fn tone_map(img: &mut Vec<Vec3<f32>>) {
+fn tone_map(img: &mut [Vec3<f32>]) {
     let max = img.iter()
-        .map(|v| vec![v.x, v.y, v.z])
-        .flatten()
-        .max_by(|a, b| a.partial_cmp(&b).unwrap())
+        .flat_map(|v| vec![v.x, v.y, v.z])
+        .max_by(|a, b| a.partial_cmp(b).unwrap())
         .unwrap();
 
     img.iter_mut()
         .for_each(|c| {
-            c.x = c.x / max;
-            c.y = c.y / max;
-            c.z = c.z / max;
+            c.x /= max;
+            c.y /= max;
+            c.z /= max;
         })
 }
 
@@ -63,7 +52,7 @@ fn main() {
 
     let mut world = World::new();
 
-    let mat = world.add_material(Material::Phong(
+    let mat = world.add_material(Material::Phong(Phong::new(
             Texture::Solid(Color::RGB(200, 76, 40)),
             0.7,
             0.3,
@@ -71,29 +60,37 @@ fn main() {
             0.0,
             0.0,
             0.0
-    ));
+    )));
     //let mat = world.add_material(Material::Normal);
     //let mat = world.add_material(Material::Distance);
 
-    world.lights.push(Light {
-        pos: Vec3::new(0.0, 1.0, 2.0),
-        color: Vec3::new(0.5, 0.5, 0.5)
-    });
+    world.add_light(Light::point(
+        Vec3::new(0.0, 1.0, 2.0),
+        Vec3::new(2.4, 2.4, 2.4)
+    ));
 
     // Add the bunny...
     let fpath = std::env::args().nth(1).unwrap();
     eprintln!("Reading ply file: {}", fpath);
 
 
-    for t in load_ply(&fpath) {
-        world.add_entity(t, mat)
-    }
+    let mesh = match load_ply(&fpath) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{fpath}: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    world.add_entity(Geometry::Mesh(mesh.mesh().smooth()), mat);
 
     // Let's build the KD Tree!!!!!!!!!!!
     let a = Instant::now();
-    world.kdtree = Some(build_kdtree(&world.geometry));
+    world.build_accel(AccelMethod::default());
     eprintln!("kdtree built in {} seconds", a.elapsed().as_secs_f32());
 
+    let integrator = Integrator::Whitted;
+
     let bar = Arc::new(Mutex::new(ProgressBar::new((WIDTH*HEIGHT) as u64)));
 
     let x_jitter = 1.0 / WIDTH as f32 / 2.0;
@@ -110,8 +107,8 @@ fn main() {
                 let cx = x as f32 / WIDTH as f32 + thread_rng().gen_range(-x_jitter..x_jitter);
                 let cy = 1.0 - y as f32 / HEIGHT as f32 + thread_rng().gen_range(-y_jitter..y_jitter);
 
-                let ray = cam.get_ray(cx, cy);
-                world.fire(&ray, 0)
+                let ray = cam.get_ray(cx, cy, thread_rng().gen(), thread_rng().gen());
+                integrator.radiance(&world, &ray, &mut thread_rng())
             }).sum::<Vec3<f32>>() / SAMPLES as f32;
 
             if i % 10 == 0 {
@@ -122,6 +119,6 @@ fn main() {
     bar.lock().unwrap().finish();
 
     tone_map(&mut img);
-    output_ppm(&img, WIDTH, HEIGHT);
+    write_ppm(&mut std::io::stdout().lock(), &img, WIDTH, HEIGHT).unwrap();
 }
 
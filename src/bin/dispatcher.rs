@@ -1,8 +1,13 @@
 use gi_tracer::world::World;
+use gi_tracer::geometry::Geometry;
 use gi_tracer::vector::Vec3;
+use gi_tracer::image::write_ppm;
 use gi_tracer::camera::Camera;
-use gi_tracer::material::{Material, Color, Light, Texture};
+use gi_tracer::material::{Material, Color, Texture};
+use gi_tracer::light::Light;
+use gi_tracer::bsdf::Phong;
 use gi_tracer::job::RenderJob;
+use gi_tracer::integrator::Integrator;
 use gi_tracer::ply::load_ply;
 
 use std::net::TcpStream;
@@ -16,7 +21,7 @@ const WIDTH: usize = 800;
 const HEIGHT: usize = 800;
 const SAMPLES: usize = 100;
 
-const CLIENTS: [&'static str; 5] = [
+const CLIENTS: [&str; 5] = [
     "glados.cs.rit.edu:8000",
     "silver.cs.rit.edu:8000",
     "queeg.cs.rit.edu:8000",
@@ -24,33 +29,17 @@ const CLIENTS: [&'static str; 5] = [
     "argon.cs.rit.edu:8000"
 ];
 
-fn output_ppm(img: &Vec<Vec3<f32>>, w: usize, h: usize) {
-    // Header
-    println!("P3");
-    println!("{w} {h}");
-    println!("255");
-
-    // Now print data
-    for row in 0..h {
-        for col in 0..w {
-            let color = img[row*w+col] * 255.0;
-            println!("{} {} {}", color.x as u8, color.y as u8, color.z as u8);
-        }
-    }
-}
-
-fn tone_map(img: &mut Vec<Vec3<f32>>) {
+fn tone_map(img: &mut [Vec3<f32>]) {
     let max = img.iter()
-        .map(|v| vec![v.x, v.y, v.z])
-        .flatten()
-        .max_by(|a, b| a.partial_cmp(&b).unwrap())
+        .flat_map(|v| vec![v.x, v.y, v.z])
+        .max_by(|a, b| a.partial_cmp(b).unwrap())
         .unwrap();
 
     img.iter_mut()
         .for_each(|c| {
-            c.x = c.x / max;
-            c.y = c.y / max;
-            c.z = c.z / max;
+            c.x /= max;
+            c.y /= max;
+            c.z /= max;
         })
 }
 
@@ -67,16 +56,15 @@ fn main() {
 
     let mut world = World::new();
 
-    let mat = world.add_material(Material::Phong(Texture::Solid(Color::RGB(200, 76, 40)), 0.6, 0.3, 5.1, 0.0, 0.0, 0.0));
+    let mat = world.add_material(Material::Phong(Phong::new(Texture::Solid(Color::RGB(200, 76, 40)), 0.6, 0.3, 5.1, 0.0, 0.0, 0.0)));
 
-    world.lights.push(Light {
-        pos: Vec3::new(0.0, 1.0, 2.0),
-        color: Vec3::new(0.5, 0.5, 0.5)
-    });
+    world.add_light(Light::point(
+        Vec3::new(0.0, 1.0, 2.0),
+        Vec3::new(2.4, 2.4, 2.4)
+    ));
 
-    for t in load_ply("./bunny/reconstruction/bun_zipper.ply") {
-        world.add_entity(t, mat);
-    }
+    let mesh = load_ply("./bunny/reconstruction/bun_zipper.ply").unwrap().mesh().smooth();
+    world.add_entity(Geometry::Mesh(mesh), mat);
 
     let world = Arc::new(world);
 
@@ -90,6 +78,7 @@ fn main() {
             img_width: WIDTH,
             img_height: HEIGHT,
             num_samples: SAMPLES,
+            integrator: Integrator::Whitted,
             start: i*WIDTH*HEIGHT/CLIENTS.len(),
             count: WIDTH*HEIGHT/CLIENTS.len(),
         });
@@ -109,7 +98,7 @@ fn main() {
                 let serjob = to_vec(&jobs_c[i], Endian::Big).unwrap();
 
                 // Write number of bytes in job
-                stream.write(&usize::to_be_bytes(serjob.len())).unwrap();
+                stream.write_all(&usize::to_be_bytes(serjob.len())).unwrap();
 
                 // Send render job
                 stream.write_all(&to_vec(&jobs_c[i], Endian::Big).unwrap()).unwrap();
@@ -140,5 +129,5 @@ fn main() {
     });
 
     tone_map(&mut img);
-    output_ppm(&img, WIDTH, HEIGHT);
+    write_ppm(&mut std::io::stdout().lock(), &img, WIDTH, HEIGHT).unwrap();
 }
@@ -0,0 +1,67 @@
+use gi_tracer::tone_map::{tone_map, Algorithm};
+use gi_tracer::image::{self, Format};
+
+use clap::{Parser, ValueEnum};
+
+use std::path::PathBuf;
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum ToneMapArg {
+    Ward,
+    Reinhard,
+    Alm
+}
+
+/// Tone map a high dynamic range render (hdr, pfm, or exr) into a displayable image
+#[derive(Parser)]
+struct Args {
+    /// Linear image to read
+    input: PathBuf,
+
+    /// Where to write the tone mapped image, format is taken from the extension
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Tone mapping operator
+    #[arg(short, long, value_enum, default_value = "ward")]
+    tone_map: ToneMapArg,
+
+    /// Bias for the adaptive logarithmic mapping, 0.85 if not given
+    #[arg(long)]
+    bias: Option<f32>,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    if args.bias.is_some() && !matches!(args.tone_map, ToneMapArg::Alm) {
+        eprintln!("--bias only applies to the alm tone map");
+        std::process::exit(1);
+    }
+
+    let mut img = match image::load(&args.input) {
+        Ok(img) => img,
+        Err(e) => {
+            eprintln!("{}: {e}", args.input.display());
+            std::process::exit(1);
+        }
+    };
+
+    let format = Format::from_path(&args.output).unwrap_or(Format::Ppm);
+
+    // Writing another hdr format just converts between them
+    if !format.is_hdr() {
+        let algo = match args.tone_map {
+            ToneMapArg::Ward => Algorithm::Ward,
+            ToneMapArg::Reinhard => Algorithm::Reinhard,
+            ToneMapArg::Alm => Algorithm::ALM(args.bias.unwrap_or(0.85))
+        };
+
+        tone_map(&mut img.pixels, algo);
+    }
+
+    if let Err(e) = image::save(&args.output, &img.pixels, img.width, img.height, format) {
+        eprintln!("failed to write image: {e}");
+        std::process::exit(1);
+    }
+}
@@ -49,15 +49,15 @@ fn main() {
                     let cx = x as f32 / job.img_width as f32 + thread_rng().gen_range(-x_jitter..x_jitter);
                     let cy = 1.0 - y as f32 / job.img_height as f32 + thread_rng().gen_range(-y_jitter..y_jitter);
 
-                    let ray = job.camera.get_ray(cx, cy);
-                    job.world.fire(&ray, 0)
+                    let ray = job.camera.get_ray(cx, cy, thread_rng().gen(), thread_rng().gen());
+                    job.integrator.radiance(&job.world, &ray, &mut thread_rng())
                 }).sum::<Vec3<f32>>() / job.num_samples as f32;
             });
         
         eprintln!("Writing result!");
 
         let payload = to_vec(&img, Endian::Big).unwrap();
-        stream.write(&usize::to_be_bytes(payload.len())).unwrap();
+        stream.write_all(&usize::to_be_bytes(payload.len())).unwrap();
 
         stream.write_all(&payload).unwrap();
         eprintln!("Finished");
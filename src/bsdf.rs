@@ -0,0 +1,456 @@
+use crate::vector::Vec3;
+use crate::geometry::HitRecord;
+use crate::material::Texture;
+use crate::sampling;
+
+use serde::{Serialize, Deserialize};
+
+use std::f32::consts::PI;
+
+/// A direction sampled from a bsdf
+#[derive(Debug, Copy, Clone)]
+pub struct BsdfSample {
+    pub dir: Vec3<f32>,
+    pub weight: Vec3<f32>,  // f * cos / pdf
+    pub pdf: f32,
+    pub specular: bool,
+    pub inside: bool
+}
+
+/// Describes how a surface scatters light, wo and wi both point away from the surface
+pub trait Bsdf {
+    /// Light leaving the surface on its own
+    fn emitted(&self, _hit: &HitRecord) -> Vec3<f32> {
+        Vec3::new(0.0, 0.0, 0.0)
+    }
+
+    /// Base color of the surface, used for the ambient term
+    fn albedo(&self, _hit: &HitRecord) -> Vec3<f32> {
+        Vec3::new(0.0, 0.0, 0.0)
+    }
+
+    /// Value of the non specular part of the bsdf, surfaces that don't
+    /// scatter light only need emitted
+    fn evaluate(&self, _hit: &HitRecord, _wo: &Vec3<f32>, _wi: &Vec3<f32>) -> Vec3<f32> {
+        Vec3::new(0.0, 0.0, 0.0)
+    }
+
+    /// Sample an incoming direction given three uniform random numbers
+    fn sample(&self, _hit: &HitRecord, _wo: &Vec3<f32>, _u: [f32; 3]) -> Option<BsdfSample> {
+        None
+    }
+
+    /// Solid angle density with which sample returns wi, ignoring specular lobes
+    fn pdf(&self, _hit: &HitRecord, _wo: &Vec3<f32>, _wi: &Vec3<f32>) -> f32 {
+        0.0
+    }
+
+    /// Every perfectly specular direction leaving along wo, with its weight
+    fn specular(&self, _hit: &HitRecord, _wo: &Vec3<f32>) -> Vec<BsdfSample> {
+        Vec::new()
+    }
+}
+
+/// Finish a sample from one of the non specular lobes of a bsdf
+fn glossy_sample<B: Bsdf + ?Sized>(bsdf: &B, hit: &HitRecord, wo: &Vec3<f32>, dir: Vec3<f32>) -> Option<BsdfSample> {
+    let cos_i = hit.n.dot(&dir);
+    if cos_i <= 0.0 {
+        return None;
+    }
+
+    let pdf = bsdf.pdf(hit, wo, &dir);
+    if pdf <= 0.0 {
+        return None;
+    }
+
+    Some(BsdfSample {
+        dir,
+        weight: bsdf.evaluate(hit, wo, &dir) * (cos_i / pdf),
+        pdf,
+        specular: false,
+        inside: hit.inside
+    })
+}
+
+/// Colors the surface by its normal
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Normal;
+
+impl Bsdf for Normal {
+    fn emitted(&self, hit: &HitRecord) -> Vec3<f32> {
+        hit.n
+    }
+}
+
+/// Colors the surface by its uv coordinates, red for u and green for v,
+/// wrapped to [0, 1) so unbounded surfaces show repeating tiles
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Uv;
+
+impl Bsdf for Uv {
+    fn emitted(&self, hit: &HitRecord) -> Vec3<f32> {
+        Vec3::new(hit.uv.0.rem_euclid(1.0), hit.uv.1.rem_euclid(1.0), 0.0)
+    }
+}
+
+/// Colors the surface by its distance from the ray origin
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Distance;
+
+impl Bsdf for Distance {
+    fn emitted(&self, hit: &HitRecord) -> Vec3<f32> {
+        Vec3::new(hit.dist*10.0, 0.0, 0.0)
+    }
+}
+
+/// Pure emitter of radiance, attaching it to geometry turns that geometry into a light
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Emissive {
+    pub color: Vec3<f32>
+}
+
+impl Emissive {
+    pub fn new(color: Vec3<f32>) -> Self {
+        Self { color }
+    }
+}
+
+impl Bsdf for Emissive {
+    fn emitted(&self, _hit: &HitRecord) -> Vec3<f32> {
+        self.color
+    }
+}
+
+/// Normalized phong with perfect mirror reflection and transmission
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Phong {
+    pub texture: Texture,
+    pub kd: f32,
+    pub ks: f32,
+    pub ke: f32,
+    pub kr: f32,
+    pub kt: f32,
+    pub eta: f32
+}
+
+impl Phong {
+    pub fn new(texture: Texture, kd: f32, ks: f32, ke: f32, kr: f32, kt: f32, eta: f32) -> Self {
+        Self { texture, kd, ks, ke, kr, kt, eta }
+    }
+
+    /// Lobe selection weights: diffuse, glossy, mirror, transmission
+    fn lobes(&self, hit: &HitRecord) -> [f32; 4] {
+        let ws = [self.kd * self.texture.get_color(hit).max_component(), self.ks, self.kr, self.kt];
+        let total: f32 = ws.iter().sum();
+
+        if total <= 0.0 {
+            [0.0; 4]
+        } else {
+            ws.map(|w| w / total)
+        }
+    }
+
+    fn mirror(&self, hit: &HitRecord, wo: &Vec3<f32>, pdf: f32) -> BsdfSample {
+        BsdfSample {
+            dir: (-*wo).reflect(&hit.n),
+            weight: Vec3::new(1.0, 1.0, 1.0) * (self.kr / pdf),
+            pdf,
+            specular: true,
+            inside: hit.inside
+        }
+    }
+
+    fn transmit(&self, hit: &HitRecord, wo: &Vec3<f32>, pdf: f32) -> BsdfSample {
+        let (ni, nt) = if hit.inside { (self.eta, 1.0) } else { (1.0, self.eta) };
+        let nit = ni / nt;
+
+        let cos_i = wo.dot(&hit.n);
+        let determ = 1.0 - nit.powi(2) * (1.0 - cos_i.powi(2));
+        let weight = Vec3::new(1.0, 1.0, 1.0) * (self.kt / pdf);
+
+        // Total internal reflection
+        if determ < 0.0 {
+            return BsdfSample {
+                dir: (-*wo).reflect(&hit.n),
+                weight,
+                pdf,
+                specular: true,
+                inside: hit.inside
+            };
+        }
+
+        BsdfSample {
+            dir: (-*wo * nit + hit.n * (nit * cos_i - determ.sqrt())).normalized(),
+            weight,
+            pdf,
+            specular: true,
+            inside: !hit.inside
+        }
+    }
+}
+
+impl Bsdf for Phong {
+    fn albedo(&self, hit: &HitRecord) -> Vec3<f32> {
+        self.texture.get_color(hit)
+    }
+
+    fn evaluate(&self, hit: &HitRecord, wo: &Vec3<f32>, wi: &Vec3<f32>) -> Vec3<f32> {
+        if hit.n.dot(wi) <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+
+        let r = (-*wo).reflect(&hit.n);
+        let spec = r.dot(wi).max(0.0).powf(self.ke);
+
+        self.texture.get_color(hit) * (self.kd / PI)
+            + Vec3::new(1.0, 1.0, 1.0) * (self.ks * (self.ke + 2.0) / (2.0 * PI) * spec)
+    }
+
+    fn sample(&self, hit: &HitRecord, wo: &Vec3<f32>, u: [f32; 3]) -> Option<BsdfSample> {
+        let [pd, ps, pr, pt] = self.lobes(hit);
+
+        let dir = if u[0] < pd {
+            sampling::to_world(&sampling::cosine_hemisphere(u[1], u[2]), &hit.n)
+        } else if u[0] < pd + ps {
+            let r = (-*wo).reflect(&hit.n);
+            sampling::to_world(&sampling::phong_lobe(u[1], u[2], self.ke), &r)
+        } else if u[0] < pd + ps + pr {
+            return Some(self.mirror(hit, wo, pr));
+        } else if pt > 0.0 {
+            return Some(self.transmit(hit, wo, pt));
+        } else {
+            return None;
+        };
+
+        glossy_sample(self, hit, wo, dir)
+    }
+
+    fn pdf(&self, hit: &HitRecord, wo: &Vec3<f32>, wi: &Vec3<f32>) -> f32 {
+        let cos_i = hit.n.dot(wi);
+        if cos_i <= 0.0 {
+            return 0.0;
+        }
+
+        let [pd, ps, ..] = self.lobes(hit);
+        let r = (-*wo).reflect(&hit.n);
+
+        pd * sampling::cosine_hemisphere_pdf(cos_i) + ps * sampling::phong_lobe_pdf(r.dot(wi), self.ke)
+    }
+
+    fn specular(&self, hit: &HitRecord, wo: &Vec3<f32>) -> Vec<BsdfSample> {
+        let mut res = Vec::new();
+
+        if self.kr > 0.0 {
+            res.push(self.mirror(hit, wo, 1.0));
+        }
+
+        if self.kt > 0.0 {
+            res.push(self.transmit(hit, wo, 1.0));
+        }
+
+        res
+    }
+}
+
+/// Cook torrance microfacet specular over a lambertian base, k is the diffuse fraction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CookTorrance {
+    pub texture: Texture,
+    pub f0: f32,
+    pub roughness: f32,
+    pub k: f32
+}
+
+impl CookTorrance {
+    pub fn new(texture: Texture, f0: f32, roughness: f32, k: f32) -> Self {
+        Self { texture, f0, roughness, k }
+    }
+
+    /// Lobe selection weights: diffuse, specular
+    fn lobes(&self, hit: &HitRecord) -> (f32, f32) {
+        let pd = self.k * self.texture.get_color(hit).max_component();
+        let ps = 1.0 - self.k;
+
+        if pd + ps <= 0.0 {
+            (0.0, 0.0)
+        } else {
+            (pd / (pd + ps), ps / (pd + ps))
+        }
+    }
+
+    /// Specular term, returns (brdf, pdf of sampling wi through the half vector)
+    fn specular_term(&self, n: &Vec3<f32>, wo: &Vec3<f32>, wi: &Vec3<f32>) -> (f32, f32) {
+        let h = (*wo + *wi).normalized();
+        let n_dot_h = n.dot(&h).max(0.0);
+        let n_dot_v = n.dot(wo).max(0.0);
+        let n_dot_l = n.dot(wi).max(0.0);
+        let v_dot_h = wo.dot(&h).max(0.0);
+
+        if n_dot_v <= 0.0 || n_dot_l <= 0.0 || v_dot_h <= 0.0 {
+            return (0.0, 0.0);
+        }
+
+        // Fresnel reflectance
+        let f = (1.0-v_dot_h).powi(5) * (1.0 - self.f0) + self.f0;
+
+        // microfacet distribution by beckman
+        let d = sampling::beckmann_d(n_dot_h, self.roughness);
+
+        // geometric shadowing
+        let two_n_dot_h = 2.0 * n_dot_h;
+        let g1 = (two_n_dot_h * n_dot_v) / v_dot_h;
+        let g2 = (two_n_dot_h * n_dot_l) / v_dot_h;
+        let g = 1.0f32.min(g1).min(g2);
+
+        (
+            (f*d*g) / (4.0*n_dot_v*n_dot_l),
+            d * n_dot_h / (4.0 * v_dot_h)
+        )
+    }
+}
+
+impl Bsdf for CookTorrance {
+    fn albedo(&self, hit: &HitRecord) -> Vec3<f32> {
+        self.texture.get_color(hit)
+    }
+
+    fn evaluate(&self, hit: &HitRecord, wo: &Vec3<f32>, wi: &Vec3<f32>) -> Vec3<f32> {
+        if hit.n.dot(wi) <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+
+        let (spec, _) = self.specular_term(&hit.n, wo, wi);
+
+        self.texture.get_color(hit) * (self.k / PI) + Vec3::new(1.0, 1.0, 1.0) * (spec * (1.0 - self.k))
+    }
+
+    fn sample(&self, hit: &HitRecord, wo: &Vec3<f32>, u: [f32; 3]) -> Option<BsdfSample> {
+        let (pd, _) = self.lobes(hit);
+
+        let dir = if u[0] < pd {
+            sampling::to_world(&sampling::cosine_hemisphere(u[1], u[2]), &hit.n)
+        } else {
+            let h = sampling::to_world(&sampling::beckmann(u[1], u[2], self.roughness), &hit.n);
+            (-*wo).reflect(&h)
+        };
+
+        glossy_sample(self, hit, wo, dir)
+    }
+
+    fn pdf(&self, hit: &HitRecord, wo: &Vec3<f32>, wi: &Vec3<f32>) -> f32 {
+        let cos_i = hit.n.dot(wi);
+        if cos_i <= 0.0 {
+            return 0.0;
+        }
+
+        let (pd, ps) = self.lobes(hit);
+        let (_, spec_pdf) = self.specular_term(&hit.n, wo, wi);
+
+        pd * sampling::cosine_hemisphere_pdf(cos_i) + ps * spec_pdf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn white() -> Texture {
+        Texture::Solid(Vec3::new(1.0, 1.0, 1.0))
+    }
+
+    fn hit() -> HitRecord {
+        HitRecord::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0), 1.0)
+    }
+
+    fn wo() -> Vec3<f32> {
+        Vec3::new(0.4, 0.1, 1.0).normalized()
+    }
+
+    /// Mean of f over many samples of the bsdf
+    fn mean_over_samples(bsdf: &dyn Bsdf, f: impl Fn(&BsdfSample) -> f32) -> f32 {
+        let mut rng = StdRng::seed_from_u64(7);
+        let n = 200_000;
+
+        (0..n)
+            .filter_map(|_| bsdf.sample(&hit(), &wo(), [rng.gen(), rng.gen(), rng.gen()]))
+            .map(|s| f(&s))
+            .sum::<f32>() / n as f32
+    }
+
+    #[test]
+    fn lambertian_weight_is_its_albedo() {
+        let bsdf = Phong::new(Texture::Solid(Vec3::new(0.5, 0.25, 1.0)), 0.6, 0.0, 1.0, 0.0, 0.0, 1.0);
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for _ in 0..100 {
+            let s = bsdf.sample(&hit(), &wo(), [rng.gen(), rng.gen(), rng.gen()]).unwrap();
+            assert!(!s.specular && s.dir.z > 0.0);
+            assert!((s.weight - Vec3::new(0.3, 0.15, 0.6)).mag() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn sampling_covers_the_hemisphere_with_the_stated_pdf() {
+        // Averaging 1 / pdf over samples estimates the solid angle sampled, 2pi
+        let bsdfs: [&dyn Bsdf; 2] = [
+            &Phong::new(white(), 0.5, 0.5, 20.0, 0.0, 0.0, 1.0),
+            &CookTorrance::new(white(), 0.04, 0.3, 0.5)
+        ];
+
+        for bsdf in bsdfs {
+            let solid_angle = mean_over_samples(bsdf, |s| 1.0 / s.pdf);
+            assert!((solid_angle - 2.0 * PI).abs() < 0.05 * 2.0 * PI, "{solid_angle}");
+        }
+    }
+
+    #[test]
+    fn surfaces_do_not_create_energy() {
+        let bsdfs: [&dyn Bsdf; 3] = [
+            &Phong::new(white(), 0.5, 0.5, 20.0, 0.0, 0.0, 1.0),
+            &CookTorrance::new(white(), 0.9, 0.2, 0.0),
+            &CookTorrance::new(white(), 0.04, 0.5, 0.8)
+        ];
+
+        for bsdf in bsdfs {
+            let albedo = mean_over_samples(bsdf, |s| s.weight.max_component());
+            assert!(albedo > 0.0 && albedo <= 1.01, "{albedo}");
+        }
+    }
+
+    #[test]
+    fn backfacing_directions_are_black() {
+        let bsdf = CookTorrance::new(white(), 0.04, 0.3, 0.5);
+        let below = Vec3::new(0.3, 0.0, -1.0).normalized();
+
+        assert!(bsdf.evaluate(&hit(), &wo(), &below).is_black());
+        assert_eq!(bsdf.pdf(&hit(), &wo(), &below), 0.0);
+    }
+
+    #[test]
+    fn mirror_reflects_about_the_normal() {
+        let bsdf = Phong::new(white(), 0.0, 0.0, 1.0, 1.0, 0.0, 1.0);
+        let s = bsdf.specular(&hit(), &wo());
+
+        assert_eq!(s.len(), 1);
+        let wo = wo();
+        assert!((s[0].dir - Vec3::new(-wo.x, -wo.y, wo.z)).mag() < 1e-5);
+        assert!((s[0].weight - Vec3::new(1.0, 1.0, 1.0)).mag() < 1e-6);
+    }
+
+    #[test]
+    fn refraction_follows_snells_law() {
+        let bsdf = Phong::new(white(), 0.0, 0.0, 1.0, 0.0, 1.0, 1.5);
+        let s = bsdf.specular(&hit(), &wo());
+
+        assert_eq!(s.len(), 1);
+        let t = s[0];
+        let sin_i = (1.0 - wo().z * wo().z).sqrt();
+        let sin_t = (1.0 - t.dir.z * t.dir.z).sqrt();
+
+        assert!(t.dir.z < 0.0 && t.inside);
+        assert!((sin_i - 1.5 * sin_t).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,299 @@
+use crate::geometry::{Geometry, AABB, Axis, Ray, Intersection};
+use crate::accel::Accelerator;
+use crate::vector::Vec3;
+
+use serde::{Serialize, Deserialize};
+
+const BINS: usize = 12;
+const MAX_LEAF: usize = 4;
+const TRAVERSAL_COST: f32 = 0.125;
+const INTERSECT_COST: f32 = 1.0;
+
+/// A node in the flattened tree, the first child of a branch directly follows it
+#[derive(Debug, Serialize, Deserialize)]
+struct BvhNode {
+    aabb: AABB,
+    /// Index of the second child for branches, of the first primitive for leaves
+    offset: usize,
+    /// Number of primitives in a leaf, 0 for branches
+    count: usize,
+    axis: Axis
+}
+
+/// Bounding volume hierarchy built with binned surface area heuristic splits
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    /// Geometry ids ordered so every leaf refers to a contiguous run
+    prims: Vec<usize>
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Bin {
+    aabb: Option<AABB>,
+    count: usize
+}
+
+fn union(a: Option<AABB>, b: AABB) -> AABB {
+    a.map_or(b, |a| a.union(b))
+}
+
+fn surface_area(a: Option<AABB>) -> f32 {
+    a.map_or(0.0, |a| a.surface_area())
+}
+
+impl Bvh {
+    /// Build over the bounded geometry, unbounded geometry is tested separately by the world
+    pub fn build(g: &[Geometry]) -> Self {
+        let bounds: Vec<AABB> = g.iter().map(Geometry::fit).collect();
+        Self::from_prims(&bounds, (0..g.len()).filter(|&i| g[i].is_bounded()).collect())
+    }
+
+    /// Build over any primitives given their bounding boxes, ids index into bounds
+    pub fn from_bounds(bounds: &[AABB]) -> Self {
+        Self::from_prims(bounds, (0..bounds.len()).collect())
+    }
+
+    fn from_prims(bounds: &[AABB], prims: Vec<usize>) -> Self {
+        let centroids: Vec<Vec3<f32>> = bounds.iter().map(|b| (b.min + b.max) * 0.5).collect();
+
+        let n = prims.len();
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * n),
+            prims
+        };
+
+        if n > 0 {
+            bvh.build_h(0, n, bounds, &centroids);
+        }
+
+        bvh
+    }
+
+    /// Build the node covering prims[start..end], returning its index
+    fn build_h(&mut self, start: usize, end: usize, bounds: &[AABB], centroids: &[Vec3<f32>]) -> usize {
+        let prims = &mut self.prims[start..end];
+
+        let aabb = prims.iter().skip(1).fold(bounds[prims[0]], |a, &i| a.union(bounds[i]));
+        let cbounds = prims.iter().fold(
+            AABB { min: centroids[prims[0]], max: centroids[prims[0]] },
+            |a, &i| a.union(AABB { min: centroids[i], max: centroids[i] })
+        );
+
+        let id = self.nodes.len();
+        self.nodes.push(BvhNode { aabb, offset: start, count: prims.len(), axis: Axis::X });
+
+        if prims.len() == 1 {
+            return id;
+        }
+
+        // Sort the centroids into bins along each axis and sweep for the cheapest split
+        let mut best: Option<(f32, Axis, usize)> = None;
+        for axis in Axis::ALL {
+            let (lo, hi) = (axis.of(&cbounds.min), axis.of(&cbounds.max));
+            if hi <= lo {
+                continue;
+            }
+
+            let bin = |i: usize| (((axis.of(&centroids[i]) - lo) / (hi - lo) * BINS as f32) as usize).min(BINS-1);
+
+            let mut bins = [Bin { aabb: None, count: 0 }; BINS];
+            for &i in prims.iter() {
+                let b = &mut bins[bin(i)];
+                b.aabb = Some(union(b.aabb, bounds[i]));
+                b.count += 1;
+            }
+
+            // Area and count of everything right of each boundary
+            let mut right = [(0.0, 0); BINS];
+            let mut acc = (None, 0);
+            for b in (1..BINS).rev() {
+                if let Some(a) = bins[b].aabb {
+                    acc.0 = Some(union(acc.0, a));
+                }
+                acc.1 += bins[b].count;
+                right[b] = (surface_area(acc.0), acc.1);
+            }
+
+            let mut left = (None, 0);
+            for b in 1..BINS {
+                if let Some(a) = bins[b-1].aabb {
+                    left.0 = Some(union(left.0, a));
+                }
+                left.1 += bins[b-1].count;
+
+                if left.1 == 0 || right[b].1 == 0 {
+                    continue;
+                }
+
+                let cost = surface_area(left.0) * left.1 as f32 + right[b].0 * right[b].1 as f32;
+                if best.is_none_or(|(c, _, _)| cost < c) {
+                    best = Some((cost, axis, b));
+                }
+            }
+        }
+
+        let (cost, axis, split) = match best {
+            Some(b) => b,
+            // Every centroid is in the same place, nothing to split on
+            None => return id
+        };
+
+        let cost = TRAVERSAL_COST + INTERSECT_COST * cost / aabb.surface_area();
+        let leaf_cost = INTERSECT_COST * prims.len() as f32;
+        if prims.len() <= MAX_LEAF && cost >= leaf_cost {
+            return id;
+        }
+
+        let (lo, hi) = (axis.of(&cbounds.min), axis.of(&cbounds.max));
+        let bin = |i: usize| (((axis.of(&centroids[i]) - lo) / (hi - lo) * BINS as f32) as usize).min(BINS-1);
+
+        // Partition the primitives in place around the split bin
+        let mut mid = 0;
+        for j in 0..prims.len() {
+            if bin(prims[j]) < split {
+                prims.swap(j, mid);
+                mid += 1;
+            }
+        }
+
+        self.build_h(start, start+mid, bounds, centroids);
+        let second = self.build_h(start+mid, end, bounds, centroids);
+
+        let node = &mut self.nodes[id];
+        node.offset = second;
+        node.count = 0;
+        node.axis = axis;
+
+        id
+    }
+}
+
+impl Accelerator for Bvh {
+    fn intersect(&self, ray: &Ray, gs: &[Geometry]) -> Option<(usize, Intersection)> {
+        self.traverse(ray, f32::INFINITY, false, |i| gs[i].hit(ray))
+    }
+
+    fn occluded(&self, ray: &Ray, gs: &[Geometry], max_dist: f32) -> bool {
+        self.traverse(ray, max_dist, true, |i| gs[i].hit(ray)).is_some()
+    }
+}
+
+impl Bvh {
+    /// Closest hit before max_dist, or with any set the first one found
+    pub fn traverse<F>(&self, ray: &Ray, max_dist: f32, any: bool, hit: F) -> Option<(usize, Intersection)>
+    where
+        F: Fn(usize) -> Option<Intersection>
+    {
+        let mut closest: Option<(usize, Intersection)> = None;
+
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut stack = vec![0];
+        while let Some(id) = stack.pop() {
+            let node = &self.nodes[id];
+
+            // Skip boxes behind the ray or beyond the closest hit so far
+            match node.aabb.range(ray) {
+                Some((tmin, tmax)) if tmax >= 0.0 && tmin < closest.map_or(max_dist, |(_, h)| h.dist) => (),
+                _ => continue
+            }
+
+            if node.count > 0 {
+                for &i in &self.prims[node.offset..node.offset+node.count] {
+                    if let Some(h) = hit(i) {
+                        if h.dist < max_dist && closest.is_none_or(|(_, c)| h.dist < c.dist) {
+                            if any {
+                                return Some((i, h));
+                            }
+                            closest = Some((i, h));
+                        }
+                    }
+                }
+            } else if node.axis.of(&ray.dir) < 0.0 {
+                // Visit the child nearer to the ray first
+                stack.push(id + 1);
+                stack.push(node.offset);
+            } else {
+                stack.push(node.offset);
+                stack.push(id + 1);
+            }
+        }
+
+        closest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accel::{random_scene, linear_hit};
+
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn contains(outer: &AABB, inner: &AABB) -> bool {
+        Axis::ALL.iter().all(|&a| a.of(&outer.min) <= a.of(&inner.min) && a.of(&outer.max) >= a.of(&inner.max))
+    }
+
+    #[test]
+    fn nodes_bound_their_children() {
+        let gs = random_scene(300, 1.0, 3);
+        let bvh = Bvh::build(&gs);
+
+        let mut prims = bvh.prims.clone();
+        prims.sort_unstable();
+        assert_eq!(prims, (0..gs.len()).collect::<Vec<_>>());
+
+        for (id, node) in bvh.nodes.iter().enumerate() {
+            if node.count > 0 {
+                assert!(node.count <= MAX_LEAF);
+                for &i in &bvh.prims[node.offset..node.offset+node.count] {
+                    assert!(contains(&node.aabb, &gs[i].fit()));
+                }
+            } else {
+                assert!(contains(&node.aabb, &bvh.nodes[id+1].aabb));
+                assert!(contains(&node.aabb, &bvh.nodes[node.offset].aabb));
+            }
+        }
+    }
+
+    #[test]
+    fn finds_the_same_hits_as_a_linear_scan() {
+        let gs = random_scene(300, 1.0, 4);
+        let bvh = Bvh::build(&gs);
+        let mut rng = StdRng::seed_from_u64(5);
+
+        for _ in 0..2000 {
+            let origin = Vec3::new(rng.gen_range(-15.0..15.0), rng.gen_range(-15.0..15.0), rng.gen_range(-15.0..15.0));
+            let dir = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+            let ray = Ray::new(origin, dir);
+
+            assert_eq!(bvh.intersect(&ray, &gs).map(|(i, h)| (i, h.dist)), linear_hit(&ray, &gs));
+        }
+    }
+
+    #[test]
+    fn keeps_primitives_at_one_point_together() {
+        let gs: Vec<Geometry> = (0..10).map(|i| Geometry::new_sphere(Vec3::new(0.0, 0.0, 0.0), 1.0 + i as f32)).collect();
+        let bvh = Bvh::build(&gs);
+
+        assert_eq!(bvh.nodes.len(), 1);
+        assert_eq!(bvh.nodes[0].count, 10);
+
+        // From outside the ray meets the largest of the nested spheres first
+        let ray = Ray::new(Vec3::new(0.0, 0.0, -20.0), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(bvh.intersect(&ray, &gs).map(|(i, _)| i), Some(9));
+    }
+
+    #[test]
+    fn empty_trees_hit_nothing() {
+        let bvh = Bvh::from_bounds(&[]);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0));
+
+        assert!(bvh.nodes.is_empty());
+        assert!(bvh.traverse(&ray, f32::INFINITY, false, |_| panic!("no primitives to test")).is_none());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::geometry::Ray;
 use crate::vector::Vec3;
+use crate::sampling;
 
 use serde::{Serialize, Deserialize};
 
@@ -8,7 +9,16 @@ pub struct Camera {
     origin: Vec3<f32>,
     lower_left_corner: Vec3<f32>,
     horizontal: Vec3<f32>,
-    vertical: Vec3<f32>
+    vertical: Vec3<f32>,
+    /// Unit vectors across and up the lens
+    lens_u: Vec3<f32>,
+    lens_v: Vec3<f32>,
+    /// Distance from the origin to the image plane
+    focal_length: f32,
+    /// Radius of the lens, zero for a pinhole
+    aperture: f32,
+    /// Sides of the aperture's polygonal opening, fewer than 3 for a round one
+    blades: u32
 }
 
 impl Camera {
@@ -25,8 +35,6 @@ impl Camera {
         let width = 2.0*w;
         let height = width / aspect_ratio;
 
-        eprintln!("w: {width} h: {height}");
-
         let n = (look_from-look_at).normalized();
         let u = up.cross(&n).normalized();
         let v = n.cross(&u).normalized();
@@ -35,20 +43,105 @@ impl Camera {
         let vertical = v * focal_length * height;
         let lower_left_corner = look_from - horizontal/2.0 - vertical/2.0 - n*focal_length;
 
-        eprintln!("h: {} h: {}", horizontal.mag(), vertical.mag());
-
         Self {
             origin: look_from,
             horizontal,
             vertical,
-            lower_left_corner
+            lower_left_corner,
+            lens_u: -u,
+            lens_v: v,
+            focal_length,
+            aperture: 0.0,
+            blades: 0
         }
     }
 
-    pub fn get_ray(&self, x: f32, y: f32) -> Ray {
-        Ray::new(
-            self.origin,
-            self.lower_left_corner + self.horizontal*x + self.vertical*y - self.origin
-        )
+    /// Give the camera a thin lens of radius aperture that brings everything
+    /// focus_distance away into focus. With 3 or more blades the opening is
+    /// a polygon, shaping out of focus highlights like a real diaphragm.
+    /// The aperture can't be negative and the focus distance has to be positive.
+    pub fn with_lens(mut self, aperture: f32, focus_distance: f32, blades: u32) -> Self {
+        // Move the image plane out to the plane in focus, the field of view stays the same
+        let scale = focus_distance / self.focal_length;
+        self.horizontal = self.horizontal * scale;
+        self.vertical = self.vertical * scale;
+        self.lower_left_corner = self.origin + (self.lower_left_corner - self.origin) * scale;
+        self.focal_length = focus_distance;
+
+        self.aperture = aperture;
+        self.blades = blades;
+        self
+    }
+
+    /// Ray through x, y on the image, leaving from a point on the lens picked by u and v
+    pub fn get_ray(&self, x: f32, y: f32, u: f32, v: f32) -> Ray {
+        let target = self.lower_left_corner + self.horizontal*x + self.vertical*y;
+
+        let origin = if self.aperture > 0.0 {
+            let (lx, ly) = if self.blades >= 3 {
+                sampling::uniform_polygon(u, v, self.blades)
+            } else {
+                sampling::uniform_disc(u, v)
+            };
+
+            self.origin + (self.lens_u * lx + self.lens_v * ly) * self.aperture
+        } else {
+            self.origin
+        };
+
+        Ray::new(origin, target - origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::grid;
+
+    fn camera() -> Camera {
+        Camera::new(Vec3::new(1.0, 2.0, 5.0), Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 60.0, 2.0, 1.0)
+    }
+
+    #[test]
+    fn pinholes_look_at_the_target_across_the_field_of_view() {
+        let cam = camera();
+        let from = Vec3::new(1.0, 2.0, 5.0);
+
+        let center = cam.get_ray(0.5, 0.5, 0.3, 0.8);
+        assert!((center.origin - from).mag() < 1e-6);
+        assert!((center.dir - (-from).normalized()).mag() < 1e-5);
+
+        // The left and right edges are the field of view apart, the image is twice as wide as high
+        let (left, right) = (cam.get_ray(0.0, 0.5, 0.0, 0.0), cam.get_ray(1.0, 0.5, 0.0, 0.0));
+        assert!((left.dir.dot(&right.dir).acos().to_degrees() - 60.0).abs() < 1e-3);
+
+        let (bottom, top) = (cam.get_ray(0.5, 0.0, 0.0, 0.0), cam.get_ray(0.5, 1.0, 0.0, 0.0));
+        let half_height = (30.0f32.to_radians().tan() / 2.0).atan().to_degrees();
+        assert!((bottom.dir.dot(&top.dir).acos().to_degrees() - 2.0 * half_height).abs() < 1e-3);
+
+        // Up on the image is up in the world
+        assert!(top.dir.y > bottom.dir.y);
+    }
+
+    #[test]
+    fn lens_rays_meet_on_the_plane_in_focus() {
+        let pinhole = camera();
+
+        for blades in [0, 6] {
+            let cam = camera().with_lens(0.2, 3.0, blades);
+
+            for (x, y) in [(0.5, 0.5), (0.1, 0.9), (0.8, 0.3)] {
+                // The pinhole ray through the same point finds where it is in focus
+                let focus = pinhole.get_ray(x, y, 0.0, 0.0);
+                let toward = focus.dir * (3.0 / focus.dir.dot(&(-Vec3::new(1.0, 2.0, 5.0)).normalized()));
+                let target = focus.origin + toward;
+
+                for (u, v) in grid(8) {
+                    let ray = cam.get_ray(x, y, u, v);
+                    assert!((ray.origin - focus.origin).mag() <= 0.2 + 1e-5);
+                    assert!((target - ray.origin).normalized().dot(&ray.dir) > 1.0 - 1e-5);
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,316 @@
+use crate::vector::Vec3;
+use crate::image::Image;
+use crate::material::Color;
+use crate::sampling::Distribution2D;
+use crate::sky::Sky;
+
+use serde::{Serialize, Deserialize};
+
+use std::f32::consts::PI;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Radiance arriving from infinitely far away along rays that leave the scene
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Environment {
+    /// The same radiance from every direction
+    Constant(Vec3<f32>),
+    /// Sky blending from the horizon up to the zenith over a uniform ground
+    Gradient { zenith: Vec3<f32>, horizon: Vec3<f32>, ground: Vec3<f32> },
+    /// Equirectangular image surrounding the scene, sampled as a light
+    Map(EnvironmentMap),
+    /// Daylight sky, its sun is sampled as a light
+    Sky(Sky)
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment::Constant(Color::RGB(31, 176, 255))
+    }
+}
+
+impl Environment {
+    /// Radiance arriving from direction dir
+    pub fn radiance(&self, dir: &Vec3<f32>) -> Vec3<f32> {
+        match self {
+            Environment::Constant(c) => *c,
+            Environment::Gradient { zenith, horizon, ground } => {
+                if dir.y < 0.0 {
+                    *ground
+                } else {
+                    *horizon * (1.0 - dir.y) + *zenith * dir.y
+                }
+            }
+            Environment::Map(m) => m.radiance(dir),
+            Environment::Sky(s) => s.radiance(dir)
+        }
+    }
+
+    /// Whether the environment has to be sampled as a light, smooth
+    /// backgrounds only light the scene through rays that escape it
+    pub fn is_light(&self) -> bool {
+        matches!(self, Environment::Map(_) | Environment::Sky(_))
+    }
+
+    /// Pick a direction towards the environment, returning it with its radiance and solid angle density
+    pub fn sample(&self, u: f32, v: f32) -> Option<(Vec3<f32>, Vec3<f32>, f32)> {
+        match self {
+            Environment::Map(m) => m.sample(u, v),
+            Environment::Sky(s) => s.sample(u, v),
+            _ => None
+        }
+    }
+
+    /// Solid angle density with which sample picks dir
+    pub fn pdf(&self, dir: &Vec3<f32>) -> f32 {
+        match self {
+            Environment::Map(m) => m.pdf(dir),
+            Environment::Sky(s) => s.pdf(dir),
+            _ => 0.0
+        }
+    }
+
+    /// Luminance of the power it sends into a scene bounded by a sphere of
+    /// the given radius, zero for environments that aren't lights
+    pub fn power(&self, radius: f32) -> f32 {
+        let area = PI * radius * radius;
+
+        match self {
+            Environment::Map(m) => area * PI * m.average_luminance(),
+            Environment::Sky(s) => area * (s.sun_irradiance().luminance() + PI * s.sky_radiance(&Vec3::new(0.0, 1.0, 0.0)).luminance()),
+            _ => 0.0
+        }
+    }
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+/// A latitude longitude image, the top row is straight up and the middle
+/// column looks down -z before rotation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvironmentMap {
+    /// Image file, scenes look it up relative to the scene file
+    pub path: PathBuf,
+    /// Multiplier on the image's radiance
+    #[serde(default = "one")]
+    pub intensity: f32,
+    /// Degrees the map is turned around the y axis
+    #[serde(default)]
+    pub rotation: f32,
+    /// Linear pixels of the image, filled in by set_image
+    #[serde(default)]
+    image: Arc<Image>,
+    /// Pixels picked by luminance, built by set_image
+    #[serde(default)]
+    distribution: Distribution2D
+}
+
+impl EnvironmentMap {
+    pub fn new(path: PathBuf, image: Arc<Image>) -> Self {
+        let mut map = Self {
+            path,
+            intensity: 1.0,
+            rotation: 0.0,
+            image: Arc::default(),
+            distribution: Distribution2D::default()
+        };
+
+        map.set_image(image);
+        map
+    }
+
+    /// Use image for the map and build the distribution to sample it by
+    pub fn set_image(&mut self, image: Arc<Image>) {
+        let (w, h) = (image.width, image.height);
+
+        // Rows near the poles cover less of the sphere
+        let func: Vec<f32> = (0..w*h)
+            .map(|i| {
+                let theta = ((i / w) as f32 + 0.5) / h as f32 * PI;
+                image.pixels[i].luminance() * theta.sin()
+            })
+            .collect();
+
+        self.distribution = Distribution2D::new(&func, w, h);
+        self.image = image;
+    }
+
+    /// Luminance averaged over every direction
+    fn average_luminance(&self) -> f32 {
+        // The distribution holds luminance times sin(theta) over the image,
+        // which stretches over 2pi by pi radians of the 4pi sphere
+        self.distribution.integral() * PI / 2.0 * self.intensity
+    }
+
+    /// Turn a direction by the map's rotation about y, backwards when inverse is set
+    fn rotate(&self, d: &Vec3<f32>, inverse: bool) -> Vec3<f32> {
+        let a = if inverse { -self.rotation } else { self.rotation }.to_radians();
+        let (sin, cos) = a.sin_cos();
+
+        Vec3::new(d.x * cos + d.z * sin, d.y, -d.x * sin + d.z * cos)
+    }
+
+    /// Image coordinates in [0, 1)^2 of a direction, rows from the top
+    fn uv(&self, dir: &Vec3<f32>) -> (f32, f32) {
+        let d = self.rotate(dir, true);
+
+        let u = 0.5 + d.x.atan2(-d.z) / (2.0 * PI);
+        let v = d.y.clamp(-1.0, 1.0).acos() / PI;
+
+        (u.rem_euclid(1.0), v.min(1.0 - f32::EPSILON))
+    }
+
+    fn direction(&self, (u, v): (f32, f32)) -> Vec3<f32> {
+        let (sin_theta, cos_theta) = (v * PI).sin_cos();
+        let (sin_phi, cos_phi) = ((u - 0.5) * 2.0 * PI).sin_cos();
+
+        self.rotate(&Vec3::new(sin_theta * sin_phi, cos_theta, -sin_theta * cos_phi), false)
+    }
+
+    fn pixel(&self, x: i64, y: i64) -> Vec3<f32> {
+        let img = &self.image;
+
+        // Wrap around horizontally, stop at the poles
+        let x = x.rem_euclid(img.width as i64) as usize;
+        let y = y.clamp(0, img.height as i64 - 1) as usize;
+
+        img.pixels[y * img.width + x]
+    }
+
+    pub fn radiance(&self, dir: &Vec3<f32>) -> Vec3<f32> {
+        let img = &self.image;
+        if img.width == 0 || img.height == 0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+
+        // Bilinear between pixel centers
+        let (u, v) = self.uv(dir);
+        let x = u * img.width as f32 - 0.5;
+        let y = v * img.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = self.pixel(x0, y0) * (1.0 - tx) + self.pixel(x0 + 1, y0) * tx;
+        let bottom = self.pixel(x0, y0 + 1) * (1.0 - tx) + self.pixel(x0 + 1, y0 + 1) * tx;
+
+        (top * (1.0 - ty) + bottom * ty) * self.intensity
+    }
+
+    /// Pick a direction in proportion to the luminance arriving from it
+    pub fn sample(&self, u: f32, v: f32) -> Option<(Vec3<f32>, Vec3<f32>, f32)> {
+        if self.image.width == 0 || self.image.height == 0 {
+            return None;
+        }
+
+        let (uv, pdf) = self.distribution.sample(u, v);
+
+        // The image stretches over 2pi by pi radians, squeezed by sin(theta) towards the poles
+        let sin_theta = (uv.1 * PI).sin();
+        if pdf <= 0.0 || sin_theta <= 0.0 {
+            return None;
+        }
+
+        let dir = self.direction(uv);
+        Some((dir, self.radiance(&dir), pdf / (2.0 * PI * PI * sin_theta)))
+    }
+
+    pub fn pdf(&self, dir: &Vec3<f32>) -> f32 {
+        if self.image.width == 0 || self.image.height == 0 {
+            return 0.0;
+        }
+
+        let uv = self.uv(dir);
+        let sin_theta = (uv.1 * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        self.distribution.pdf(uv) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::grid;
+
+    /// A dim 8 by 4 map with one bright pixel just above the horizon
+    fn map(rotation: f32) -> EnvironmentMap {
+        let mut pixels = vec![Vec3::new(0.1, 0.1, 0.1); 32];
+        pixels[13] = Vec3::new(50.0, 40.0, 30.0);
+
+        let mut m = EnvironmentMap::new(PathBuf::from("sky.hdr"), Arc::new(Image { width: 8, height: 4, pixels }));
+        m.rotation = rotation;
+        m
+    }
+
+    #[test]
+    fn directions_and_image_coordinates_round_trip() {
+        let m = map(30.0);
+
+        for (u, v) in grid(16) {
+            let (bu, bv) = m.uv(&m.direction((u, v)));
+            assert!((bu - u).abs() < 1e-4 && (bv - v).abs() < 1e-4);
+        }
+
+        // The middle column looks down -z and the top row straight up
+        let m = map(0.0);
+        assert!((m.direction((0.5, 0.5)) - Vec3::new(0.0, 0.0, -1.0)).mag() < 1e-6);
+        assert!((m.direction((0.3, 0.0)) - Vec3::new(0.0, 1.0, 0.0)).mag() < 1e-6);
+    }
+
+    #[test]
+    fn samples_have_the_density_pdf_gives() {
+        for rotation in [0.0, 75.0] {
+            let m = map(rotation);
+
+            for (u, v) in grid(32) {
+                let (dir, radiance, pdf) = m.sample(u, v).unwrap();
+                assert!((pdf - m.pdf(&dir)).abs() < 1e-3 * pdf, "{pdf} {}", m.pdf(&dir));
+                assert!((radiance - m.radiance(&dir)).mag() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn density_integrates_over_the_sphere() {
+        let m = map(40.0);
+
+        // Uniformly spread directions, each standing for 4pi / n^2 steradians
+        let n = 256;
+        let total: f32 = grid(n)
+            .map(|(u, v)| {
+                let z = 1.0 - 2.0 * u;
+                let r = (1.0 - z * z).sqrt();
+                let phi = 2.0 * PI * v;
+                m.pdf(&Vec3::new(r * phi.cos(), r * phi.sin(), z))
+            })
+            .sum();
+
+        let integral = total * 4.0 * PI / (n * n) as f32;
+        assert!((integral - 1.0).abs() < 1e-2, "{integral}");
+    }
+
+    #[test]
+    fn constant_maps_average_to_their_value() {
+        let image = Image { width: 6, height: 3, pixels: vec![Vec3::new(2.0, 2.0, 2.0); 18] };
+        let m = EnvironmentMap { intensity: 0.5, ..EnvironmentMap::new(PathBuf::from("grey.hdr"), Arc::new(image)) };
+
+        // Only the rows' sin(theta) weights fall short of the exact 2 / pi
+        assert!((m.average_luminance() - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn empty_maps_are_dark_and_never_sampled() {
+        let m = EnvironmentMap::new(PathBuf::from("none.hdr"), Arc::new(Image::default()));
+        let up = Vec3::new(0.0, 1.0, 0.0);
+
+        assert!(m.sample(0.5, 0.5).is_none());
+        assert_eq!(m.pdf(&up), 0.0);
+        assert_eq!(m.radiance(&up).mag(), 0.0);
+    }
+}
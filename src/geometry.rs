@@ -1,7 +1,17 @@
 use crate::vector::Vec3;
+use crate::sampling;
+use crate::bvh::Bvh;
+use crate::transform::Transform;
+use crate::shapes::{Plane, Disc, Cuboid, OrientedBox, Cylinder, Cone, Torus};
 
 use serde::{Serialize, Deserialize};
 
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Hits closer than this are the surface a ray started on
+pub(crate) const MIN_DIST: f32 = 0.000001;
+
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Ray {
     pub origin: Vec3<f32>,
@@ -30,6 +40,100 @@ impl Ray {
 pub trait Object {
     fn intersect(&self, ray: &Ray) -> Option<f32>;      // (distance, point)
     fn normal(&self, point: Vec3<f32>) -> Vec3<f32>;
+    /// Surface coordinates of a point on the surface and the direction u increases in
+    fn uv(&self, point: Vec3<f32>) -> ((f32, f32), Vec3<f32>);
+}
+
+/// Where a ray hit a piece of geometry
+#[derive(Debug, Copy, Clone)]
+pub struct Intersection {
+    pub dist: f32,
+    /// Triangle hit within a mesh, 0 for single primitives
+    pub prim: usize,
+    /// Barycentric weights of the second and third vertex of a hit triangle
+    pub bary: (f32, f32)
+}
+
+/// Everything known about the surface where a ray hit it, the normals face the incoming ray
+#[derive(Debug, Copy, Clone)]
+pub struct HitRecord {
+    pub p: Vec3<f32>,
+    /// The point before the geometry was placed by an instance's transform
+    pub local: Vec3<f32>,
+    pub dist: f32,
+    /// Normal of the actual surface
+    pub ng: Vec3<f32>,
+    /// Normal to shade with, on the same side as ng
+    pub n: Vec3<f32>,
+    /// Surface coordinates, in [0, 1] over most shapes
+    pub uv: (f32, f32),
+    /// Perpendicular to n and each other, tangent points the way u increases
+    pub tangent: Vec3<f32>,
+    pub bitangent: Vec3<f32>,
+    /// Whether the ray hit the outside of the surface
+    pub front_face: bool,
+    /// Triangle hit within a mesh, 0 for single primitives
+    pub prim: usize,
+    /// Whether the ray was travelling inside an object
+    pub inside: bool,
+    /// Blended from the vertex colors of meshes that have them
+    pub color: Option<Vec3<f32>>
+}
+
+impl HitRecord {
+    /// A record for a point known only by its position and normal, as when sampling lights
+    pub fn new(p: Vec3<f32>, n: Vec3<f32>, dist: f32) -> Self {
+        let (tangent, bitangent) = sampling::basis(&n);
+
+        Self {
+            p,
+            local: p,
+            dist,
+            ng: n,
+            n,
+            uv: (0.0, 0.0),
+            tangent,
+            bitangent,
+            front_face: true,
+            prim: 0,
+            inside: false,
+            color: None
+        }
+    }
+}
+
+/// A point sampled on a surface as seen from some origin
+#[derive(Debug, Copy, Clone)]
+pub struct SurfaceSample {
+    pub dir: Vec3<f32>,
+    pub dist: f32,
+    pub normal: Vec3<f32>,
+    pub pdf: f32    // with respect to solid angle at the origin
+}
+
+/// Turn a point picked with an area density into a sample as seen from origin
+fn area_sample(origin: &Vec3<f32>, point: Vec3<f32>, normal: Vec3<f32>, pdf: f32) -> Option<SurfaceSample> {
+    let d = point - *origin;
+    let dist = d.mag();
+    let pdf = area_to_solid_angle(pdf, origin, &point, &normal);
+    if dist <= 0.0 || !(pdf > 0.0 && pdf.is_finite()) {
+        return None;
+    }
+
+    Some(SurfaceSample { dir: d / dist, dist, normal, pdf })
+}
+
+/// Convert an area density at point to a solid angle density at origin
+fn area_to_solid_angle(pdf: f32, origin: &Vec3<f32>, point: &Vec3<f32>, normal: &Vec3<f32>) -> f32 {
+    let d = *point - *origin;
+    let dist2 = d.dot(&d);
+    let cos = normal.dot(&d).abs() / dist2.sqrt();
+
+    if cos <= 0.0 {
+        0.0
+    } else {
+        pdf * dist2 / cos
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -38,6 +142,83 @@ pub struct Sphere {
     radius: f32,
 }
 
+impl Sphere {
+    pub fn new(center: Vec3<f32>, radius: f32) -> Self {
+        Self { center, radius }
+    }
+
+    pub fn area(&self) -> f32 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    /// Pick a point uniformly over the surface, returning it with its normal and area density
+    fn sample_area(&self, u: f32, v: f32) -> (Vec3<f32>, Vec3<f32>, f32) {
+        let z = 1.0 - 2.0 * u;
+        let r = (1.0 - z*z).max(0.0).sqrt();
+        let phi = 2.0 * PI * v;
+        let normal = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+
+        (self.center + normal * self.radius, normal, 1.0 / self.area())
+    }
+
+    /// Sample the cone of directions from origin that hit the sphere
+    pub fn sample_from(&self, origin: &Vec3<f32>, u: f32, v: f32) -> Option<SurfaceSample> {
+        let oc = self.center - *origin;
+        let dc = oc.mag();
+        if dc <= self.radius {
+            // Inside the sphere, fall back to sampling the whole surface
+            let (point, normal, pdf) = self.sample_area(u, v);
+
+            let d = point - *origin;
+            let dist = d.mag();
+            if dist <= 0.0 {
+                return None;
+            }
+
+            return Some(SurfaceSample {
+                dir: d / dist,
+                dist,
+                normal,
+                pdf: area_to_solid_angle(pdf, origin, &point, &normal)
+            });
+        }
+
+        let sin2_max = self.radius * self.radius / (dc * dc);
+        let cos_max = (1.0 - sin2_max).max(0.0).sqrt();
+
+        let cos_theta = 1.0 - u * (1.0 - cos_max);
+        let sin_theta = (1.0 - cos_theta*cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * v;
+
+        let axis = oc / dc;
+        let dir = sampling::to_world(&Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta), &axis);
+
+        let dist = dc * cos_theta - (self.radius * self.radius - dc * dc * sin_theta * sin_theta).max(0.0).sqrt();
+        let point = *origin + dir * dist;
+
+        Some(SurfaceSample {
+            dir,
+            dist,
+            normal: (point - self.center) / self.radius,
+            pdf: 1.0 / (2.0 * PI * (1.0 - cos_max))
+        })
+    }
+
+    /// Density with which sample_from picks point
+    pub fn pdf_from(&self, origin: &Vec3<f32>, point: &Vec3<f32>) -> f32 {
+        let dc = (self.center - *origin).mag();
+        if dc <= self.radius {
+            let normal = (*point - self.center) / self.radius;
+            return area_to_solid_angle(1.0 / self.area(), origin, point, &normal);
+        }
+
+        let sin2_max = self.radius * self.radius / (dc * dc);
+        let cos_max = (1.0 - sin2_max).max(0.0).sqrt();
+
+        1.0 / (2.0 * PI * (1.0 - cos_max))
+    }
+}
+
 impl Object for Sphere {
     fn intersect(&self, ray: &Ray) -> Option<f32> {
         let oc = ray.origin - self.center;  // Vector from ray origin to circle center
@@ -52,9 +233,9 @@ impl Object for Sphere {
             let r1 = -b - h;
             let r2 = -b + h;
 
-            if r1 < 0.0001 || r2 < 0.0001 {
-                return None
-            } else if r1 < 0.0 {
+            if r2 < 0.0001 {
+                None
+            } else if r1 < 0.0001 {
                 Some(r2)
             } else {
                 Some(r1)
@@ -63,8 +244,67 @@ impl Object for Sphere {
     }
 
     fn normal(&self, point: Vec3<f32>) -> Vec3<f32> {
-       -(point-self.center).normalized()
+        (point-self.center).normalized()
     }
+
+    /// Longitude and latitude with v increasing towards +y
+    fn uv(&self, point: Vec3<f32>) -> ((f32, f32), Vec3<f32>) {
+        let d = (point - self.center) / self.radius;
+        let u = (d.z.atan2(d.x) + PI) / (2.0 * PI);
+        let v = (-d.y).clamp(-1.0, 1.0).acos() / PI;
+
+        ((u, v), Vec3::new(-d.z, 0.0, d.x))
+    }
+}
+
+/// Blend three vertex normals at a barycentric point, None where they cancel out
+fn interpolate(normals: [Vec3<f32>; 3], (u, v): (f32, f32)) -> Option<Vec3<f32>> {
+    let n = normals[0] * (1.0 - u - v) + normals[1] * u + normals[2] * v;
+    let len = n.mag();
+
+    if len > 0.0 {
+        Some(n / len)
+    } else {
+        None
+    }
+}
+
+/// Blend three vertex uvs at a barycentric point, along with the direction u
+/// increases in across the triangle with the given corners
+fn texture_coords(corners: [Vec3<f32>; 3], uvs: [(f32, f32); 3], (b1, b2): (f32, f32)) -> ((f32, f32), Vec3<f32>) {
+    let b0 = 1.0 - b1 - b2;
+    let uv = (
+        uvs[0].0 * b0 + uvs[1].0 * b1 + uvs[2].0 * b2,
+        uvs[0].1 * b0 + uvs[1].1 * b1 + uvs[2].1 * b2
+    );
+
+    let e1 = corners[1] - corners[0];
+    let e2 = corners[2] - corners[0];
+    let (du1, dv1) = (uvs[1].0 - uvs[0].0, uvs[1].1 - uvs[0].1);
+    let (du2, dv2) = (uvs[2].0 - uvs[0].0, uvs[2].1 - uvs[0].1);
+
+    // Solve e1 = du1 dpdu + dv1 dpdv, e2 = du2 dpdu + dv2 dpdv for dpdu
+    let det = du1 * dv2 - dv1 * du2;
+    let dpdu = if det.abs() > 1e-12 { (e1 * dv2 - e2 * dv1) / det } else { e1 };
+
+    (uv, dpdu)
+}
+
+/// A normal for every position averaged from the triangles around it, weighted by their area
+pub fn vertex_normals(positions: &[Vec3<f32>], triangles: &[[usize; 3]]) -> Vec<Vec3<f32>> {
+    let mut normals = vec![Vec3::new(0.0, 0.0, 0.0); positions.len()];
+
+    for &[a, b, c] in triangles {
+        // The cross product is as long as twice the area
+        let n = (positions[b] - positions[a]).cross(&(positions[c] - positions[a]));
+        for i in [a, b, c] {
+            normals[i] += n;
+        }
+    }
+
+    normals.into_iter()
+        .map(|n| if n.mag() > 0.0 { n.normalized() } else { n })
+        .collect()
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -72,10 +312,55 @@ pub struct Triangle {
     v0: Vec3<f32>,
     v1: Vec3<f32>,
     v2: Vec3<f32>,
+    /// Vertex normals interpolated across the face, flat shaded without them
+    normals: Option<[Vec3<f32>; 3]>,
+    /// Texture coordinates of each vertex, barycentric coordinates are used without them
+    uvs: Option<[(f32, f32); 3]>
 }
 
-impl Object for Triangle {
-    fn intersect(&self, ray: &Ray) -> Option<f32> {
+impl Triangle {
+    pub fn new(v0: Vec3<f32>, v1: Vec3<f32>, v2: Vec3<f32>) -> Self {
+        Self { v0, v1, v2, normals: None, uvs: None }
+    }
+
+    /// Shade with normals interpolated from one at each vertex
+    pub fn with_normals(mut self, normals: [Vec3<f32>; 3]) -> Self {
+        self.normals = Some(normals);
+        self
+    }
+
+    /// Texture with coordinates interpolated from one at each vertex
+    pub fn with_uvs(mut self, uvs: [(f32, f32); 3]) -> Self {
+        self.uvs = Some(uvs);
+        self
+    }
+
+    pub fn area(&self) -> f32 {
+        (self.v1 - self.v0).cross(&(self.v2 - self.v0)).mag() / 2.0
+    }
+
+    /// Pick a point uniformly over the area, returning it with its normal and area density
+    fn sample_area(&self, u: f32, v: f32) -> (Vec3<f32>, Vec3<f32>, f32) {
+        let su = u.sqrt();
+        let (b0, b1) = (1.0 - su, v * su);
+        let point = self.v0 * b0 + self.v1 * b1 + self.v2 * (1.0 - b0 - b1);
+
+        (point, self.normal(point), 1.0 / self.area())
+    }
+
+    /// Sample a point uniformly over the area of the triangle
+    pub fn sample_from(&self, origin: &Vec3<f32>, u: f32, v: f32) -> Option<SurfaceSample> {
+        let (point, normal, pdf) = self.sample_area(u, v);
+        area_sample(origin, point, normal, pdf)
+    }
+
+    /// Density with which sample_from picks point
+    pub fn pdf_from(&self, origin: &Vec3<f32>, point: &Vec3<f32>) -> f32 {
+        area_to_solid_angle(1.0 / self.area(), origin, point, &self.normal(*point))
+    }
+
+    /// Distance to the hit along with its barycentric coordinates
+    pub fn hit(&self, ray: &Ray) -> Option<(f32, (f32, f32))> {
         let v1v0 = self.v1 - self.v0;
         let v2v0 = self.v2 - self.v0;
         let rov0 = ray.origin - self.v0;
@@ -94,8 +379,45 @@ impl Object for Triangle {
         if u<0.0 || v<0.0 || (u+v)>1.0 || t<0.0 {
             None
         } else {
-            Some(t)
+            Some((t, (u, v)))
+        }
+    }
+
+    /// Normal interpolated from the vertex normals at barycentric point bary
+    pub fn shading_normal(&self, bary: (f32, f32)) -> Vec3<f32> {
+        self.normals
+            .and_then(|ns| interpolate(ns, bary))
+            .unwrap_or_else(|| self.normal(self.v0))
+    }
+
+    /// Texture coordinates at barycentric point bary
+    pub fn texture_uv(&self, bary: (f32, f32)) -> ((f32, f32), Vec3<f32>) {
+        match self.uvs {
+            Some(uvs) => texture_coords([self.v0, self.v1, self.v2], uvs, bary),
+            None => (bary, self.v1 - self.v0)
+        }
+    }
+}
+
+impl Object for Triangle {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        self.hit(ray).map(|(t, _)| t)
+    }
+
+    /// Vertex uvs interpolated to the point, or its barycentric coordinates
+    fn uv(&self, point: Vec3<f32>) -> ((f32, f32), Vec3<f32>) {
+        let e1 = self.v1 - self.v0;
+        let e2 = self.v2 - self.v0;
+        let d = point - self.v0;
+
+        let (d00, d01, d11) = (e1.dot(&e1), e1.dot(&e2), e2.dot(&e2));
+        let (d20, d21) = (d.dot(&e1), d.dot(&e2));
+        let denom = d00 * d11 - d01 * d01;
+        if denom == 0.0 {
+            return ((0.0, 0.0), e1);
         }
+
+        self.texture_uv(((d11 * d20 - d01 * d21) / denom, (d00 * d21 - d01 * d20) / denom))
     }
 
     fn normal(&self, _point: Vec3<f32>) -> Vec3<f32> {
@@ -103,10 +425,302 @@ impl Object for Triangle {
     }
 }
 
+/// Triangles sharing one vertex buffer, with their own bvh
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Mesh {
+    positions: Vec<Vec3<f32>>,
+    triangles: Vec<[usize; 3]>,
+    bounds: AABB,
+    bvh: Bvh,
+    /// Running total of the triangle areas, for picking triangles by area
+    cdf: Vec<f32>,
+    /// One normal per position to shade with
+    normals: Option<Vec<Vec3<f32>>>,
+    /// One texture coordinate per position
+    uvs: Option<Vec<(f32, f32)>>,
+    /// One color per position
+    colors: Option<Vec<Vec3<f32>>>
+}
+
+impl Mesh {
+    pub fn new(positions: Vec<Vec3<f32>>, triangles: Vec<[usize; 3]>) -> Self {
+        let mut mesh = Self {
+            positions,
+            triangles,
+            bounds: AABB { min: Vec3::new(0.0, 0.0, 0.0), max: Vec3::new(0.0, 0.0, 0.0) },
+            bvh: Bvh::from_bounds(&[]),
+            cdf: Vec::new(),
+            normals: None,
+            uvs: None,
+            colors: None
+        };
+
+        let bounds: Vec<AABB> = (0..mesh.triangles.len())
+            .map(|i| Geometry::Triangle(mesh.triangle(i)).fit())
+            .collect();
+
+        if let Some(first) = bounds.first() {
+            mesh.bounds = bounds.iter().fold(*first, |a, b| a.union(*b));
+        }
+        mesh.bvh = Bvh::from_bounds(&bounds);
+
+        let mut total = 0.0;
+        mesh.cdf = (0..mesh.triangles.len())
+            .map(|i| {
+                total += mesh.triangle(i).area();
+                total
+            })
+            .collect();
+
+        mesh
+    }
+
+    fn triangle(&self, i: usize) -> Triangle {
+        let [a, b, c] = self.triangles[i];
+        Triangle::new(self.positions[a], self.positions[b], self.positions[c])
+    }
+
+    /// Shade with normals interpolated from one given per position
+    pub fn with_normals(mut self, normals: Vec<Vec3<f32>>) -> Self {
+        self.normals = Some(normals);
+        self
+    }
+
+    /// Shade with vertex normals averaged from the faces around each vertex
+    pub fn smooth(self) -> Self {
+        let normals = vertex_normals(&self.positions, &self.triangles);
+        self.with_normals(normals)
+    }
+
+    /// Texture with coordinates interpolated from one given per position
+    pub fn with_uvs(mut self, uvs: Vec<(f32, f32)>) -> Self {
+        self.uvs = Some(uvs);
+        self
+    }
+
+    /// Color with values interpolated from one given per position
+    pub fn with_colors(mut self, colors: Vec<Vec3<f32>>) -> Self {
+        self.colors = Some(colors);
+        self
+    }
+
+    pub fn has_normals(&self) -> bool {
+        self.normals.is_some()
+    }
+
+    pub fn area(&self) -> f32 {
+        self.cdf.last().copied().unwrap_or(0.0)
+    }
+
+    /// Area the mesh would have with every vertex moved by a transform
+    pub fn transformed_area(&self, t: &Transform) -> f32 {
+        (0..self.triangles.len())
+            .map(|i| {
+                let tri = self.triangle(i);
+                Triangle::new(t.point(&tri.v0), t.point(&tri.v1), t.point(&tri.v2)).area()
+            })
+            .sum()
+    }
+
+    pub fn hit(&self, ray: &Ray) -> Option<Intersection> {
+        self.bvh.traverse(ray, f32::INFINITY, false, |i| {
+            self.triangle(i).hit(ray)
+                .filter(|&(dist, _)| dist > MIN_DIST)
+                .map(|(dist, bary)| Intersection { dist, prim: i, bary })
+        }).map(|(_, h)| h)
+    }
+
+    pub fn normal(&self, prim: usize) -> Vec3<f32> {
+        self.triangle(prim).normal(Vec3::new(0.0, 0.0, 0.0))
+    }
+
+    /// Vertex uvs interpolated across the hit triangle, or barycentric coordinates without them
+    pub fn uv(&self, hit: &Intersection) -> ((f32, f32), Vec3<f32>) {
+        let [a, b, c] = self.triangles[hit.prim];
+        let corners = [self.positions[a], self.positions[b], self.positions[c]];
+
+        match &self.uvs {
+            Some(uvs) => texture_coords(corners, [uvs[a], uvs[b], uvs[c]], hit.bary),
+            None => (hit.bary, corners[1] - corners[0])
+        }
+    }
+
+    /// Vertex colors blended across the hit triangle
+    pub fn color(&self, hit: &Intersection) -> Option<Vec3<f32>> {
+        let [a, b, c] = self.triangles[hit.prim];
+        let (u, v) = hit.bary;
+
+        self.colors.as_ref().map(|cs| cs[a] * (1.0 - u - v) + cs[b] * u + cs[c] * v)
+    }
+
+    pub fn shading_normal(&self, hit: &Intersection) -> Vec3<f32> {
+        let [a, b, c] = self.triangles[hit.prim];
+
+        self.normals.as_ref()
+            .and_then(|ns| interpolate([ns[a], ns[b], ns[c]], hit.bary))
+            .unwrap_or_else(|| self.normal(hit.prim))
+    }
+
+    /// Pick a point uniformly by area, returning it with its normal and area density
+    fn sample_area(&self, u: f32, v: f32) -> Option<(Vec3<f32>, Vec3<f32>, f32)> {
+        let total = self.area();
+        if total <= 0.0 {
+            return None;
+        }
+
+        // Choose a triangle and stretch u back out to [0..1) within it
+        let target = u * total;
+        let i = self.cdf.partition_point(|&c| c <= target).min(self.cdf.len() - 1);
+        let start = if i == 0 { 0.0 } else { self.cdf[i-1] };
+        let u = ((target - start) / (self.cdf[i] - start)).clamp(0.0, 1.0);
+
+        let t = self.triangle(i);
+        let su = u.sqrt();
+        let (b0, b1) = (1.0 - su, v * su);
+        let point = t.v0 * b0 + t.v1 * b1 + t.v2 * (1.0 - b0 - b1);
+
+        Some((point, self.normal(i), 1.0 / total))
+    }
+
+    pub fn sample_from(&self, origin: &Vec3<f32>, u: f32, v: f32) -> Option<SurfaceSample> {
+        let (point, normal, pdf) = self.sample_area(u, v)?;
+        area_sample(origin, point, normal, pdf)
+    }
+
+    pub fn pdf_from(&self, origin: &Vec3<f32>, point: &Vec3<f32>, normal: &Vec3<f32>) -> f32 {
+        area_to_solid_angle(1.0 / self.area(), origin, point, normal)
+    }
+
+    /// The same mesh with every vertex moved by a transform
+    pub fn transformed(&self, t: &Transform) -> Self {
+        let mesh = Mesh::new(
+            self.positions.iter().map(|p| t.point(p)).collect(),
+            self.triangles.clone()
+        );
+
+        let mesh = match &self.normals {
+            Some(ns) => mesh.with_normals(ns.iter().map(|n| t.normal(n).normalized()).collect()),
+            None => mesh
+        };
+
+        let mesh = match &self.uvs {
+            Some(uvs) => mesh.with_uvs(uvs.clone()),
+            None => mesh
+        };
+
+        match &self.colors {
+            Some(cs) => mesh.with_colors(cs.clone()),
+            None => mesh
+        }
+    }
+}
+
+/// Geometry placed in the world with a transform, sharing its data with every other placement
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Instance {
+    geometry: Arc<Geometry>,
+    transform: Transform
+}
+
+impl Instance {
+    pub fn new(geometry: Arc<Geometry>, transform: Transform) -> Self {
+        Self { geometry, transform }
+    }
+
+    pub fn hit(&self, ray: &Ray) -> Option<Intersection> {
+        // Intersect in object space, scaling the distance back to world space
+        let inv = self.transform.inverse();
+        let dir = inv.vector(&ray.dir);
+        let scale = dir.mag();
+
+        let local = Ray { origin: inv.point(&ray.origin), dir: dir / scale, inside: ray.inside };
+        self.geometry.hit(&local).map(|h| Intersection { dist: h.dist / scale, ..h })
+    }
+
+    pub fn normal(&self, point: Vec3<f32>, prim: usize) -> Vec3<f32> {
+        let local = self.transform.inverse().point(&point);
+        self.transform.normal(&self.geometry.normal(local, prim)).normalized()
+    }
+
+    pub fn shading_normal(&self, point: Vec3<f32>, hit: &Intersection) -> Vec3<f32> {
+        let local = self.transform.inverse().point(&point);
+        self.transform.normal(&self.geometry.shading_normal(local, hit)).normalized()
+    }
+
+    pub fn uv(&self, point: Vec3<f32>, hit: &Intersection) -> ((f32, f32), Vec3<f32>) {
+        let local = self.transform.inverse().point(&point);
+        let (uv, dpdu) = self.geometry.uv(local, hit);
+
+        (uv, self.transform.vector(&dpdu))
+    }
+
+    pub fn fit(&self) -> AABB {
+        let AABB { min, max } = self.geometry.fit();
+
+        let corners = (0..8).map(|i| Vec3::new(
+            if i & 1 == 0 { min.x } else { max.x },
+            if i & 2 == 0 { min.y } else { max.y },
+            if i & 4 == 0 { min.z } else { max.z }
+        )).map(|c| self.transform.point(&c));
+
+        let first = self.transform.point(&min);
+        corners.fold(AABB { min: first, max: first }, |a, c| a.union(AABB { min: c, max: c }))
+    }
+
+    /// Surface area after the transform. Mesh triangles stay triangles and are
+    /// measured exactly, other shapes are stretched by different amounts in
+    /// different places, so the stretch is averaged over points spread evenly
+    /// across the surface
+    pub fn area(&self) -> f32 {
+        const STEPS: usize = 16;
+
+        if let Geometry::Mesh(m) = self.geometry.as_ref() {
+            return m.transformed_area(&self.transform);
+        }
+
+        let mut scale = 0.0;
+        for i in 0..STEPS {
+            for j in 0..STEPS {
+                let (u, v) = ((i as f32 + 0.5) / STEPS as f32, (j as f32 + 0.5) / STEPS as f32);
+                if let Some((_, normal, _)) = self.geometry.sample_area(u, v) {
+                    scale += self.transform.area_scale(&self.transform.normal(&normal).normalized());
+                }
+            }
+        }
+
+        self.geometry.area() * scale / (STEPS * STEPS) as f32
+    }
+
+    fn sample_area(&self, u: f32, v: f32) -> Option<(Vec3<f32>, Vec3<f32>, f32)> {
+        let (point, normal, pdf) = self.geometry.sample_area(u, v)?;
+        let point = self.transform.point(&point);
+        let normal = self.transform.normal(&normal).normalized();
+
+        // Areas stretch under the transform so the density shrinks by as much
+        Some((point, normal, pdf / self.transform.area_scale(&normal)))
+    }
+
+    fn area_pdf(&self, point: &Vec3<f32>, normal: &Vec3<f32>) -> f32 {
+        let inv = self.transform.inverse();
+        let local = self.geometry.area_pdf(&inv.point(point), &inv.normal(normal).normalized());
+
+        local / self.transform.area_scale(normal)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Geometry {
     Sphere(Sphere),
     Triangle(Triangle),
+    Mesh(Mesh),
+    Instance(Instance),
+    Plane(Plane),
+    Disc(Disc),
+    Cuboid(Cuboid),
+    OrientedBox(OrientedBox),
+    Cylinder(Cylinder),
+    Cone(Cone),
+    Torus(Torus),
 }
 
 impl Geometry {
@@ -118,16 +732,241 @@ impl Geometry {
     }
 
     pub fn new_triangle(v0: Vec3<f32>, v1: Vec3<f32>, v2: Vec3<f32>) -> Self {
-        Self::Triangle(Triangle {
-            v0,
-            v1,
-            v2
-        })
+        Self::Triangle(Triangle::new(v0, v1, v2))
+    }
+
+    /// A triangle shaded with normals interpolated from one at each vertex
+    pub fn new_smooth_triangle(v0: Vec3<f32>, v1: Vec3<f32>, v2: Vec3<f32>, normals: [Vec3<f32>; 3]) -> Self {
+        Self::Triangle(Triangle::new(v0, v1, v2).with_normals(normals))
+    }
+
+    /// Pick a point on the surface, as seen from origin
+    pub fn sample_from(&self, origin: &Vec3<f32>, u: f32, v: f32) -> Option<SurfaceSample> {
+        match self {
+            Geometry::Sphere(s) => s.sample_from(origin, u, v),
+            Geometry::Triangle(t) => t.sample_from(origin, u, v),
+            Geometry::Mesh(m) => m.sample_from(origin, u, v),
+            _ => {
+                let (point, normal, pdf) = self.sample_area(u, v)?;
+                area_sample(origin, point, normal, pdf)
+            }
+        }
+    }
+
+    /// Density with which sample_from picks a point on the surface with the given normal
+    pub fn pdf_from(&self, origin: &Vec3<f32>, point: &Vec3<f32>, normal: &Vec3<f32>) -> f32 {
+        match self {
+            Geometry::Sphere(s) => s.pdf_from(origin, point),
+            Geometry::Triangle(t) => t.pdf_from(origin, point),
+            Geometry::Mesh(m) => m.pdf_from(origin, point, normal),
+            _ => area_to_solid_angle(self.area_pdf(point, normal), origin, point, normal),
+        }
+    }
+
+    /// Surface area, infinite surfaces have none
+    pub fn area(&self) -> f32 {
+        match self {
+            Geometry::Sphere(s) => s.area(),
+            Geometry::Triangle(t) => t.area(),
+            Geometry::Mesh(m) => m.area(),
+            Geometry::Instance(i) => i.area(),
+            Geometry::Plane(_) => 0.0,
+            Geometry::Disc(d) => d.area(),
+            Geometry::Cuboid(c) => c.area(),
+            Geometry::OrientedBox(b) => b.area(),
+            Geometry::Cylinder(c) => c.area(),
+            Geometry::Cone(c) => c.area(),
+            Geometry::Torus(t) => t.area()
+        }
+    }
+
+    /// Pick a point uniformly over the untransformed surface, returning it with its normal and area density
+    fn sample_area(&self, u: f32, v: f32) -> Option<(Vec3<f32>, Vec3<f32>, f32)> {
+        match self {
+            Geometry::Sphere(s) => Some(s.sample_area(u, v)),
+            Geometry::Triangle(t) => Some(t.sample_area(u, v)),
+            Geometry::Mesh(m) => m.sample_area(u, v),
+            Geometry::Instance(i) => i.sample_area(u, v),
+            // Infinite area, can't be picked from uniformly
+            Geometry::Plane(_) => None,
+            Geometry::Disc(d) => Some(d.sample_area(u, v)),
+            Geometry::Cuboid(c) => Some(c.sample_area(u, v)),
+            Geometry::OrientedBox(b) => Some(b.sample_area(u, v)),
+            Geometry::Cylinder(c) => Some(c.sample_area(u, v)),
+            Geometry::Cone(c) => Some(c.sample_area(u, v)),
+            Geometry::Torus(t) => Some(t.sample_area(u, v))
+        }
+    }
+
+    /// Area density with which sample_area picks point
+    fn area_pdf(&self, point: &Vec3<f32>, normal: &Vec3<f32>) -> f32 {
+        match self {
+            Geometry::Sphere(s) => 1.0 / s.area(),
+            Geometry::Triangle(t) => 1.0 / t.area(),
+            Geometry::Mesh(m) => 1.0 / m.area(),
+            Geometry::Instance(i) => i.area_pdf(point, normal),
+            Geometry::Plane(_) => 0.0,
+            Geometry::Disc(d) => 1.0 / d.area(),
+            Geometry::Cuboid(c) => 1.0 / c.area(),
+            Geometry::OrientedBox(b) => 1.0 / b.area(),
+            Geometry::Cylinder(c) => 1.0 / c.area(),
+            Geometry::Cone(c) => 1.0 / c.area(),
+            Geometry::Torus(t) => 1.0 / t.area()
+        }
+    }
+
+    /// Apply a transform, triangles, meshes and planes are moved directly
+    /// while other shapes are wrapped in an instance
+    pub fn transformed(self, t: &Transform) -> Self {
+        match self {
+            Geometry::Triangle(Triangle { v0, v1, v2, normals, uvs }) => Geometry::Triangle(Triangle {
+                v0: t.point(&v0),
+                v1: t.point(&v1),
+                v2: t.point(&v2),
+                normals: normals.map(|ns| ns.map(|n| t.normal(&n).normalized())),
+                uvs
+            }),
+            Geometry::Mesh(m) => Geometry::Mesh(m.transformed(t)),
+            Geometry::Instance(i) => Geometry::Instance(Instance::new(i.geometry, i.transform.then(t))),
+            Geometry::Plane(p) => Geometry::Plane(Plane::new(t.point(&p.point), t.normal(&p.normal))),
+            g => Geometry::Instance(Instance::new(Arc::new(g), *t))
+        }
+    }
+
+    /// Whether the geometry fits in a finite box, unbounded geometry is
+    /// left out of acceleration structures and tested against every ray
+    pub fn is_bounded(&self) -> bool {
+        match self {
+            Geometry::Plane(_) => false,
+            Geometry::Instance(i) => i.geometry.is_bounded(),
+            _ => true
+        }
+    }
+
+    pub fn hit(&self, ray: &Ray) -> Option<Intersection> {
+        let at = |dist| Intersection { dist, prim: 0, bary: (0.0, 0.0) };
+
+        let h = match self {
+            Geometry::Sphere(s) => s.intersect(ray).map(at),
+            Geometry::Triangle(t) => t.hit(ray).map(|(dist, bary)| Intersection { dist, prim: 0, bary }),
+            Geometry::Mesh(m) => m.hit(ray),
+            Geometry::Instance(i) => i.hit(ray),
+            Geometry::Plane(p) => p.intersect(ray).map(at),
+            Geometry::Disc(d) => d.intersect(ray).map(at),
+            Geometry::Cuboid(c) => c.intersect(ray).map(at),
+            Geometry::OrientedBox(b) => b.intersect(ray).map(at),
+            Geometry::Cylinder(c) => c.intersect(ray).map(at),
+            Geometry::Cone(c) => c.intersect(ray).map(at),
+            Geometry::Torus(t) => t.intersect(ray).map(at)
+        }?;
+
+        if h.dist > MIN_DIST {
+            Some(h)
+        } else {
+            None
+        }
+    }
+
+    /// Surface normal at a point, prim picks the triangle within a mesh
+    pub fn normal(&self, point: Vec3<f32>, prim: usize) -> Vec3<f32> {
+        match self {
+            Geometry::Sphere(s) => s.normal(point),
+            Geometry::Triangle(t) => t.normal(point),
+            Geometry::Mesh(m) => m.normal(prim),
+            Geometry::Instance(i) => i.normal(point, prim),
+            Geometry::Plane(p) => p.normal(point),
+            Geometry::Disc(d) => d.normal(point),
+            Geometry::Cuboid(c) => c.normal(point),
+            Geometry::OrientedBox(b) => b.normal(point),
+            Geometry::Cylinder(c) => c.normal(point),
+            Geometry::Cone(c) => c.normal(point),
+            Geometry::Torus(t) => t.normal(point)
+        }
+    }
+
+    /// Surface coordinates of a point on the surface and the direction u increases in
+    pub fn uv(&self, point: Vec3<f32>, hit: &Intersection) -> ((f32, f32), Vec3<f32>) {
+        match self {
+            Geometry::Sphere(s) => s.uv(point),
+            Geometry::Triangle(t) => t.texture_uv(hit.bary),
+            Geometry::Mesh(m) => m.uv(hit),
+            Geometry::Instance(i) => i.uv(point, hit),
+            Geometry::Plane(p) => p.uv(point),
+            Geometry::Disc(d) => d.uv(point),
+            Geometry::Cuboid(c) => c.uv(point),
+            Geometry::OrientedBox(b) => b.uv(point),
+            Geometry::Cylinder(c) => c.uv(point),
+            Geometry::Cone(c) => c.uv(point),
+            Geometry::Torus(t) => t.uv(point)
+        }
+    }
+
+    /// Color from the vertex colors at the hit, for meshes that have them
+    pub fn vertex_color(&self, hit: &Intersection) -> Option<Vec3<f32>> {
+        match self {
+            Geometry::Mesh(m) => m.color(hit),
+            Geometry::Instance(i) => i.geometry.vertex_color(hit),
+            _ => None
+        }
+    }
+
+    /// Undo instance transforms to find where a point is on the untransformed geometry
+    pub fn local_point(&self, point: Vec3<f32>) -> Vec3<f32> {
+        match self {
+            Geometry::Instance(i) => i.geometry.local_point(i.transform.inverse().point(&point)),
+            _ => point
+        }
+    }
+
+    /// Describe the surface where a ray hit it
+    pub fn hit_record(&self, ray: &Ray, hit: &Intersection) -> HitRecord {
+        let p = ray.origin + ray.dir * hit.dist;
+
+        let mut ng = self.normal(p, hit.prim);
+        let front_face = ng.dot(&ray.dir) < 0.0;
+        if !front_face {
+            ng = -ng;
+        }
+
+        let mut n = self.shading_normal(p, hit);
+        if n.dot(&ng) < 0.0 {
+            n = -n;
+        }
+
+        // Follow the parameterization where it has a direction, made perpendicular to n
+        let (uv, dpdu) = self.uv(p, hit);
+        let t = dpdu - n * n.dot(&dpdu);
+        let tangent = if t.mag() > 1e-8 { t.normalized() } else { sampling::basis(&n).0 };
+
+        HitRecord {
+            p,
+            local: self.local_point(p),
+            dist: hit.dist,
+            ng,
+            n,
+            uv,
+            tangent,
+            bitangent: n.cross(&tangent),
+            front_face,
+            prim: hit.prim,
+            inside: ray.inside,
+            color: self.vertex_color(hit)
+        }
+    }
+
+    /// Normal to shade with, interpolated from vertex normals where there are any
+    pub fn shading_normal(&self, point: Vec3<f32>, hit: &Intersection) -> Vec3<f32> {
+        match self {
+            Geometry::Triangle(t) => t.shading_normal(hit.bary),
+            Geometry::Mesh(m) => m.shading_normal(hit),
+            Geometry::Instance(i) => i.shading_normal(point, hit),
+            _ => self.normal(point, hit.prim)
+        }
     }
 
     pub fn fit(&self) -> AABB {
         match self {
-            Geometry::Triangle(Triangle { v0, v1, v2 }) => {
+            Geometry::Triangle(Triangle { v0, v1, v2, .. }) => {
                 let minx = v0.x.min(v1.x).min(v2.x);
                 let miny = v0.y.min(v1.y).min(v2.y);
                 let minz = v0.z.min(v1.z).min(v2.z);
@@ -142,92 +981,85 @@ impl Geometry {
                 }
             }
             Geometry::Sphere(Sphere { center, radius }) => {
-                let mut min = center.clone();
-                let mut max = center.clone();
+                let mut min = *center;
+                let mut max = *center;
 
-                let r2 = radius.powi(2);
-                min.x -= r2;
-                min.y -= r2;
-                min.z -= r2;
+                min.x -= radius;
+                min.y -= radius;
+                min.z -= radius;
 
-                max.x += r2;
-                max.y += r2;
-                max.z += r2;
+                max.x += radius;
+                max.y += radius;
+                max.z += radius;
 
                 AABB { min, max }
             }
+            Geometry::Mesh(m) => m.bounds,
+            Geometry::Instance(i) => i.fit(),
+            Geometry::Plane(p) => p.fit(),
+            Geometry::Disc(d) => d.fit(),
+            Geometry::Cuboid(c) => c.fit(),
+            Geometry::OrientedBox(b) => b.fit(),
+            Geometry::Cylinder(c) => c.fit(),
+            Geometry::Cone(c) => c.fit(),
+            Geometry::Torus(t) => t.fit()
         }
     }
 
     pub fn left_of(&self, axis: Axis, v: f32) -> bool {
         match self {
-            Geometry::Triangle(Triangle { v0, v1, v2 }) => {
+            Geometry::Triangle(Triangle { v0, v1, v2, .. }) => {
                 match axis {
                     Axis::X => v0.x <= v || v1.x <= v || v2.x <= v,
                     Axis::Y => v0.y <= v || v1.y <= v || v2.y <= v,
                     Axis::Z => v0.z <= v || v1.z <= v || v2.z <= v,
                 }
             }
-            Geometry::Sphere(Sphere { center, radius }) => {
-                let r2 = radius.powi(2);
-
-                match axis {
-                    Axis::X => center.x-r2 <= v,
-                    Axis::Y => center.y-r2 <= v,
-                    Axis::Z => center.z-r2 <= v
-                }
-            }
+            Geometry::Sphere(Sphere { center, radius }) => axis.of(center)-radius <= v,
+            _ => axis.of(&self.fit().min) <= v
         }
     }
 
     pub fn right_of(&self, axis: Axis, v: f32) -> bool {
         match self {
-            Geometry::Triangle(Triangle { v0, v1, v2 }) => {
+            Geometry::Triangle(Triangle { v0, v1, v2, .. }) => {
                 match axis {
                     Axis::X => v0.x >= v || v1.x >= v || v2.x >= v,
                     Axis::Y => v0.y >= v || v1.y >= v || v2.y >= v,
                     Axis::Z => v0.z >= v || v1.z >= v || v2.z >= v,
                 }
             }
-            Geometry::Sphere(Sphere { center, radius }) => {
-                let r2 = radius.powi(2);
-
-                match axis {
-                    Axis::X => center.x+r2 >= v,
-                    Axis::Y => center.y+r2 >= v,
-                    Axis::Z => center.z+r2 >= v
-                }
-            }
+            Geometry::Sphere(Sphere { center, radius }) => axis.of(center)+radius >= v,
+            _ => axis.of(&self.fit().max) >= v
         }
     }
 }
 
-impl Object for Geometry {
-    fn intersect(&self, ray: &Ray) -> Option<f32> {
-        if let Some(d) = match self {
-            Geometry::Sphere(s) => s.intersect(&ray),
-            Geometry::Triangle(t) => t.intersect(&ray),
-        } {
-            if d > 0.000001 {
-                return Some(d)
-            }
-        }
 
-        None
-    }
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Axis { X, Y, Z }
 
-    fn normal(&self, point: Vec3<f32>) -> Vec3<f32> {
+impl Axis {
+    pub const ALL: [Axis; 3] = [Axis::X, Axis::Y, Axis::Z];
+
+    /// The component of a vector along this axis
+    pub fn of(self, v: &Vec3<f32>) -> f32 {
         match self {
-            Geometry::Sphere(s) => s.normal(point),
-            Geometry::Triangle(t) => t.normal(point)
+            Axis::X => v.x,
+            Axis::Y => v.y,
+            Axis::Z => v.z
         }
     }
 
+    fn set(self, v: &mut Vec3<f32>, value: f32) {
+        match self {
+            Axis::X => v.x = value,
+            Axis::Y => v.y = value,
+            Axis::Z => v.z = value
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub enum Axis { X, Y, Z }
-
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct AABB {
     pub min: Vec3<f32>,
@@ -250,103 +1082,365 @@ impl AABB {
         }
     }
 
+    /// Split in half along an axis, returning both halves and the split position
     pub fn split(self, axis: Axis) -> (Self, Self, f32) {
-        match axis {
-            Axis::X => {
-                let mid = (self.min.x + self.max.x) / 2.0;
-
-                let l = AABB {
-                    min: self.min,
-                    max: Vec3::new(mid, self.max.y, self.max.z)
-                };
-                let r = AABB {
-                    min: Vec3::new(mid, self.min.y, self.min.z),
-                    max: self.max
-                };
-
-                (l, r, mid)
-            }
-            Axis::Y => {
-                let mid = (self.min.y + self.max.y) / 2.0;
-
-                let l = AABB {
-                    min: self.min,
-                    max: Vec3::new(self.max.x, mid, self.max.z)
-                };
-                let r = AABB {
-                    min: Vec3::new(self.min.x, mid, self.min.z),
-                    max: self.max
-                };
-
-                (l, r, mid)
-            }
-            Axis::Z => {
-                let mid = (self.min.z + self.max.z) / 2.0;
-
-                let l = AABB {
-                    min: self.min,
-                    max: Vec3::new(self.max.x, self.max.y, mid)
-                };
-                let r = AABB {
-                    min: Vec3::new(self.min.x, self.min.y, mid),
-                    max: self.max
-                };
-
-                (l, r, mid)
-            }
-        }
+        let mid = (axis.of(&self.min) + axis.of(&self.max)) / 2.0;
+        let (l, r) = self.split_at(axis, mid);
+
+        (l, r, mid)
+    }
+
+    /// Split with a plane at v along an axis
+    pub fn split_at(self, axis: Axis, v: f32) -> (Self, Self) {
+        let mut l = self;
+        let mut r = self;
+        axis.set(&mut l.max, v);
+        axis.set(&mut r.min, v);
+
+        (l, r)
+    }
+
+    /// The overlap of two boxes, degenerate boxes are flattened rather than inverted
+    pub fn clip(self, other: &Self) -> Self {
+        let min = Vec3::new(
+            self.min.x.max(other.min.x),
+            self.min.y.max(other.min.y),
+            self.min.z.max(other.min.z)
+        );
+        let max = Vec3::new(
+            self.max.x.min(other.max.x).max(min.x),
+            self.max.y.min(other.max.y).max(min.y),
+            self.max.z.min(other.max.z).max(min.z)
+        );
+
+        Self { min, max }
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        2.0 * (d.x*d.y + d.y*d.z + d.z*d.x)
     }
 
     pub fn intersect(&self, ray: &Ray) -> bool {
+        self.range(ray).is_some()
+    }
+
+    /// Distances along the ray's line where it enters and leaves the box
+    pub fn range(&self, ray: &Ray) -> Option<(f32, f32)> {
         let mut tmin = f32::NEG_INFINITY;
         let mut tmax = f32::INFINITY;
 
+        for axis in Axis::ALL {
+            let (o, d) = (axis.of(&ray.origin), axis.of(&ray.dir));
+            let (lo, hi) = (axis.of(&self.min), axis.of(&self.max));
 
-        if ray.dir.x == 0.0 {
-            if ray.origin.x < self.min.x || ray.origin.x > self.max.x {
-                return false;
-            }
-        } else {
-            let t1 = (self.min.x - ray.origin.x) / ray.dir.x;
-            let t2 = (self.max.x - ray.origin.x) / ray.dir.x;
-            let (t1, t2) = if t1 > t2 { (t2, t1) } else { (t1, t2) };
-            tmin = tmin.max(t1);
-            tmax = tmax.min(t2);
-            if tmin > tmax {
-                return false;
+            if d == 0.0 {
+                if o < lo || o > hi {
+                    return None;
+                }
+            } else {
+                let t1 = (lo - o) / d;
+                let t2 = (hi - o) / d;
+                let (t1, t2) = if t1 > t2 { (t2, t1) } else { (t1, t2) };
+                tmin = tmin.max(t1);
+                tmax = tmax.min(t2);
+                if tmin > tmax {
+                    return None;
+                }
             }
         }
 
-        if ray.dir.y == 0.0 {
-            if ray.origin.y < self.min.y || ray.origin.y > self.max.y {
-                return false;
-            }
-        } else {
-            let t1 = (self.min.y - ray.origin.y) / ray.dir.y;
-            let t2 = (self.max.y - ray.origin.y) / ray.dir.y;
-            let (t1, t2) = if t1 > t2 { (t2, t1) } else { (t1, t2) };
-            tmin = tmin.max(t1);
-            tmax = tmax.min(t2);
-            if tmin > tmax {
-                return false;
-            }
+        Some((tmin, tmax))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::grid;
+
+    /// Check that every sample lands on g with the density pdf_from gives it,
+    /// and that the densities add up over the solid angle g covers from origin
+    fn check_sampling(g: &Geometry, origin: &Vec3<f32>, solid_angle: f32) {
+        let n = 64;
+        let mut total = 0.0;
+
+        for (u, v) in grid(n) {
+            let s = g.sample_from(origin, u, v).unwrap();
+            let point = *origin + s.dir * s.dist;
+
+            let hit = g.hit(&Ray::new(*origin, s.dir)).unwrap();
+            assert!((hit.dist - s.dist).abs() < 1e-3 * s.dist);
+
+            let pdf = g.pdf_from(origin, &point, &s.normal);
+            assert!((pdf - s.pdf).abs() < 1e-3 * s.pdf, "{pdf} {}", s.pdf);
+            total += 1.0 / s.pdf;
+        }
+
+        let estimate = total / (n * n) as f32;
+        assert!((estimate - solid_angle).abs() < 0.01 * solid_angle, "{estimate} {solid_angle}");
+    }
+
+    #[test]
+    fn sphere_samples_the_cone_it_subtends() {
+        let sphere = Geometry::new_sphere(Vec3::new(0.0, 0.0, -4.0), 1.0);
+        let cos_max = (1.0 - 1.0 / 16.0f32).sqrt();
+
+        check_sampling(&sphere, &Vec3::new(0.0, 0.0, 0.0), 2.0 * PI * (1.0 - cos_max));
+    }
+
+    #[test]
+    fn triangle_samples_by_area() {
+        // Half a face of a cube around the origin covers a twelfth of the sphere
+        let t = Geometry::new_triangle(Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, -1.0, -1.0), Vec3::new(-1.0, 1.0, -1.0));
+
+        check_sampling(&t, &Vec3::new(0.0, 0.0, 0.0), 4.0 * PI / 12.0);
+    }
+
+    #[test]
+    fn mesh_vertex_colors_blend_at_the_hit() {
+        let mesh = Mesh::new(
+            vec![Vec3::new(-1.0, -1.0, 0.0), Vec3::new(1.0, -1.0, 0.0), Vec3::new(-1.0, 1.0, 0.0)],
+            vec![[0, 1, 2]]
+        ).with_colors(vec![Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 1.0)]);
+        let g = Geometry::Mesh(mesh);
+
+        // A third of the way along each edge from the first corner
+        let ray = Ray::new(Vec3::new(-1.0 / 3.0, -1.0 / 3.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let color = g.hit_record(&ray, &g.hit(&ray).unwrap()).color.unwrap();
+        assert!((color - Vec3::new(1.0, 1.0, 1.0) / 3.0).mag() < 1e-5);
+
+        let plain = Geometry::new_sphere(Vec3::new(0.0, 0.0, 0.0), 1.0);
+        assert!(plain.hit_record(&ray, &plain.hit(&ray).unwrap()).color.is_none());
+    }
+
+    /// A bumpy square of 2 n^2 triangles over [-1, 1]^2 facing +z
+    fn bumpy_mesh(n: usize) -> Mesh {
+        let positions = (0..(n + 1) * (n + 1))
+            .map(|i| {
+                let (x, y) = ((i % (n + 1)) as f32 / n as f32 * 2.0 - 1.0, (i / (n + 1)) as f32 / n as f32 * 2.0 - 1.0);
+                Vec3::new(x, y, 0.3 * (3.0 * x).sin() * (2.0 * y).cos())
+            })
+            .collect();
+
+        let triangles = (0..n * n)
+            .flat_map(|i| {
+                let c = i / n * (n + 1) + i % n;
+                [[c, c + 1, c + n + 2], [c, c + n + 2, c + n + 1]]
+            })
+            .collect();
+
+        Mesh::new(positions, triangles)
+    }
+
+    /// Rays from above the square aimed down at it from a spread of angles
+    fn rays_at_square() -> impl Iterator<Item = Ray> {
+        grid(24).map(|(u, v)| {
+            let target = Vec3::new(u * 2.4 - 1.2, v * 2.4 - 1.2, 0.0);
+            let origin = Vec3::new(v * 3.0 - 1.5, 1.0 - u * 2.0, 2.0 + u);
+
+            Ray::new(origin, target - origin)
+        })
+    }
+
+    #[test]
+    fn mesh_hits_match_its_triangles() {
+        let mesh = bumpy_mesh(8);
+        let triangles: Vec<Geometry> = (0..mesh.triangles.len()).map(|i| Geometry::Triangle(mesh.triangle(i))).collect();
+
+        let mut hits = 0;
+        for ray in rays_at_square() {
+            let separate = (0..triangles.len())
+                .filter_map(|i| triangles[i].hit(&ray).map(|h| (i, h.dist)))
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+
+            assert_eq!(mesh.hit(&ray).map(|h| (h.prim, h.dist)), separate);
+            hits += separate.is_some() as usize;
         }
 
-        if ray.dir.z == 0.0 {
-            if ray.origin.z < self.min.z || ray.origin.z > self.max.z {
-                return false;
+        assert!(hits > 300);
+    }
+
+    #[test]
+    fn mesh_samples_by_area() {
+        // A square 2 across seen face on from 4 away
+        let quad = Geometry::Mesh(Mesh::new(
+            vec![Vec3::new(-1.0, -1.0, -4.0), Vec3::new(1.0, -1.0, -4.0), Vec3::new(1.0, 1.0, -4.0), Vec3::new(-1.0, 1.0, -4.0)],
+            vec![[0, 1, 2], [0, 2, 3]]
+        ));
+
+        check_sampling(&quad, &Vec3::new(0.0, 0.0, 0.0), 4.0 * (4.0f32 / 68.0).asin());
+    }
+
+    #[test]
+    fn instances_hit_like_the_moved_mesh() {
+        let t = Transform::rotate(Vec3::new(1.0, 1.0, 0.0), 0.4)
+            .then(&Transform::scale(Vec3::new(1.0, 2.0, 0.5)))
+            .then(&Transform::translate(Vec3::new(0.2, -0.3, 0.1)));
+
+        let instance = Geometry::Instance(Instance::new(Arc::new(Geometry::Mesh(bumpy_mesh(8))), t));
+        let moved = Geometry::Mesh(bumpy_mesh(8).transformed(&t));
+
+        for ray in rays_at_square() {
+            match (instance.hit(&ray), moved.hit(&ray)) {
+                (Some(a), Some(b)) => {
+                    assert_eq!(a.prim, b.prim);
+                    assert!((a.dist - b.dist).abs() < 1e-4 * b.dist);
+
+                    let point = ray.origin + ray.dir * b.dist;
+                    assert!((instance.normal(point, a.prim) - moved.normal(point, b.prim)).mag() < 1e-4);
+                }
+                (None, None) => (),
+                (a, b) => panic!("{a:?} {b:?}")
             }
-        } else {
-            let t1 = (self.min.z - ray.origin.z) / ray.dir.z;
-            let t2 = (self.max.z - ray.origin.z) / ray.dir.z;
-            let (t1, t2) = if t1 > t2 { (t2, t1) } else { (t1, t2) };
-            tmin = tmin.max(t1);
-            tmax = tmax.min(t2);
-            if tmin > tmax {
-                return false;
+        }
+    }
+
+    #[test]
+    fn instance_areas_follow_the_transform() {
+        let instance = |g: Geometry, t: Transform| Instance::new(Arc::new(g), t).area();
+
+        // Triangles stay triangles, a unit square on the x/z plane becomes 2 by 3
+        let square = Mesh::new(
+            vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 1.0), Vec3::new(0.0, 0.0, 1.0)],
+            vec![[0, 1, 2], [0, 2, 3]]
+        );
+        let scale = Transform::scale(Vec3::new(2.0, 1.0, 3.0));
+        assert!((instance(Geometry::Mesh(square), scale) - 6.0).abs() < 1e-4);
+
+        let sphere = instance(Geometry::new_sphere(Vec3::new(0.0, 0.0, 0.0), 1.0), Transform::scale(Vec3::new(3.0, 3.0, 3.0)));
+        assert!((sphere - 36.0 * PI).abs() < 1e-2 * 36.0 * PI);
+
+        // Other shapes average the stretch over a grid of samples, so they only come close
+        let cube = Geometry::Cuboid(Cuboid::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 1.0)));
+        let area = instance(cube, Transform::scale(Vec3::new(2.0, 1.0, 3.0)));
+        assert!((area - 22.0).abs() < 0.05 * 22.0, "{area}");
+    }
+
+    #[test]
+    fn vertex_normals_average_by_area() {
+        // A tent with a ridge along x, one side twice as wide as the other
+        let positions = vec![
+            Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, -1.0, -1.0), Vec3::new(0.0, 2.0, -2.0)
+        ];
+        let triangles = vec![[0, 2, 1], [0, 1, 3]];
+        let normals = vertex_normals(&positions, &triangles);
+
+        let a = (positions[2] - positions[0]).cross(&(positions[1] - positions[0]));
+        let b = (positions[1] - positions[0]).cross(&(positions[3] - positions[0]));
+        assert!((normals[0] - (a + b).normalized()).mag() < 1e-5);
+        assert!((normals[1] - normals[0]).mag() < 1e-5);
+        assert!((normals[2] - a.normalized()).mag() < 1e-5);
+        assert!((normals[3] - b.normalized()).mag() < 1e-5);
+
+        // Positions no triangle uses are left without a normal
+        let normals = vertex_normals(&[Vec3::new(0.0, 0.0, 0.0)], &[]);
+        assert_eq!(normals[0].mag(), 0.0);
+    }
+
+    #[test]
+    fn smooth_triangles_blend_their_vertex_normals() {
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        let tilted = Vec3::new(1.0, 0.0, 1.0).normalized();
+        let t = Triangle::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0))
+            .with_normals([up, tilted, up]);
+
+        assert!((t.shading_normal((0.0, 0.0)) - up).mag() < 1e-6);
+        assert!((t.shading_normal((1.0, 0.0)) - tilted).mag() < 1e-6);
+        assert!((t.shading_normal((0.5, 0.0)) - (up + tilted).normalized()).mag() < 1e-6);
+
+        // Normals that cancel out fall back to the face normal
+        let opposed = Triangle::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0))
+            .with_normals([up, -up, up]);
+        assert!((opposed.shading_normal((0.5, 0.0)) - opposed.normal(Vec3::new(0.0, 0.0, 0.0))).mag() < 1e-6);
+    }
+
+    #[test]
+    fn smooth_meshes_agree_across_shared_edges() {
+        let mesh = Geometry::Mesh(bumpy_mesh(8).smooth());
+        let flat = Geometry::Mesh(bumpy_mesh(8));
+
+        for ray in rays_at_square() {
+            let Some(hit) = mesh.hit(&ray) else { continue };
+            let p = ray.origin + ray.dir * hit.dist;
+
+            // Shading normals stay close to the faces of a gently curved mesh
+            let n = mesh.shading_normal(p, &hit);
+            assert!(n.dot(&flat.normal(p, hit.prim)) > 0.9);
+        }
+
+        // Both triangles of a grid cell meet along its diagonal, check at its middle
+        let Geometry::Mesh(m) = &mesh else { unreachable!() };
+        let (a, b) = (m.shading_normal(&Intersection { dist: 0.0, prim: 0, bary: (0.0, 0.5) }),
+                      m.shading_normal(&Intersection { dist: 0.0, prim: 1, bary: (0.5, 0.0) }));
+        assert!((a - b).mag() < 1e-6);
+    }
+
+    #[test]
+    fn hit_records_have_an_orthonormal_frame_facing_the_ray() {
+        let placed = Transform::rotate(Vec3::new(1.0, 0.0, 1.0), 0.5).then(&Transform::translate(Vec3::new(0.0, 0.0, -1.0)));
+        let shapes = [
+            Geometry::new_sphere(Vec3::new(0.0, 0.0, 0.0), 1.0),
+            Geometry::new_triangle(Vec3::new(-2.0, -2.0, 0.0), Vec3::new(2.0, -2.0, 0.0), Vec3::new(0.0, 2.0, 0.0)),
+            Geometry::Mesh(bumpy_mesh(8).smooth()),
+            Geometry::Instance(Instance::new(Arc::new(Geometry::Mesh(bumpy_mesh(4))), placed)),
+            Geometry::Cylinder(Cylinder::new(Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 1.0), 1.0, 1.5)),
+            Geometry::Torus(Torus::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 1.0, 0.4))
+        ];
+
+        for g in &shapes {
+            let mut hits = 0;
+            for ray in rays_at_square() {
+                let Some(hit) = g.hit(&ray) else { continue };
+                let rec = g.hit_record(&ray, &hit);
+                hits += 1;
+
+                assert!((rec.p - (ray.origin + ray.dir * hit.dist)).mag() < 1e-5);
+                assert!(rec.ng.dot(&ray.dir) <= 0.0 && rec.n.dot(&rec.ng) >= 0.0);
+
+                for v in [rec.n, rec.ng, rec.tangent, rec.bitangent] {
+                    assert!((v.mag() - 1.0).abs() < 1e-4, "{g:?}");
+                }
+                for (a, b) in [(rec.n, rec.tangent), (rec.n, rec.bitangent), (rec.tangent, rec.bitangent)] {
+                    assert!(a.dot(&b).abs() < 1e-4, "{g:?}");
+                }
             }
+
+            assert!(hits > 100, "{g:?}");
         }
+    }
+
+    #[test]
+    fn hit_records_tell_inside_from_outside() {
+        let sphere = Geometry::new_sphere(Vec3::new(0.0, 0.0, 0.0), 1.0);
+
+        let outside = Ray::new(Vec3::new(0.0, 3.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let rec = sphere.hit_record(&outside, &sphere.hit(&outside).unwrap());
+        assert!(rec.front_face && !rec.inside);
+        assert!((rec.ng - Vec3::new(0.0, 1.0, 0.0)).mag() < 1e-5);
+        assert!((rec.uv.1 - 1.0).abs() < 1e-3);
+
+        // From the center the normals are turned to face back at the ray
+        let inside = Ray::inside(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let rec = sphere.hit_record(&inside, &sphere.hit(&inside).unwrap());
+        assert!(!rec.front_face && rec.inside);
+        assert!((rec.ng - Vec3::new(0.0, 1.0, 0.0)).mag() < 1e-5);
+        assert!(rec.uv.1 < 1e-3);
+    }
+
+    #[test]
+    fn instance_hit_records_keep_the_local_point() {
+        let t = Transform::scale(Vec3::new(2.0, 2.0, 2.0)).then(&Transform::translate(Vec3::new(5.0, 0.0, 0.0)));
+        let g = Geometry::Instance(Instance::new(Arc::new(Geometry::new_sphere(Vec3::new(0.0, 0.0, 0.0), 1.0)), t));
+
+        let ray = Ray::new(Vec3::new(5.0, 0.0, 10.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = g.hit(&ray).unwrap();
+        let rec = g.hit_record(&ray, &hit);
 
-        true
+        assert!((hit.dist - 8.0).abs() < 1e-4);
+        assert!((rec.p - Vec3::new(5.0, 0.0, 2.0)).mag() < 1e-4);
+        assert!((rec.local - Vec3::new(0.0, 0.0, 1.0)).mag() < 1e-4);
+        assert!((rec.n - Vec3::new(0.0, 0.0, 1.0)).mag() < 1e-4);
     }
 }
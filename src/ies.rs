@@ -0,0 +1,237 @@
+use serde::{Serialize, Deserialize};
+
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum IesError {
+    Io(std::io::Error),
+    /// Malformed photometric data
+    Data(String)
+}
+
+impl fmt::Display for IesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IesError::Io(e) => write!(f, "{e}"),
+            IesError::Data(msg) => write!(f, "{msg}")
+        }
+    }
+}
+
+impl std::error::Error for IesError {}
+
+impl From<std::io::Error> for IesError {
+    fn from(e: std::io::Error) -> Self {
+        IesError::Io(e)
+    }
+}
+
+/// Candela distribution of a light fixture from an IES LM-63 file, scaled so
+/// the brightest direction is 1. Angles are in degrees, vertical angles from
+/// straight down and horizontal angles around the fixture's axis.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IesProfile {
+    vertical: Vec<f32>,
+    horizontal: Vec<f32>,
+    /// One row of vertical samples per horizontal angle
+    candela: Vec<f32>
+}
+
+/// Linear interpolation position of x in the increasing values, None outside them
+fn locate(values: &[f32], x: f32) -> Option<(usize, f32)> {
+    let (first, last) = (*values.first()?, *values.last()?);
+    if x < first || x > last {
+        return None;
+    }
+    if values.len() == 1 {
+        return Some((0, 0.0));
+    }
+
+    let i = values.partition_point(|&v| v <= x).clamp(1, values.len() - 1) - 1;
+    let span = values[i+1] - values[i];
+    let t = if span > 0.0 { (x - values[i]) / span } else { 0.0 };
+
+    Some((i, t.clamp(0.0, 1.0)))
+}
+
+impl IesProfile {
+    fn value(&self, h: usize, v: usize) -> f32 {
+        self.candela[h * self.vertical.len() + v]
+    }
+
+    /// Intensity averaged over the stored samples, a rough fraction of the
+    /// power a uniform light of the peak intensity would give off
+    pub fn average(&self) -> f32 {
+        if self.candela.is_empty() {
+            0.0
+        } else {
+            self.candela.iter().sum::<f32>() / self.candela.len() as f32
+        }
+    }
+
+    /// Relative intensity towards vertical angle theta and horizontal angle phi
+    pub fn intensity(&self, theta: f32, phi: f32) -> f32 {
+        if self.candela.is_empty() {
+            return 0.0;
+        }
+
+        // Fixtures that are symmetric only store part of the way around
+        let phi = phi.rem_euclid(360.0);
+        let phi = match self.horizontal.last() {
+            Some(&last) if last <= 0.0 => 0.0,
+            Some(&last) if last <= 90.0 => {
+                let p = if phi > 180.0 { 360.0 - phi } else { phi };
+                if p > 90.0 { 180.0 - p } else { p }
+            }
+            Some(&last) if last <= 180.0 => if phi > 180.0 { 360.0 - phi } else { phi },
+            _ => phi
+        };
+
+        let (v, tv) = match locate(&self.vertical, theta) {
+            Some(l) => l,
+            None => return 0.0
+        };
+        let (h, th) = locate(&self.horizontal, phi).unwrap_or((0, 0.0));
+
+        let v1 = (v + 1).min(self.vertical.len() - 1);
+        let h1 = (h + 1).min(self.horizontal.len() - 1);
+
+        let near = self.value(h, v) * (1.0 - tv) + self.value(h, v1) * tv;
+        let far = self.value(h1, v) * (1.0 - tv) + self.value(h1, v1) * tv;
+
+        near * (1.0 - th) + far * th
+    }
+}
+
+/// Parse the contents of an IES file, only the photometric web is kept
+pub fn parse_ies(src: &str) -> Result<IesProfile, IesError> {
+    let err = |msg: &str| IesError::Data(msg.to_string());
+
+    // Keyword lines run up to the tilt line, the numbers follow it
+    let mut lines = src.lines();
+    let tilt = lines.by_ref()
+        .find(|l| l.trim_start().starts_with("TILT="))
+        .ok_or_else(|| err("missing TILT= line"))?;
+
+    let rest: Vec<&str> = lines.collect();
+    let mut numbers = rest.iter()
+        .flat_map(|l| l.split(|c: char| c.is_ascii_whitespace() || c == ','))
+        .filter(|w| !w.is_empty())
+        .map(|w| w.parse::<f32>().map_err(|_| IesError::Data(format!("`{w}` is not a number"))));
+
+    let mut next = || numbers.next().unwrap_or_else(|| Err(err("file ended before the candela values")));
+
+    // Lamp tilt factors only matter for lamps mounted at an angle, skip them
+    if tilt.trim() == "TILT=INCLUDE" {
+        next()?;
+        let count = next()? as usize;
+        for _ in 0..2*count {
+            next()?;
+        }
+    }
+
+    let _lamps = next()?;
+    let _lumens = next()?;
+    let multiplier = next()?;
+    let n_vertical = next()? as usize;
+    let n_horizontal = next()? as usize;
+    let _photometric_type = next()?;
+    let _units = next()?;
+    let (_width, _length, _height) = (next()?, next()?, next()?);
+    let (_ballast, _future, _watts) = (next()?, next()?, next()?);
+
+    if n_vertical == 0 || n_horizontal == 0 {
+        return Err(err("no candela values"));
+    }
+
+    let vertical = (0..n_vertical).map(|_| next()).collect::<Result<Vec<_>, _>>()?;
+    let horizontal = (0..n_horizontal).map(|_| next()).collect::<Result<Vec<_>, _>>()?;
+    let mut candela = (0..n_vertical*n_horizontal)
+        .map(|_| next().map(|c| (c * multiplier).max(0.0)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if vertical.windows(2).any(|w| w[0] > w[1]) || horizontal.windows(2).any(|w| w[0] > w[1]) {
+        return Err(err("angles must be increasing"));
+    }
+
+    let peak = candela.iter().cloned().fold(0.0, f32::max);
+    if peak > 0.0 {
+        for c in candela.iter_mut() {
+            *c /= peak;
+        }
+    }
+
+    Ok(IesProfile { vertical, horizontal, candela })
+}
+
+pub fn load_ies<P: AsRef<Path>>(path: P) -> Result<IesProfile, IesError> {
+    // Files are usually latin-1, anything outside ascii is only in the keywords
+    let bytes = std::fs::read(path)?;
+    parse_ies(&String::from_utf8_lossy(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fixture pointing straight down, symmetric about its axis
+    const ROUND: &str = "IESNA:LM-63-2002\n[TEST] round\nTILT=NONE\n\
+        1 1000 2 3 1 1 2 0 0 0\n\
+        1.0 1.0 100\n\
+        0 45 90\n\
+        0\n\
+        50 25 0\n";
+
+    fn data_error(src: &str) -> String {
+        match parse_ies(src) {
+            Err(IesError::Data(msg)) => msg,
+            r => panic!("expected a data error, got {r:?}")
+        }
+    }
+
+    #[test]
+    fn round_profiles_interpolate_between_vertical_angles() {
+        let p = parse_ies(ROUND).unwrap();
+
+        assert_eq!(p.intensity(0.0, 0.0), 1.0);
+        assert_eq!(p.intensity(22.5, 0.0), 0.75);
+        assert_eq!(p.intensity(45.0, 123.0), 0.5);
+        assert_eq!(p.intensity(120.0, 0.0), 0.0);
+        assert!((p.average() - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn quadrant_profiles_are_mirrored_around() {
+        // Brighter towards phi = 0 than phi = 90, with the multiplier and tilt data to skip
+        let src = "IESNA:LM-63-1995\nTILT=INCLUDE\n1\n2\n0 90\n1 1\n\
+            1 -1 0.5 2 2 1 2 0 0 0 1 1 60\n\
+            0, 90\n0, 90\n\
+            40, 10\n20, 10\n";
+        let p = parse_ies(src).unwrap();
+
+        for phi in [0.0, 180.0, 360.0, -180.0] {
+            assert!((p.intensity(0.0, phi) - 1.0).abs() < 1e-6, "{phi}");
+        }
+        for phi in [90.0, 270.0] {
+            assert!((p.intensity(0.0, phi) - 0.5).abs() < 1e-6, "{phi}");
+        }
+        assert!((p.intensity(0.0, 135.0) - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn bad_files_are_rejected() {
+        assert_eq!(data_error("IESNA:LM-63-2002\n1 2 3\n"), "missing TILT= line");
+        assert_eq!(data_error(&ROUND.replace("50 25 0", "50 x 0")), "`x` is not a number");
+        assert_eq!(data_error(&ROUND.replace("50 25 0", "50 25")), "file ended before the candela values");
+        assert_eq!(data_error(&ROUND.replace("0 45 90", "0 90 45")), "angles must be increasing");
+        assert_eq!(data_error(&ROUND.replace("2 3 1 1", "2 0 1 1")), "no candela values");
+    }
+
+    #[test]
+    fn empty_profiles_give_no_light() {
+        let p = IesProfile::default();
+        assert_eq!(p.intensity(0.0, 0.0), 0.0);
+        assert_eq!(p.average(), 0.0);
+    }
+}
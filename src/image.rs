@@ -0,0 +1,737 @@
+use crate::vector::Vec3;
+
+use std::io::{self, Read, BufRead, Write, BufReader, BufWriter, Cursor, Seek};
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Serialize, Deserialize};
+
+/// A linear image as produced by the renderer, rows from top to bottom
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec3<f32>>
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    /// Binary P6 ppm
+    Ppm,
+    Png8,
+    Png16,
+    /// Radiance rgbe, written without tone mapping
+    Hdr,
+    /// Portable float map, written without tone mapping
+    Pfm,
+    /// OpenEXR with float channels, written without tone mapping
+    Exr
+}
+
+impl Format {
+    /// Guess the format from a file extension
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+
+        match ext.as_str() {
+            "ppm" => Some(Format::Ppm),
+            "png" => Some(Format::Png8),
+            "hdr" | "pic" => Some(Format::Hdr),
+            "pfm" => Some(Format::Pfm),
+            "exr" => Some(Format::Exr),
+            _ => None
+        }
+    }
+
+    /// Whether the format stores linear radiance rather than display values
+    pub fn is_hdr(&self) -> bool {
+        matches!(self, Format::Hdr | Format::Pfm | Format::Exr)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Encode a linear [0..1] value with the srgb transfer curve
+pub fn srgb_encode(v: f32) -> f32 {
+    let v = v.clamp(0.0, 1.0);
+
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Decode an srgb encoded [0..1] value back to linear
+pub fn srgb_decode(v: f32) -> f32 {
+    let v = v.clamp(0.0, 1.0);
+
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn quantize(v: f32, max: f32) -> f32 {
+    (srgb_encode(v) * max).round()
+}
+
+pub fn write_ppm<W: Write>(out: &mut W, img: &[Vec3<f32>], w: usize, h: usize) -> io::Result<()> {
+    write!(out, "P6\n{w} {h}\n255\n")?;
+
+    let data: Vec<u8> = img[..w*h].iter()
+        .flat_map(|c| [c.x, c.y, c.z])
+        .map(|v| quantize(v, 255.0) as u8)
+        .collect();
+
+    out.write_all(&data)
+}
+
+pub fn write_png<W: Write>(out: &mut W, img: &[Vec3<f32>], w: usize, h: usize, sixteen_bit: bool) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(out, w as u32, h as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+
+    let values = img[..w*h].iter().flat_map(|c| [c.x, c.y, c.z]);
+    let data: Vec<u8> = if sixteen_bit {
+        encoder.set_depth(png::BitDepth::Sixteen);
+        values.flat_map(|v| (quantize(v, 65535.0) as u16).to_be_bytes()).collect()
+    } else {
+        encoder.set_depth(png::BitDepth::Eight);
+        values.map(|v| quantize(v, 255.0) as u8).collect()
+    };
+
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer.write_image_data(&data).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)
+}
+
+/// Shared exponent encoding of a radiance value
+fn rgbe(c: &Vec3<f32>) -> [u8; 4] {
+    let m = c.max_component();
+    if m < 1e-32 {
+        return [0; 4];
+    }
+
+    // Find e such that m = f * 2^e with f in [0.5, 1)
+    let e = m.log2().floor() as i32 + 1;
+    let scale = 256.0 / 2f32.powi(e);
+
+    [
+        (c.x.max(0.0) * scale) as u8,
+        (c.y.max(0.0) * scale) as u8,
+        (c.z.max(0.0) * scale) as u8,
+        (e + 128) as u8
+    ]
+}
+
+pub fn write_hdr<W: Write>(out: &mut W, img: &[Vec3<f32>], w: usize, h: usize) -> io::Result<()> {
+    write!(out, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {h} +X {w}\n")?;
+
+    let data: Vec<u8> = img[..w*h].iter().flat_map(rgbe).collect();
+    out.write_all(&data)
+}
+
+/// Float rgb rows from bottom to top, a negative scale marks little endian
+pub fn write_pfm<W: Write>(out: &mut W, img: &[Vec3<f32>], w: usize, h: usize) -> io::Result<()> {
+    write!(out, "PF\n{w} {h}\n-1.0\n")?;
+
+    let data: Vec<u8> = (0..h).rev()
+        .flat_map(|row| img[row*w..(row+1)*w].iter())
+        .flat_map(|c| [c.x, c.y, c.z])
+        .flat_map(f32::to_le_bytes)
+        .collect();
+
+    out.write_all(&data)
+}
+
+pub fn write_exr<W: Write + Seek>(out: &mut W, img: &[Vec3<f32>], w: usize, h: usize) -> io::Result<()> {
+    use exr::prelude::{Image as ExrImage, SpecificChannels, Vec2, WritableImage};
+
+    let channels = SpecificChannels::rgb(|Vec2(x, y): Vec2<usize>| {
+        let c = img[y*w+x];
+        (c.x, c.y, c.z)
+    });
+
+    ExrImage::from_channels((w, h), channels)
+        .write()
+        .to_buffered(out)
+        .map_err(io::Error::other)
+}
+
+pub fn write<W: Write>(out: &mut W, img: &[Vec3<f32>], w: usize, h: usize, format: Format) -> io::Result<()> {
+    match format {
+        Format::Ppm => write_ppm(out, img, w, h),
+        Format::Png8 => write_png(out, img, w, h, false),
+        Format::Png16 => write_png(out, img, w, h, true),
+        Format::Hdr => write_hdr(out, img, w, h),
+        Format::Pfm => write_pfm(out, img, w, h),
+        Format::Exr => {
+            // Exr needs to seek while writing
+            let mut buf = Cursor::new(Vec::new());
+            write_exr(&mut buf, img, w, h)?;
+            out.write_all(buf.get_ref())
+        }
+    }
+}
+
+/// Write an image to a file, ldr formats expect tone mapped values in [0..1]
+pub fn save<P: AsRef<Path>>(path: P, img: &[Vec3<f32>], w: usize, h: usize, format: Format) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write(&mut out, img, w, h, format)?;
+    out.flush()
+}
+
+/// Read the next whitespace separated header token, skipping # comments
+fn read_token<R: BufRead>(r: &mut R) -> io::Result<String> {
+    let mut token = String::new();
+    let mut byte = [0u8];
+    let mut comment = false;
+
+    loop {
+        if r.read(&mut byte)? == 0 {
+            if token.is_empty() {
+                return Err(invalid("unexpected end of header"));
+            }
+            return Ok(token);
+        }
+
+        let c = byte[0] as char;
+        if comment {
+            comment = c != '\n';
+        } else if c == '#' && token.is_empty() {
+            comment = true;
+        } else if c.is_ascii_whitespace() {
+            // The single whitespace after the last token is consumed here
+            if !token.is_empty() {
+                return Ok(token);
+            }
+        } else {
+            token.push(c);
+        }
+    }
+}
+
+fn read_number<R: BufRead, T: FromStr>(r: &mut R) -> io::Result<T> {
+    let t = read_token(r)?;
+    t.parse().map_err(|_| invalid(&format!("`{t}` is not a number")))
+}
+
+/// Bytes taken up by the pixels of a width by height image, checked since the
+/// dimensions come from the file
+fn data_size(width: usize, height: usize, bytes_per_pixel: usize) -> io::Result<usize> {
+    width.checked_mul(height)
+        .and_then(|n| n.checked_mul(bytes_per_pixel))
+        .ok_or_else(|| invalid("image dimensions are too large"))
+}
+
+/// Read exactly len bytes, the buffer only grows as data arrives so a bad
+/// header can't ask for more memory than the file holds
+fn read_data<R: Read>(r: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    r.take(len as u64).read_to_end(&mut data)?;
+
+    if data.len() < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "image data is truncated"));
+    }
+    Ok(data)
+}
+
+pub fn read_pfm<R: BufRead>(r: &mut R) -> io::Result<Image> {
+    let channels = match read_token(r)?.as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid("not a pfm file"))
+    };
+
+    let width: usize = read_number(r)?;
+    let height: usize = read_number(r)?;
+    let scale: f32 = read_number(r)?;
+    if scale == 0.0 || !scale.is_finite() {
+        return Err(invalid("pfm scale must be a nonzero number"));
+    }
+
+    let data = read_data(r, data_size(width, height, channels * 4)?)?;
+
+    // The scale's sign gives the byte order and its size multiplies every value
+    let values: Vec<f32> = data.chunks_exact(4)
+        .map(|b| {
+            let b = b.try_into().unwrap();
+            let v = if scale < 0.0 { f32::from_le_bytes(b) } else { f32::from_be_bytes(b) };
+            v * scale.abs()
+        })
+        .collect();
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in (0..height).rev() {
+        for col in 0..width {
+            let i = (row * width + col) * channels;
+            pixels.push(if channels == 3 {
+                Vec3::new(values[i], values[i+1], values[i+2])
+            } else {
+                Vec3::new(values[i], values[i], values[i])
+            });
+        }
+    }
+
+    Ok(Image { width, height, pixels })
+}
+
+/// Read a binary P6 or plain P3 ppm, or their greyscale P5 and P2 versions.
+/// Values are taken to be srgb encoded and are converted to linear
+pub fn read_ppm<R: BufRead>(r: &mut R) -> io::Result<Image> {
+    let (channels, binary) = match read_token(r)?.as_str() {
+        "P6" => (3, true),
+        "P5" => (1, true),
+        "P3" => (3, false),
+        "P2" => (1, false),
+        _ => return Err(invalid("not a ppm file"))
+    };
+
+    let width: usize = read_number(r)?;
+    let height: usize = read_number(r)?;
+    let max: u32 = read_number(r)?;
+    if max == 0 || max > 65535 {
+        return Err(invalid("ppm maximum value must be between 1 and 65535"));
+    }
+
+    let count = data_size(width, height, channels)?;
+    let values: Vec<u32> = if !binary {
+        (0..count).map(|_| read_number(r)).collect::<io::Result<_>>()?
+    } else if max < 256 {
+        read_data(r, count)?.into_iter().map(u32::from).collect()
+    } else {
+        read_data(r, data_size(width, height, channels * 2)?)?
+            .chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as u32).collect()
+    };
+
+    let value = |v: u32| srgb_decode(v as f32 / max as f32);
+    let pixels = values.chunks_exact(channels)
+        .map(|c| if channels == 3 {
+            Vec3::new(value(c[0]), value(c[1]), value(c[2]))
+        } else {
+            let v = value(c[0]);
+            Vec3::new(v, v, v)
+        })
+        .collect();
+
+    Ok(Image { width, height, pixels })
+}
+
+/// Read a png of any bit depth or color type, alpha is dropped and values are
+/// taken to be srgb encoded and are converted to linear
+pub fn read_png<R: BufRead + Seek>(r: R) -> io::Result<Image> {
+    let mut decoder = png::Decoder::new(r);
+    // Palettes and low bit depths are expanded to 8 bit channels
+    decoder.set_transformations(png::Transformations::EXPAND);
+
+    let mut reader = decoder.read_info().map_err(io::Error::other)?;
+    let size = reader.output_buffer_size().ok_or_else(|| invalid("png is too large"))?;
+    let mut data = vec![0u8; size];
+    let info = reader.next_frame(&mut data).map_err(io::Error::other)?;
+
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::Rgb => 3,
+        png::ColorType::Rgba => 4,
+        png::ColorType::Indexed => return Err(invalid("png palette was not expanded"))
+    };
+
+    let (width, height) = (info.width as usize, info.height as usize);
+    let sixteen_bit = info.bit_depth == png::BitDepth::Sixteen;
+    let bytes = if sixteen_bit { 2 } else { 1 };
+
+    let value = |row: &[u8], i: usize| if sixteen_bit {
+        srgb_decode(u16::from_be_bytes([row[2*i], row[2*i+1]]) as f32 / 65535.0)
+    } else {
+        srgb_decode(row[i] as f32 / 255.0)
+    };
+
+    if info.line_size < width * channels * bytes || data.len() < info.line_size * height {
+        return Err(invalid("png data is truncated"));
+    }
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in data.chunks(info.line_size).take(height) {
+        for col in 0..width {
+            let i = col * channels;
+            pixels.push(if channels >= 3 {
+                Vec3::new(value(row, i), value(row, i+1), value(row, i+2))
+            } else {
+                let v = value(row, i);
+                Vec3::new(v, v, v)
+            });
+        }
+    }
+
+    Ok(Image { width, height, pixels })
+}
+
+fn from_rgbe(p: &[u8]) -> Vec3<f32> {
+    if p[3] == 0 {
+        return Vec3::new(0.0, 0.0, 0.0);
+    }
+
+    // Mantissas were truncated, so take the middle of each step but keep zero at zero
+    let f = 2f32.powi(p[3] as i32 - (128 + 8));
+    let m = |b: u8| if b == 0 { 0.0 } else { (b as f32 + 0.5) * f };
+
+    Vec3::new(m(p[0]), m(p[1]), m(p[2]))
+}
+
+pub fn read_hdr<R: BufRead>(r: &mut R) -> io::Result<Image> {
+    let mut line = String::new();
+    r.read_line(&mut line)?;
+    if !line.starts_with("#?") {
+        return Err(invalid("not a radiance hdr file"));
+    }
+
+    // Header variables end at an empty line
+    loop {
+        line.clear();
+        if r.read_line(&mut line)? == 0 {
+            return Err(invalid("unexpected end of header"));
+        }
+
+        let l = line.trim();
+        if l.is_empty() {
+            break;
+        }
+
+        if let Some(f) = l.strip_prefix("FORMAT=") {
+            if f != "32-bit_rle_rgbe" {
+                return Err(invalid(&format!("unsupported format {f}")));
+            }
+        }
+    }
+
+    line.clear();
+    r.read_line(&mut line)?;
+    let (height, width) = match line.split_ascii_whitespace().collect::<Vec<_>>().as_slice() {
+        ["-Y", h, "+X", w] => (
+            h.parse::<usize>().map_err(|_| invalid("bad resolution"))?,
+            w.parse::<usize>().map_err(|_| invalid("bad resolution"))?
+        ),
+        _ => return Err(invalid("only -Y h +X w images are supported"))
+    };
+
+    let row_size = data_size(width, 1, 4)?;
+    if width == 0 {
+        return Ok(Image { width, height, pixels: Vec::new() });
+    }
+
+    let mut pixels = Vec::new();
+    let mut scanline = Vec::new();
+
+    for _ in 0..height {
+        let mut start = [0u8; 4];
+        r.read_exact(&mut start)?;
+
+        let rle = start[0] == 2 && start[1] == 2 && ((start[2] as usize) << 8 | start[3] as usize) == width
+            && (8..32768).contains(&width);
+
+        if rle {
+            scanline.resize(row_size, 0);
+
+            // Each channel is run length encoded separately
+            for c in 0..4 {
+                let mut x = 0;
+                while x < width {
+                    let mut count = [0u8; 2];
+                    r.read_exact(&mut count[..1])?;
+
+                    if count[0] > 128 {
+                        let n = count[0] as usize - 128;
+                        if x + n > width {
+                            return Err(invalid("bad scanline run"));
+                        }
+
+                        r.read_exact(&mut count[1..])?;
+                        for i in 0..n {
+                            scanline[(x + i) * 4 + c] = count[1];
+                        }
+                        x += n;
+                    } else {
+                        let n = count[0] as usize;
+                        if n == 0 || x + n > width {
+                            return Err(invalid("bad scanline run"));
+                        }
+
+                        let mut buf = vec![0u8; n];
+                        r.read_exact(&mut buf)?;
+                        for (i, b) in buf.into_iter().enumerate() {
+                            scanline[(x + i) * 4 + c] = b;
+                        }
+                        x += n;
+                    }
+                }
+            }
+        } else {
+            scanline.clear();
+            scanline.extend_from_slice(&start);
+            scanline.extend(read_data(r, row_size - 4)?);
+        }
+
+        pixels.extend(scanline.chunks_exact(4).map(from_rgbe));
+    }
+
+    Ok(Image { width, height, pixels })
+}
+
+pub fn read_exr<R: Read + Seek>(r: R) -> io::Result<Image> {
+    use exr::prelude::{read, ReadChannels, ReadLayers, Vec2};
+
+    let img = read()
+        .no_deep_data()
+        .largest_resolution_level()
+        .rgb_channels(
+            |Vec2(w, h), _| Image { width: w, height: h, pixels: vec![Vec3::new(0.0, 0.0, 0.0); w*h] },
+            |img: &mut Image, Vec2(x, y), (r, g, b): (f32, f32, f32)| {
+                let w = img.width;
+                img.pixels[y*w+x] = Vec3::new(r, g, b);
+            }
+        )
+        .first_valid_layer()
+        .all_attributes()
+        .from_buffered(r)
+        .map_err(io::Error::other)?;
+
+    Ok(img.layer_data.channel_data.pixels)
+}
+
+/// Load an image, the format is taken from the file extension
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Image> {
+    let path = path.as_ref();
+    let format = Format::from_path(path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "unknown image extension"))?;
+
+    let mut r = BufReader::new(File::open(path)?);
+
+    match format {
+        Format::Hdr => read_hdr(&mut r),
+        Format::Pfm => read_pfm(&mut r),
+        Format::Exr => read_exr(r),
+        Format::Ppm => read_ppm(&mut r),
+        Format::Png8 | Format::Png16 => read_png(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2 by 2 image: black, white, mid grey and pure red
+    fn pixels() -> Vec<Vec3<f32>> {
+        vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 1.0), Vec3::new(0.5, 0.5, 0.5), Vec3::new(1.0, 0.0, 0.0)]
+    }
+
+    fn encode(format: Format) -> Vec<u8> {
+        let mut out = Vec::new();
+        write(&mut out, &pixels(), 2, 2, format).unwrap();
+        out
+    }
+
+    #[test]
+    fn formats_are_guessed_from_extensions() {
+        assert_eq!(Format::from_path("a/b.PNG"), Some(Format::Png8));
+        assert_eq!(Format::from_path("image.pic"), Some(Format::Hdr));
+        assert_eq!(Format::from_path("render.exr"), Some(Format::Exr));
+        assert_eq!(Format::from_path("notes.txt"), None);
+        assert_eq!(Format::from_path("ppm"), None);
+    }
+
+    #[test]
+    fn ppm_is_binary_and_srgb_encoded() {
+        let out = encode(Format::Ppm);
+        let header = b"P6\n2 2\n255\n";
+
+        assert_eq!(&out[..header.len()], header);
+        assert_eq!(&out[header.len()..], &[0, 0, 0, 255, 255, 255, 188, 188, 188, 255, 0, 0]);
+    }
+
+    #[test]
+    fn png_holds_the_same_values_as_ppm() {
+        for (format, depth) in [(Format::Png8, png::BitDepth::Eight), (Format::Png16, png::BitDepth::Sixteen)] {
+            let out = encode(format);
+            let mut reader = png::Decoder::new(Cursor::new(out)).read_info().unwrap();
+            let mut data = vec![0; reader.output_buffer_size().unwrap()];
+            let info = reader.next_frame(&mut data).unwrap();
+
+            assert_eq!((info.width, info.height, info.bit_depth), (2, 2, depth));
+
+            // The first byte of each sample matches the 8 bit value
+            let step = if depth == png::BitDepth::Sixteen { 2 } else { 1 };
+            let firsts: Vec<u8> = data.iter().step_by(step).copied().collect();
+            assert_eq!(firsts, [0, 0, 0, 255, 255, 255, 188, 188, 188, 255, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn rgbe_shares_one_exponent() {
+        assert_eq!(rgbe(&Vec3::new(0.0, 0.0, 0.0)), [0, 0, 0, 0]);
+        assert_eq!(rgbe(&Vec3::new(1.0, 0.5, 0.25)), [128, 64, 32, 129]);
+        assert_eq!(rgbe(&Vec3::new(1000.0, 0.0, 0.0)), [250, 0, 0, 138]);
+
+        let out = encode(Format::Hdr);
+        let header = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 2\n";
+        assert_eq!(&out[..header.len()], header);
+        assert_eq!(out.len(), header.len() + 16);
+    }
+
+    fn error_kind<T: std::fmt::Debug>(res: io::Result<T>) -> (io::ErrorKind, String) {
+        let e = res.unwrap_err();
+        (e.kind(), e.to_string())
+    }
+
+    fn assert_close(a: &[Vec3<f32>], b: &[Vec3<f32>], tolerance: f32) {
+        assert_eq!(a.len(), b.len());
+        for (x, y) in a.iter().zip(b) {
+            assert!((*x - *y).mag() <= tolerance * y.max_component().max(1.0), "{x:?} {y:?}");
+        }
+    }
+
+    #[test]
+    fn pfm_and_exr_round_trip_exactly() {
+        let img = vec![Vec3::new(0.0, 1.5, -2.0), Vec3::new(1e6, 3.25, 0.125), Vec3::new(7.0, 8.0, 9.0)];
+
+        let mut pfm = Vec::new();
+        write_pfm(&mut pfm, &img, 1, 3).unwrap();
+        let back = read_pfm(&mut Cursor::new(pfm)).unwrap();
+        assert_eq!((back.width, back.height), (1, 3));
+        assert_close(&back.pixels, &img, 0.0);
+
+        let mut exr = Cursor::new(Vec::new());
+        write_exr(&mut exr, &img, 3, 1).unwrap();
+        exr.set_position(0);
+        let back = read_exr(exr).unwrap();
+        assert_eq!((back.width, back.height), (3, 1));
+        assert_close(&back.pixels, &img, 0.0);
+    }
+
+    #[test]
+    fn pfm_scale_sets_byte_order_and_size() {
+        // Big endian greyscale with its bottom row first
+        let mut src = b"Pf\n1 2\n2.0\n".to_vec();
+        src.extend(1.0f32.to_be_bytes());
+        src.extend(3.0f32.to_be_bytes());
+
+        let img = read_pfm(&mut Cursor::new(src)).unwrap();
+        assert_close(&img.pixels, &[Vec3::new(6.0, 6.0, 6.0), Vec3::new(2.0, 2.0, 2.0)], 0.0);
+    }
+
+    #[test]
+    fn bad_pfm_headers_are_rejected() {
+        let read = |src: &[u8]| error_kind(read_pfm(&mut Cursor::new(src.to_vec())));
+
+        assert_eq!(read(b"PF\n1 1\n0\n\0\0\0\0"), (io::ErrorKind::InvalidData, "pfm scale must be a nonzero number".to_string()));
+        assert_eq!(read(b"PF\n1 1\nnan\n").0, io::ErrorKind::InvalidData);
+        assert_eq!(read(b"PF\n99999999999 99999999999\n-1\n"), (io::ErrorKind::InvalidData, "image dimensions are too large".to_string()));
+        assert_eq!(read(b"PF\n100000 100000\n-1\n\0\0\0\0").0, io::ErrorKind::UnexpectedEof);
+        assert_eq!(read(b"P6\n1 1\n255\n").1, "not a pfm file");
+    }
+
+    #[test]
+    fn hdr_round_trips_within_rgbe_precision() {
+        let img = vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.5, 0.25), Vec3::new(1000.0, 2.0, 0.01), Vec3::new(0.3, 0.3, 0.3)];
+
+        let mut hdr = Vec::new();
+        write_hdr(&mut hdr, &img, 2, 2).unwrap();
+        let back = read_hdr(&mut Cursor::new(hdr)).unwrap();
+
+        assert_eq!((back.width, back.height), (2, 2));
+        assert_close(&back.pixels, &img, 1.0 / 128.0);
+    }
+
+    #[test]
+    fn hdr_run_length_scanlines_are_decoded() {
+        let mut src = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\nEXPOSURE=1\n\n-Y 1 +X 8\n".to_vec();
+        src.extend([2, 2, 0, 8]);
+        // Red as a run of 8, green as literals, blue as runs of 3 and 5, exponent as one run
+        src.extend([128 + 8, 128]);
+        src.extend([8, 0, 16, 32, 48, 64, 80, 96, 112]);
+        src.extend([128 + 3, 0, 128 + 5, 64]);
+        src.extend([128 + 8, 129]);
+
+        let img = read_hdr(&mut Cursor::new(src)).unwrap();
+        assert_eq!(img.pixels.len(), 8);
+        assert_eq!(img.pixels[0].x, (128.0 + 0.5) / 128.0);
+        assert_eq!(img.pixels[0].y, 0.0);
+        assert_eq!(img.pixels[2].y, (32.0 + 0.5) / 128.0);
+        assert_eq!(img.pixels[2].z, 0.0);
+        assert_eq!(img.pixels[3].z, (64.0 + 0.5) / 128.0);
+    }
+
+    #[test]
+    fn bad_hdr_files_are_rejected() {
+        let read = |src: &[u8]| error_kind(read_hdr(&mut Cursor::new(src.to_vec())));
+
+        assert_eq!(read(b"P6\n").1, "not a radiance hdr file");
+        assert_eq!(read(b"#?RADIANCE\nFORMAT=32-bit_rle_xyze\n\n").1, "unsupported format 32-bit_rle_xyze");
+        assert_eq!(read(b"#?RADIANCE\n\n+Y 1 +X 1\n").1, "only -Y h +X w images are supported");
+        assert_eq!(read(b"#?RADIANCE\n\n-Y 1 +X 99999999999999999999\n").1, "bad resolution");
+        assert_eq!(read(b"#?RADIANCE\n\n-Y 3 +X 999999999999\nabcd").0, io::ErrorKind::UnexpectedEof);
+
+        // A run past the end of the scanline
+        let mut src = b"#?RADIANCE\n\n-Y 1 +X 8\n".to_vec();
+        src.extend([2, 2, 0, 8, 128 + 9, 0]);
+        assert_eq!(read(&src).1, "bad scanline run");
+    }
+
+    #[test]
+    fn srgb_round_trips() {
+        for i in 0..=100 {
+            let v = i as f32 / 100.0;
+            assert!((srgb_decode(srgb_encode(v)) - v).abs() < 1e-5);
+        }
+
+        // Values outside [0, 1] are clamped first
+        assert!((srgb_encode(2.0) - 1.0).abs() < 1e-6);
+        assert_eq!(srgb_decode(-1.0), 0.0);
+    }
+
+    #[test]
+    fn ppm_reader_takes_every_variant() {
+        let read = |src: &[u8]| read_ppm(&mut Cursor::new(src.to_vec())).unwrap();
+
+        let binary = read(&encode(Format::Ppm));
+        assert_eq!((binary.width, binary.height), (2, 2));
+        // 8 bits can't hold mid grey exactly
+        assert_close(&binary.pixels, &pixels(), 6e-3);
+
+        let plain = read(b"P3\n# a comment\n2 2 255\n0 0 0  255 255 255\n188 188 188  255 0 0\n");
+        assert_close(&plain.pixels, &binary.pixels, 0.0);
+
+        let mut wide = b"P6 2 2 65535\n".to_vec();
+        for v in [0u16, 0, 0, 65535, 65535, 65535, 48192, 48192, 48192, 65535, 0, 0] {
+            wide.extend(v.to_be_bytes());
+        }
+        assert_close(&read(&wide).pixels, &pixels(), 1e-4);
+
+        let grey = read(b"P2 2 1 4 0 4");
+        assert_close(&grey.pixels, &[Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 1.0)], 0.0);
+    }
+
+    #[test]
+    fn bad_ppm_files_are_rejected() {
+        let read = |src: &[u8]| error_kind(read_ppm(&mut Cursor::new(src.to_vec())));
+
+        assert_eq!(read(b"P7\n1 1\n255\n").1, "not a ppm file");
+        assert_eq!(read(b"P6\n1 1\n0\n").1, "ppm maximum value must be between 1 and 65535");
+        assert_eq!(read(b"P6\n2 2\n255\n\0\0\0").0, io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn png_reader_decodes_what_was_written() {
+        for format in [Format::Png8, Format::Png16] {
+            let img = read_png(Cursor::new(encode(format))).unwrap();
+
+            assert_eq!((img.width, img.height), (2, 2));
+            assert_close(&img.pixels, &pixels(), 6e-3);
+        }
+    }
+}
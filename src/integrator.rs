@@ -0,0 +1,263 @@
+use crate::geometry::{Ray, HitRecord};
+use crate::vector::Vec3;
+use crate::world::World;
+use crate::bsdf::Bsdf;
+use crate::sampling;
+
+use serde::{Serialize, Deserialize};
+
+use rand::Rng;
+
+// Ambient term for the whitted integrator
+const KA: f32 = 0.9;
+
+const MAX_RECUR: usize = 7;
+
+// Distance to push secondary rays off of the surface they leave
+const RAY_EPSILON: f32 = 0.0001;
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum Integrator {
+    /// Direct lighting with perfect reflection and refraction
+    Whitted,
+    /// Unbiased monte carlo path tracing, paths longer than rr_depth are
+    /// terminated by russian roulette and max_depth is only a safety net
+    Path { rr_depth: usize, max_depth: usize }
+}
+
+impl Integrator {
+    pub fn path() -> Self {
+        Self::Path { rr_depth: 3, max_depth: 64 }
+    }
+
+    pub fn radiance<R: Rng + ?Sized>(&self, world: &World, ray: &Ray, rng: &mut R) -> Vec3<f32> {
+        match self {
+            Integrator::Whitted => whitted(world, ray, 0, rng),
+            Integrator::Path { rr_depth, max_depth } => path_trace(world, ray, *rr_depth, *max_depth, rng)
+        }
+    }
+}
+
+/// Leave the surface point in direction dir, nudged to the side of the surface the ray travels into
+fn spawn(hit: &HitRecord, dir: Vec3<f32>, inside: bool) -> Ray {
+    let offset = if hit.ng.dot(&dir) > 0.0 { hit.ng * RAY_EPSILON } else { -hit.ng * RAY_EPSILON };
+
+    if inside {
+        Ray::inside(hit.p + offset, dir)
+    } else {
+        Ray::new(hit.p + offset, dir)
+    }
+}
+
+/// Check that nothing blocks the segment leaving the hit point along dir for dist
+fn visible(world: &World, hit: &HitRecord, dir: Vec3<f32>, dist: f32) -> bool {
+    !world.occluded(&spawn(hit, dir, false), dist - 2.0 * RAY_EPSILON)
+}
+
+/// Light arriving directly from one sample of each light the world picks and
+/// scattered towards wo. With mis set, samples of lights that the bsdf could
+/// also hit are weighted against bsdf sampling.
+fn direct_lighting<R: Rng + ?Sized>(
+    world: &World,
+    hit: &HitRecord,
+    wo: &Vec3<f32>,
+    bsdf: &dyn Bsdf,
+    mis: bool,
+    rng: &mut R
+) -> Vec3<f32> {
+    let mut color = Vec3::new(0.0, 0.0, 0.0);
+
+    for (l, pmf) in world.sample_lights(&hit.p, rng) {
+        let ls = match l.sample(world, &hit.p, rng.gen(), rng.gen()) {
+            Some(ls) if ls.pdf > 0.0 && !ls.radiance.is_black() => ls,
+            _ => continue
+        };
+
+        let f = bsdf.evaluate(hit, wo, &ls.dir);
+        if f.is_black() {
+            continue;
+        }
+
+        // If we collide with something first don't add this light
+        if !visible(world, hit, ls.dir, ls.dist) {
+            continue;
+        }
+
+        let weight = if mis && ls.hittable {
+            sampling::power_heuristic(ls.pdf * pmf, bsdf.pdf(hit, wo, &ls.dir))
+        } else {
+            1.0
+        };
+
+        color += f * ls.radiance * (hit.n.dot(&ls.dir) * weight / (ls.pdf * pmf));
+    }
+
+    color
+}
+
+fn whitted<R: Rng + ?Sized>(world: &World, ray: &Ray, depth: usize, rng: &mut R) -> Vec3<f32> {
+    let (id, isect) = match world.intersect(ray) {
+        Some(i) => i,
+        None => return world.background(ray)
+    };
+
+    let hit = world.hit_record(id, ray, &isect);
+    let bsdf = world.bsdf(id);
+    let wo = -ray.dir;
+
+    let mut color = bsdf.emitted(&hit) + bsdf.albedo(&hit) * KA + direct_lighting(world, &hit, &wo, bsdf, false, rng);
+
+    // If at max depth just return local illumination color
+    if depth == MAX_RECUR {
+        return color;
+    }
+
+    // Now apply reflection and transmission
+    for s in bsdf.specular(&hit, &wo) {
+        color += whitted(world, &spawn(&hit, s.dir, s.inside), depth+1, rng) * s.weight;
+    }
+
+    color
+}
+
+fn path_trace<R: Rng + ?Sized>(
+    world: &World,
+    ray: &Ray,
+    rr_depth: usize,
+    max_depth: usize,
+    rng: &mut R
+) -> Vec3<f32> {
+    let mut color = Vec3::new(0.0, 0.0, 0.0);
+    let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+    let mut ray = *ray;
+
+    // Where the previous bounce left from and with what density, to weigh emitters hit by chance
+    let mut prev: Option<(Vec3<f32>, f32)> = None;
+
+    for bounce in 0..=max_depth {
+        let (id, isect) = match world.intersect(&ray) {
+            Some(i) => i,
+            None => {
+                // Environments that are lights were also sampled directly from the previous bounce
+                let weight = match prev {
+                    Some((origin, bsdf_pdf)) if world.environment.is_light() => {
+                        let light_pdf = world.environment.pdf(&ray.dir) * world.environment_pmf(&origin);
+                        sampling::power_heuristic(bsdf_pdf, light_pdf)
+                    }
+                    _ => 1.0
+                };
+
+                color += throughput * world.background(&ray) * weight;
+                break;
+            }
+        };
+
+        let hit = world.hit_record(id, &ray, &isect);
+        let bsdf = world.bsdf(id);
+        let wo = -ray.dir;
+
+        // Emitters were already sampled directly from the previous bounce
+        let emitted = bsdf.emitted(&hit);
+        if !emitted.is_black() {
+            let weight = match (prev, world.is_emitter(id)) {
+                (Some((origin, bsdf_pdf)), true) => {
+                    let light_pdf = world.geometry[id].pdf_from(&origin, &hit.p, &hit.ng) * world.emitter_pmf(&origin, id);
+                    sampling::power_heuristic(bsdf_pdf, light_pdf)
+                }
+                _ => 1.0
+            };
+
+            color += throughput * emitted * weight;
+        }
+
+        color += throughput * direct_lighting(world, &hit, &wo, bsdf, true, rng);
+
+        // Continue the path in a direction sampled from the bsdf
+        let scatter = match bsdf.sample(&hit, &wo, [rng.gen(), rng.gen(), rng.gen()]) {
+            Some(s) => s,
+            None => break
+        };
+
+        throughput = throughput * scatter.weight;
+        ray = spawn(&hit, scatter.dir, scatter.inside);
+        prev = if scatter.specular { None } else { Some((hit.p, scatter.pdf)) };
+
+        if bounce >= rr_depth {
+            let q = throughput.max_component().min(0.95);
+            if rng.gen::<f32>() >= q {
+                break;
+            }
+
+            throughput = throughput / q;
+        }
+    }
+
+    color
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Geometry;
+    use crate::material::{Material, Texture};
+    use crate::bsdf::{Phong, Emissive};
+    use crate::environment::Environment;
+
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn grey(kd: f32) -> Material {
+        Material::Phong(Phong::new(Texture::Solid(Vec3::new(1.0, 1.0, 1.0)), kd, 0.0, 1.0, 0.0, 0.0, 1.0))
+    }
+
+    fn furnace(kd: f32) -> World {
+        let mut world = World::new();
+        let m = world.add_material(grey(kd));
+        world.add_entity(Geometry::new_sphere(Vec3::new(0.0, 0.0, 0.0), 1.0), m);
+        world.set_environment(Environment::Constant(Vec3::new(1.0, 1.0, 1.0)));
+        world
+    }
+
+    #[test]
+    fn escaping_rays_see_the_background() {
+        let mut world = World::new();
+        world.set_environment(Environment::Constant(Vec3::new(0.25, 0.5, 0.75)));
+
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for integrator in [Integrator::Whitted, Integrator::path()] {
+            let c = integrator.radiance(&world, &ray, &mut rng);
+            assert!((c - Vec3::new(0.25, 0.5, 0.75)).mag() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn diffuse_sphere_in_a_furnace_reflects_its_albedo() {
+        // A convex lambertian surface under uniform light of 1 sends back exactly its albedo
+        let world = furnace(0.5);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.02, 0.05, -1.0));
+        let mut rng = StdRng::seed_from_u64(2);
+
+        let n = 1000;
+        let sum: Vec3<f32> = (0..n).map(|_| Integrator::path().radiance(&world, &ray, &mut rng)).sum();
+        let mean = sum / n as f32;
+
+        assert!((mean - Vec3::new(0.5, 0.5, 0.5)).mag() < 1e-3, "{mean:?}");
+    }
+
+    #[test]
+    fn max_depth_stops_paths() {
+        let mut world = World::new();
+        let m = world.add_material(Material::Emissive(Emissive::new(Vec3::new(2.0, 2.0, 2.0))));
+        world.add_entity(Geometry::new_sphere(Vec3::new(0.0, 0.0, 0.0), 1.0), m);
+        world.set_environment(Environment::Constant(Vec3::new(0.0, 0.0, 0.0)));
+        world.build_light_sampler(Default::default());
+
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut rng = StdRng::seed_from_u64(3);
+
+        let c = Integrator::Path { rr_depth: 0, max_depth: 0 }.radiance(&world, &ray, &mut rng);
+        assert!((c - Vec3::new(2.0, 2.0, 2.0)).mag() < 1e-6);
+    }
+}
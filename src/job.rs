@@ -1,5 +1,6 @@
 use crate::world::World;
 use crate::camera::Camera;
+use crate::integrator::Integrator;
 
 use std::sync::Arc;
 
@@ -10,6 +11,7 @@ pub struct RenderJob {
     pub img_width: usize,
     pub img_height: usize,
     pub num_samples: usize,
+    pub integrator: Integrator,
 
     pub camera: Arc<Camera>,
     pub world: Arc<World>,
@@ -1,4 +1,5 @@
-use crate::geometry::{Geometry, AABB, Axis, Ray, Object};
+use crate::geometry::{Geometry, AABB, Axis, Ray, Intersection};
+use crate::accel::Accelerator;
 use crate::vector::Vec3;
 
 use serde::{Serialize, Deserialize};
@@ -6,6 +7,46 @@ use serde::{Serialize, Deserialize};
 const MAX_DEPTH: usize = 20;
 const NUM_POLYGONS: usize = 1;
 
+/// How the kdtree chooses its split planes
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum SplitMethod {
+    /// Split nodes in half, cycling through the axes
+    Midpoint,
+    /// Choose the cheapest plane on any axis by the surface area heuristic
+    Sah(SahCosts)
+}
+
+impl Default for SplitMethod {
+    fn default() -> Self {
+        SplitMethod::Sah(SahCosts::default())
+    }
+}
+
+/// Relative costs the surface area heuristic weighs splits with
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SahCosts {
+    /// Cost of stepping through a branch node
+    pub traversal: f32,
+    /// Cost of intersecting a single primitive
+    pub intersect: f32,
+    /// Fraction of the cost forgiven when a split cuts off empty space
+    pub empty_bonus: f32,
+    /// Deepest the tree can get, 8 + 1.3 log2(n) when not given
+    pub max_depth: Option<usize>
+}
+
+impl Default for SahCosts {
+    fn default() -> Self {
+        Self {
+            traversal: 1.0,
+            intersect: 80.0,
+            empty_bonus: 0.5,
+            max_depth: None
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum KDNode {
     /// Decision Branch on Axis = f32, id for lef
@@ -22,67 +63,119 @@ impl KDNode {
     }
 }
 
+impl Accelerator for KDNode {
+    fn intersect(&self, r: &Ray, gs: &[Geometry]) -> Option<(usize, Intersection)> {
+        self.traverse(r, gs, f32::INFINITY, false)
+    }
+
+    fn occluded(&self, r: &Ray, gs: &[Geometry], max_dist: f32) -> bool {
+        self.traverse(r, gs, max_dist, true).is_some()
+    }
+}
+
 impl KDNode {
-    pub fn intersect(&self, r: &Ray, gs: &Vec<Geometry>) -> Option<(usize, f32)> {
-        if !self.aabb().intersect(r) {
+    /// Closest hit before max_dist, or with any set the first one found
+    fn traverse(&self, r: &Ray, gs: &[Geometry], max_dist: f32, any: bool) -> Option<(usize, Intersection)> {
+        let (tmin, tmax) = self.aabb().range(r)?;
+        let tmax = tmax.min(max_dist);
+        if tmax < 0.0 {
             return None;
         }
 
-        match self {
-            KDNode::Branch(a, v, _, left, right) => {
-                let (dist, dir) = match a {
-                    Axis::X => (r.origin.x-v, r.dir.x),
-                    Axis::Y => (r.origin.y-v, r.dir.y),
-                    Axis::Z => (r.origin.z-v, r.dir.z)
-                };
-
-                match (dist < 0.0, dir < 0.0) {
-                    // Only need to check left side
-                    (true, true) => left.intersect(r, gs),
-                    // Only need to check right side
-                    (false, false) => right.intersect(r, gs),
-                    // Check left and then right
-                    (true, false) => {
-                        let res = left.intersect(r, gs);
-                        if res.is_none() {
-                            right.intersect(r, gs)
+        let mut closest: Option<(usize, Intersection)> = None;
+
+        // Cells still to visit along with the part of the ray inside them
+        let mut stack: Vec<(&KDNode, f32, f32)> = Vec::new();
+        let mut current = Some((self, tmin.max(0.0), tmax));
+
+        while let Some((mut node, tmin, mut tmax)) = current {
+            // Everything left is further away than the closest hit
+            if closest.is_some_and(|(_, h)| h.dist < tmin) {
+                break;
+            }
+
+            loop {
+                match node {
+                    KDNode::Branch(a, v, _, left, right) => {
+                        let (o, d) = (a.of(&r.origin), a.of(&r.dir));
+
+                        // The child holding the origin is the one the ray passes through first
+                        let (near, far) = if o < *v || (o == *v && d <= 0.0) {
+                            (left, right)
                         } else {
-                            res
+                            (right, left)
+                        };
+
+                        if d == 0.0 {
+                            node = near;
+                            continue;
                         }
-                    }
-                    // Check right and then left
-                    (false, true) => {
-                        let res = right.intersect(r, gs);
-                        if res.is_none() {
-                            left.intersect(r, gs)
+
+                        let t = (v - o) / d;
+                        if t > tmax || t <= 0.0 {
+                            node = near;
+                        } else if t < tmin {
+                            node = far;
                         } else {
-                            res
+                            stack.push((far, t, tmax));
+                            node = near;
+                            tmax = t;
+                        }
+                    }
+                    KDNode::Leaf(_, objs) => {
+                        for &i in objs {
+                            if let Some(h) = gs[i].hit(r) {
+                                if h.dist < max_dist && closest.is_none_or(|(_, c)| h.dist < c.dist) {
+                                    if any {
+                                        return Some((i, h));
+                                    }
+                                    closest = Some((i, h));
+                                }
+                            }
                         }
+                        break;
                     }
                 }
             }
-            KDNode::Leaf(_, objs) => {
-                objs.iter()
-                    .filter_map(|&i| gs[i].intersect(r).map(|d| (i, d)))
-                    .min_by(|a, b| {
-                        a.1.partial_cmp(&b.1).unwrap()
-                    })
+
+            // A hit inside this cell can't be beaten by any cell behind it
+            if closest.is_some_and(|(_, h)| h.dist <= tmax) {
+                break;
             }
+
+            current = stack.pop();
         }
+
+        closest
     }
 }
 
-pub fn build_kdtree(g: &Vec<Geometry>) -> KDNode {
+pub fn build_kdtree(g: &[Geometry], method: SplitMethod) -> KDNode {
+    // Unbounded geometry is tested separately by the world
     let aabb = g.iter()
+        .filter(|g| g.is_bounded())
         .fold(
             AABB { min: Vec3::new(0.0, 0.0, 0.0), max: Vec3::new(0.0, 0.0, 0.0) },
             |a, b| a.union(b.fit())
         );
 
-    build_kdtree_h(g.iter().enumerate().collect(), aabb, Axis::X, 0)
+    match method {
+        SplitMethod::Midpoint => build_kdtree_h(g.iter().enumerate().filter(|(_, g)| g.is_bounded()).collect(), aabb, Axis::X, 0),
+        SplitMethod::Sah(costs) => {
+            let bounds: Vec<AABB> = g.iter().map(Geometry::fit).collect();
+            let max_depth = costs.max_depth
+                .unwrap_or_else(|| (8.0 + 1.3 * (g.len().max(1) as f32).log2()) as usize);
+
+            let prims: Vec<usize> = (0..g.len()).filter(|&i| g[i].is_bounded()).collect();
+            let events = sorted_events(&prims, &bounds, &aabb);
+            let mut side = vec![(false, false); g.len()];
+
+            build_sah(prims, events, &bounds, aabb, &costs, max_depth, &mut side)
+        }
+    }
 }
 
-fn build_kdtree_h<'a>(g: Vec<(usize, &'a Geometry)>, aabb: AABB, axis: Axis, depth: usize) -> KDNode {
+fn build_kdtree_h(g: Vec<(usize, &Geometry)>, aabb: AABB, axis: Axis, depth: usize) -> KDNode {
     // If we have reached our max depth return a leaf node containing the rest of the geometry
     if depth >= MAX_DEPTH {
         return KDNode::Leaf(aabb, g.iter().map(|a| a.0).collect());
@@ -96,8 +189,8 @@ fn build_kdtree_h<'a>(g: Vec<(usize, &'a Geometry)>, aabb: AABB, axis: Axis, dep
     // Now just subdivide by the axis and recur
     let (l, r, d) = aabb.split(axis);
 
-    let left: Vec<_> = g.iter().filter(|(_, g)| g.left_of(axis, d)).map(|a| *a).collect();
-    let right: Vec<_> = g.iter().filter(|(_, g)| g.right_of(axis, d)).map(|a| *a).collect();
+    let left: Vec<_> = g.iter().filter(|(_, g)| g.left_of(axis, d)).copied().collect();
+    let right: Vec<_> = g.iter().filter(|(_, g)| g.right_of(axis, d)).copied().collect();
 
     // If right and left have the same number as the parent just return a leaf node, don't recur
     if left.len() == right.len() && left.len() == g.len() {
@@ -118,3 +211,319 @@ fn build_kdtree_h<'a>(g: Vec<(usize, &'a Geometry)>, aabb: AABB, axis: Axis, dep
         Box::new(build_kdtree_h(right, r, new_axis, depth+1))
     )
 }
+
+#[derive(Debug, Copy, Clone)]
+enum Event {
+    Start,
+    End,
+    /// The primitive lies flat in the plane
+    Planar
+}
+
+struct Split {
+    axis: Axis,
+    pos: f32,
+    cost: f32
+}
+
+/// Which sides of a split a primitive's clipped bounds fall on, flat ones go left
+fn sides(b: &AABB, axis: Axis, pos: f32) -> (bool, bool) {
+    let (min, max) = (axis.of(&b.min), axis.of(&b.max));
+    (min < pos || (min == pos && max == pos), max > pos)
+}
+
+/// Bound events of a node's primitives along each axis, sorted by position
+type Events = [Vec<(f32, Event, usize)>; 3];
+
+/// Events of the primitives' bounds clipped to aabb
+fn sorted_events(prims: &[usize], bounds: &[AABB], aabb: &AABB) -> Events {
+    let mut events: Events = Default::default();
+
+    for &i in prims {
+        let b = bounds[i].clip(aabb);
+
+        for (axis, e) in Axis::ALL.into_iter().zip(&mut events) {
+            let (min, max) = (axis.of(&b.min), axis.of(&b.max));
+
+            if min == max {
+                e.push((min, Event::Planar, i));
+            } else {
+                e.push((min, Event::Start, i));
+                e.push((max, Event::End, i));
+            }
+        }
+    }
+
+    for e in &mut events {
+        e.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+    }
+    events
+}
+
+/// Merge two lists of events that are each sorted
+fn merge(a: Vec<(f32, Event, usize)>, b: Vec<(f32, Event, usize)>) -> Vec<(f32, Event, usize)> {
+    let mut out = Vec::with_capacity(a.len() + b.len());
+    let mut a = a.into_iter().peekable();
+    let mut b = b.into_iter().peekable();
+
+    while let (Some(x), Some(y)) = (a.peek(), b.peek()) {
+        out.push(if y.0.total_cmp(&x.0).is_lt() { b.next() } else { a.next() }.unwrap());
+    }
+    out.extend(a);
+    out.extend(b);
+    out
+}
+
+/// Sweep the sorted bound events along every axis for the cheapest split
+fn find_split(events: &Events, n: usize, aabb: &AABB, costs: &SahCosts) -> Option<Split> {
+    let area = aabb.surface_area();
+    if area <= 0.0 {
+        return None;
+    }
+
+    let mut best: Option<Split> = None;
+
+    for (axis, events) in Axis::ALL.into_iter().zip(events) {
+        let (lo, hi) = (axis.of(&aabb.min), axis.of(&aabb.max));
+        if hi <= lo {
+            continue;
+        }
+
+        // Counts of primitives on each side of the plane at the current event
+        let mut left = 0;
+        let mut right = n;
+
+        let mut i = 0;
+        while i < events.len() {
+            let pos = events[i].0;
+            let (mut starts, mut ends, mut planar) = (0, 0, 0);
+
+            while i < events.len() && events[i].0 == pos {
+                match events[i].1 {
+                    Event::Start => starts += 1,
+                    Event::End => ends += 1,
+                    Event::Planar => planar += 1
+                }
+                i += 1;
+            }
+
+            right -= ends + planar;
+
+            if pos > lo && pos < hi {
+                let (l, r) = aabb.split_at(axis, pos);
+                let (nl, nr) = (left + planar, right);
+
+                let bonus = if nl == 0 || nr == 0 { costs.empty_bonus } else { 0.0 };
+                let cost = costs.traversal + costs.intersect * (1.0 - bonus)
+                    * (l.surface_area() * nl as f32 + r.surface_area() * nr as f32) / area;
+
+                if best.as_ref().is_none_or(|b| cost < b.cost) {
+                    best = Some(Split { axis, pos, cost });
+                }
+            }
+
+            left += starts + planar;
+        }
+    }
+
+    best
+}
+
+/// Build the tree from events sorted once at the root. Each node hands its
+/// children the events of primitives wholly on their side still in order, so
+/// only the primitives cut by the plane are sorted again and the whole build
+/// is O(n log n). side is scratch space holding each primitive's sides.
+fn build_sah(
+    prims: Vec<usize>,
+    events: Events,
+    bounds: &[AABB],
+    aabb: AABB,
+    costs: &SahCosts,
+    depth: usize,
+    side: &mut [(bool, bool)]
+) -> KDNode {
+    if depth == 0 || prims.is_empty() {
+        return KDNode::Leaf(aabb, prims);
+    }
+
+    // Only split when it is cheaper than intersecting everything here
+    let leaf_cost = costs.intersect * prims.len() as f32;
+    let split = match find_split(&events, prims.len(), &aabb, costs) {
+        Some(s) if s.cost < leaf_cost => s,
+        _ => return KDNode::Leaf(aabb, prims)
+    };
+
+    let mut left = Vec::new();
+    let mut right = Vec::new();
+    let mut both = Vec::new();
+    for &i in &prims {
+        side[i] = sides(&bounds[i].clip(&aabb), split.axis, split.pos);
+        let (l, r) = side[i];
+        if l {
+            left.push(i);
+        }
+        if r {
+            right.push(i);
+        }
+        if l && r {
+            both.push(i);
+        }
+    }
+
+    let (l, r) = aabb.split_at(split.axis, split.pos);
+
+    // Primitives on one side keep their clipped bounds, those cut by the plane
+    // get new events for the part of them in each child
+    let mut left_events = sorted_events(&both, bounds, &l);
+    let mut right_events = sorted_events(&both, bounds, &r);
+    for ((e, le), re) in events.into_iter().zip(&mut left_events).zip(&mut right_events) {
+        let (mut only_left, mut only_right) = (Vec::new(), Vec::new());
+        for ev in e {
+            match side[ev.2] {
+                (true, false) => only_left.push(ev),
+                (false, true) => only_right.push(ev),
+                _ => {}
+            }
+        }
+
+        *le = merge(only_left, std::mem::take(le));
+        *re = merge(only_right, std::mem::take(re));
+    }
+
+    KDNode::Branch(
+        split.axis,
+        split.pos,
+        aabb,
+        Box::new(build_sah(left, left_events, bounds, l, costs, depth-1, side)),
+        Box::new(build_sah(right, right_events, bounds, r, costs, depth-1, side))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accel::{random_scene, linear_hit};
+
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn methods() -> [SplitMethod; 2] {
+        [SplitMethod::Midpoint, SplitMethod::default()]
+    }
+
+    fn leaves<'a>(node: &'a KDNode, out: &mut Vec<(&'a AABB, &'a [usize])>) {
+        match node {
+            KDNode::Branch(_, _, _, l, r) => {
+                leaves(l, out);
+                leaves(r, out);
+            }
+            KDNode::Leaf(aabb, prims) => out.push((aabb, prims))
+        }
+    }
+
+    #[test]
+    fn sah_leaves_hold_every_bounded_primitive() {
+        let mut gs = random_scene(200, 1.0, 1);
+        gs.push(Geometry::Plane(crate::shapes::Plane::new(Vec3::new(0.0, -20.0, 0.0), Vec3::new(0.0, 1.0, 0.0))));
+        let plane = gs.len() - 1;
+
+        let tree = build_kdtree(&gs, SplitMethod::default());
+        let mut found = Vec::new();
+        leaves(&tree, &mut found);
+
+        for (i, g) in gs.iter().enumerate().take(plane) {
+            let fit = g.fit();
+            let overlaps = |b: &AABB| Axis::ALL.iter().all(|&a| a.of(&fit.min) <= a.of(&b.max) && a.of(&fit.max) >= a.of(&b.min));
+
+            // Every leaf the primitive's box reaches has to list it
+            assert!(found.iter().any(|(_, p)| p.contains(&i)));
+            for (b, p) in &found {
+                if p.contains(&i) {
+                    assert!(overlaps(b));
+                }
+            }
+        }
+        assert!(found.iter().all(|(_, p)| !p.contains(&plane)));
+    }
+
+    #[test]
+    fn sah_splits_between_clusters() {
+        let cluster = |x: f32| (0..8).map(move |i| Geometry::new_sphere(Vec3::new(x, i as f32, 0.0), 0.4));
+        let gs: Vec<Geometry> = cluster(-50.0).chain(cluster(50.0)).collect();
+
+        match build_kdtree(&gs, SplitMethod::default()) {
+            KDNode::Branch(Axis::X, pos, ..) => assert!((-49.61..=49.61).contains(&pos), "{pos}"),
+            n => panic!("expected a split on x, got {n:?}")
+        }
+    }
+
+    #[test]
+    fn sah_stops_when_splitting_costs_more() {
+        let one = vec![Geometry::new_sphere(Vec3::new(0.0, 0.0, 0.0), 1.0)];
+        assert!(matches!(build_kdtree(&one, SplitMethod::default()), KDNode::Leaf(_, p) if p == [0]));
+
+        // Make every primitive cheap to test next to stepping through a node
+        let costs = SahCosts { traversal: 10000.0, ..SahCosts::default() };
+        assert!(matches!(build_kdtree(&random_scene(50, 1.0, 2), SplitMethod::Sah(costs)), KDNode::Leaf(_, p) if p.len() == 50));
+
+        let costs = SahCosts { max_depth: Some(0), ..SahCosts::default() };
+        assert!(matches!(build_kdtree(&random_scene(50, 1.0, 2), SplitMethod::Sah(costs)), KDNode::Leaf(_, p) if p.len() == 50));
+    }
+
+    #[test]
+    fn flat_primitives_go_left_of_their_plane() {
+        let flat = AABB { min: Vec3::new(0.0, 1.0, 0.0), max: Vec3::new(1.0, 1.0, 1.0) };
+        assert_eq!(sides(&flat, Axis::Y, 1.0), (true, false));
+        assert_eq!(sides(&flat, Axis::X, 0.5), (true, true));
+        assert_eq!(sides(&flat, Axis::X, 0.0), (false, true));
+    }
+
+    #[test]
+    fn finds_the_same_hits_as_a_linear_scan() {
+        let mut rng = StdRng::seed_from_u64(6);
+
+        // Large primitives straddle many cells, their hits may lie in a later one
+        for (size, seed) in [(1.0, 7), (6.0, 8)] {
+            let gs = random_scene(200, size, seed);
+
+            for method in methods() {
+                let tree = build_kdtree(&gs, method);
+
+                // Start both outside and inside the tree's box
+                for _ in 0..2000 {
+                    let origin = Vec3::new(rng.gen_range(-15.0..15.0), rng.gen_range(-15.0..15.0), rng.gen_range(-15.0..15.0));
+                    let dir = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+                    let ray = Ray::new(origin, dir);
+
+                    assert_eq!(tree.intersect(&ray, &gs).map(|(i, h)| (i, h.dist)), linear_hit(&ray, &gs));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn follows_axis_parallel_rays_along_split_planes() {
+        // A lattice of spheres puts the midpoint splits on whole and half coordinates
+        let gs: Vec<Geometry> = (0..64)
+            .map(|i| Geometry::new_sphere(Vec3::new((i % 4) as f32, (i / 4 % 4) as f32, (i / 16) as f32) * 2.0 - 3.0, 0.3))
+            .collect();
+
+        for method in methods() {
+            let tree = build_kdtree(&gs, method);
+
+            for i in 0..13 * 13 {
+                let (a, b) = ((i % 13) as f32 * 0.5 - 3.0, (i / 13) as f32 * 0.5 - 3.0);
+
+                for (origin, dir) in [
+                    (Vec3::new(-10.0, a, b), Vec3::new(1.0, 0.0, 0.0)),
+                    (Vec3::new(a, 10.0, b), Vec3::new(0.0, -1.0, 0.0)),
+                    (Vec3::new(a, b, 0.0), Vec3::new(0.0, 0.0, 1.0)),
+                    (Vec3::new(a, b, 0.0), Vec3::new(0.0, 0.0, -1.0))
+                ] {
+                    let ray = Ray::new(origin, dir);
+                    assert_eq!(tree.intersect(&ray, &gs).map(|(i, h)| (i, h.dist)), linear_hit(&ray, &gs));
+                }
+            }
+        }
+    }
+}
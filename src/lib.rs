@@ -1,9 +1,25 @@
 pub mod vector;
 pub mod geometry;
+pub mod shapes;
 pub mod material;
+pub mod light;
+pub mod light_sampler;
 pub mod world;
 pub mod camera;
 pub mod kdtree;
+pub mod bvh;
+pub mod accel;
+pub mod transform;
 pub mod ply;
+pub mod obj;
 pub mod job;
 pub mod tone_map;
+pub mod image;
+pub mod scene;
+pub mod sampling;
+pub mod noise;
+pub mod environment;
+pub mod sky;
+pub mod ies;
+pub mod integrator;
+pub mod bsdf;
@@ -0,0 +1,401 @@
+use crate::vector::Vec3;
+use crate::geometry::{Sphere, SurfaceSample, HitRecord, AABB};
+use crate::world::World;
+use crate::ies::IesProfile;
+use crate::sampling;
+
+use serde::{Serialize, Deserialize};
+
+use std::f32::consts::PI;
+use std::path::PathBuf;
+
+/// Light arriving at a point from a sampled position on a light
+#[derive(Debug, Copy, Clone)]
+pub struct LightSample {
+    pub dir: Vec3<f32>,
+    pub dist: f32,
+    pub radiance: Vec3<f32>,
+    pub pdf: f32,
+    /// Set for lights that bsdf sampled rays can also hit
+    pub hittable: bool
+}
+
+fn down() -> Vec3<f32> {
+    Vec3::new(0.0, -1.0, 0.0)
+}
+
+/// Direction from pos to p, its distance, and the inverse square falloff over it
+fn towards(pos: &Vec3<f32>, p: &Vec3<f32>) -> Option<(Vec3<f32>, f32, f32)> {
+    let s = *pos - *p;
+    let dist2 = s.dot(&s);
+    if dist2 <= 0.0 {
+        return None;
+    }
+
+    let dist = dist2.sqrt();
+    Some((s / dist, dist, 1.0 / dist2))
+}
+
+/// Hermite blend from 0 at a to 1 at b
+fn smoothstep(a: f32, b: f32, x: f32) -> f32 {
+    if b <= a {
+        return if x >= b { 1.0 } else { 0.0 };
+    }
+
+    let t = ((x - a) / (b - a)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Light {
+    /// Light from a single point, color is the intensity so the light falls
+    /// off with the square of the distance
+    Point { pos: Vec3<f32>, color: Vec3<f32> },
+    /// Parallel light travelling along dir from infinitely far away, color is
+    /// the irradiance on a surface facing it
+    Directional { dir: Vec3<f32>, color: Vec3<f32> },
+    /// Point light shining along dir, fully bright within inner degrees of it
+    /// and fading out to nothing at outer degrees
+    Spot {
+        pos: Vec3<f32>,
+        dir: Vec3<f32>,
+        color: Vec3<f32>,
+        #[serde(default)]
+        inner: f32,
+        outer: f32
+    },
+    /// Point light shaped by a measured IES profile, color is the intensity in
+    /// its brightest direction and axis is the profile's straight down
+    Ies {
+        pos: Vec3<f32>,
+        color: Vec3<f32>,
+        #[serde(default = "down")]
+        axis: Vec3<f32>,
+        /// Profile file, scenes look it up relative to the scene file
+        path: PathBuf,
+        /// Filled in when the scene is loaded
+        #[serde(default)]
+        profile: IesProfile
+    },
+    /// Sphere emitting color in every direction
+    Sphere { center: Vec3<f32>, radius: f32, color: Vec3<f32> },
+    /// Parallelogram spanned by u and v emitting color towards u x v
+    Quad { corner: Vec3<f32>, u: Vec3<f32>, v: Vec3<f32>, color: Vec3<f32> },
+    /// Geometry in the world with an emissive material
+    Emitter(usize),
+    /// The world's environment, for environments that light the scene
+    Environment,
+}
+
+impl Light {
+    pub fn point(pos: Vec3<f32>, color: Vec3<f32>) -> Self {
+        Self::Point { pos, color }
+    }
+
+    pub fn directional(dir: Vec3<f32>, color: Vec3<f32>) -> Self {
+        Self::Directional { dir, color }
+    }
+
+    /// A spot light with cone angles in degrees from its axis
+    pub fn spot(pos: Vec3<f32>, dir: Vec3<f32>, color: Vec3<f32>, inner: f32, outer: f32) -> Self {
+        Self::Spot { pos, dir, color, inner, outer }
+    }
+
+    /// Rough luminance of the total power given off, for picking between
+    /// lights. Lights at infinity count what falls on the world's bounds.
+    pub fn power(&self, world: &World) -> f32 {
+        match self {
+            Light::Point { color, .. } => 4.0 * PI * color.luminance(),
+            Light::Spot { color, inner, outer, .. } => {
+                // Solid angle of the cone, halfway through the fade
+                let cos = (inner.to_radians().cos() + outer.to_radians().cos()) / 2.0;
+                2.0 * PI * (1.0 - cos) * color.luminance()
+            }
+            Light::Ies { color, profile, .. } => 4.0 * PI * profile.average() * color.luminance(),
+            Light::Sphere { radius, color, .. } => PI * 4.0 * PI * radius * radius * color.luminance(),
+            Light::Quad { u, v, color, .. } => PI * u.cross(v).mag() * color.luminance(),
+            Light::Emitter(id) => {
+                let g = &world.geometry[*id];
+                let AABB { min, max } = g.fit();
+                let hit = HitRecord::new((min + max) * 0.5, Vec3::new(0.0, 1.0, 0.0), 0.0);
+
+                PI * g.area() * world.bsdf(*id).emitted(&hit).luminance()
+            }
+            Light::Directional { color, .. } => {
+                let r = world.radius();
+                PI * r * r * color.luminance()
+            }
+            Light::Environment => world.environment.power(world.radius())
+        }
+    }
+
+    /// Box around everywhere the light is given off from, None for lights
+    /// at infinity and emitters without bounds
+    pub fn bounds(&self, world: &World) -> Option<AABB> {
+        match self {
+            Light::Point { pos, .. } | Light::Spot { pos, .. } | Light::Ies { pos, .. } => {
+                Some(AABB { min: *pos, max: *pos })
+            }
+            Light::Sphere { center, radius, .. } => {
+                let r = Vec3::new(*radius, *radius, *radius);
+                Some(AABB { min: *center - r, max: *center + r })
+            }
+            Light::Quad { corner, u, v, .. } => {
+                let corners = [*corner + *u, *corner + *v, *corner + *u + *v];
+                Some(corners.iter().fold(AABB { min: *corner, max: *corner }, |a, c| a.union(AABB { min: *c, max: *c })))
+            }
+            Light::Emitter(id) if world.geometry[*id].is_bounded() => Some(world.geometry[*id].fit()),
+            _ => None
+        }
+    }
+
+    /// Sample light arriving at p given two uniform random numbers
+    pub fn sample(&self, world: &World, p: &Vec3<f32>, u: f32, v: f32) -> Option<LightSample> {
+        match self {
+            Light::Point { pos, color } => {
+                let (dir, dist, falloff) = towards(pos, p)?;
+
+                Some(LightSample {
+                    dir,
+                    dist,
+                    radiance: *color * falloff,
+                    pdf: 1.0,
+                    hittable: false
+                })
+            }
+            Light::Directional { dir, color } => {
+                Some(LightSample {
+                    dir: -dir.normalized(),
+                    dist: f32::INFINITY,
+                    radiance: *color,
+                    pdf: 1.0,
+                    hittable: false
+                })
+            }
+            Light::Spot { pos, dir: axis, color, inner, outer } => {
+                let (dir, dist, falloff) = towards(pos, p)?;
+
+                let cos = -dir.dot(&axis.normalized());
+                let cone = smoothstep(outer.to_radians().cos(), inner.to_radians().cos(), cos);
+                if cone <= 0.0 {
+                    return None;
+                }
+
+                Some(LightSample {
+                    dir,
+                    dist,
+                    radiance: *color * (cone * falloff),
+                    pdf: 1.0,
+                    hittable: false
+                })
+            }
+            Light::Ies { pos, color, axis, profile, .. } => {
+                let (dir, dist, falloff) = towards(pos, p)?;
+
+                // Angles of the direction leaving the light in the profile's frame
+                let axis = axis.normalized();
+                let (t, b) = sampling::basis(&axis);
+                let out = -dir;
+                let theta = out.dot(&axis).clamp(-1.0, 1.0).acos().to_degrees();
+                let phi = out.dot(&b).atan2(out.dot(&t)).to_degrees();
+
+                let intensity = profile.intensity(theta, phi);
+                if intensity <= 0.0 {
+                    return None;
+                }
+
+                Some(LightSample {
+                    dir,
+                    dist,
+                    radiance: *color * (intensity * falloff),
+                    pdf: 1.0,
+                    hittable: false
+                })
+            }
+            Light::Sphere { center, radius, color } => {
+                let s = Sphere::new(*center, *radius).sample_from(p, u, v)?;
+
+                Some(LightSample {
+                    dir: s.dir,
+                    dist: s.dist,
+                    radiance: *color,
+                    pdf: s.pdf,
+                    hittable: false
+                })
+            }
+            Light::Quad { corner, u: qu, v: qv, color } => {
+                let n = qu.cross(qv);
+                let area = n.mag();
+                let n = n / area;
+
+                let point = *corner + *qu * u + *qv * v;
+                let d = point - *p;
+                let dist = d.mag();
+                let dir = d / dist;
+
+                // Only the front of the quad emits
+                let cos = -n.dot(&dir);
+                if cos <= 0.0 {
+                    return None;
+                }
+
+                Some(LightSample {
+                    dir,
+                    dist,
+                    radiance: *color,
+                    pdf: dist * dist / (area * cos),
+                    hittable: false
+                })
+            }
+            Light::Emitter(id) => {
+                let SurfaceSample { dir, dist, normal, pdf } = world.geometry[*id].sample_from(p, u, v)?;
+
+                let n = if normal.dot(&dir) > 0.0 { -normal } else { normal };
+                let hit = HitRecord::new(*p + dir * dist, n, dist);
+
+                Some(LightSample {
+                    dir,
+                    dist,
+                    radiance: world.bsdf(*id).emitted(&hit),
+                    pdf,
+                    hittable: true
+                })
+            }
+            Light::Environment => {
+                let (dir, radiance, pdf) = world.environment.sample(u, v)?;
+
+                Some(LightSample {
+                    dir,
+                    dist: f32::INFINITY,
+                    radiance,
+                    pdf,
+                    hittable: true
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Geometry;
+    use crate::material::Material;
+    use crate::bsdf::Emissive;
+
+    /// Irradiance at p on a surface facing +y, estimated from samples of the light on a grid
+    fn irradiance(light: &Light, world: &World, p: &Vec3<f32>) -> f32 {
+        let n = 128;
+        let up = Vec3::new(0.0, 1.0, 0.0);
+
+        (0..n * n)
+            .filter_map(|i| {
+                let (u, v) = (((i % n) as f32 + 0.5) / n as f32, ((i / n) as f32 + 0.5) / n as f32);
+                light.sample(world, p, u, v)
+            })
+            .map(|s| s.radiance.x * up.dot(&s.dir).max(0.0) / s.pdf)
+            .sum::<f32>() / (n * n) as f32
+    }
+
+    #[test]
+    fn sphere_lights_give_analytic_irradiance() {
+        // A sphere of radiance L straight above at distance d gives E = pi L r^2 / d^2
+        let (r, d) = (0.5, 3.0);
+        let expected = PI * 2.0 * r * r / (d * d);
+
+        let world = World::new();
+        let light = Light::Sphere { center: Vec3::new(0.0, d, 0.0), radius: r, color: Vec3::new(2.0, 2.0, 2.0) };
+        let e = irradiance(&light, &world, &Vec3::new(0.0, 0.0, 0.0));
+
+        assert!((e - expected).abs() < 0.01 * expected, "{e} {expected}");
+    }
+
+    #[test]
+    fn emissive_geometry_matches_the_sphere_light() {
+        let (r, d) = (0.5, 3.0);
+        let expected = PI * 2.0 * r * r / (d * d);
+
+        let mut world = World::new();
+        let m = world.add_material(Material::Emissive(Emissive::new(Vec3::new(2.0, 2.0, 2.0))));
+        world.add_entity(Geometry::new_sphere(Vec3::new(0.0, d, 0.0), r), m);
+
+        assert!(matches!(world.lights[..], [Light::Emitter(0)]));
+        let e = irradiance(&world.lights[0], &world, &Vec3::new(0.0, 0.0, 0.0));
+
+        assert!((e - expected).abs() < 0.01 * expected, "{e} {expected}");
+    }
+
+    #[test]
+    fn quad_lights_only_shine_from_the_front() {
+        let world = World::new();
+        let light = Light::Quad {
+            corner: Vec3::new(-0.05, 10.0, -0.05),
+            u: Vec3::new(0.1, 0.0, 0.0),
+            v: Vec3::new(0.0, 0.0, 0.1),
+            color: Vec3::new(1.0, 1.0, 1.0)
+        };
+
+        // u x v points down, so a small quad far above gives about L A / d^2
+        let e = irradiance(&light, &world, &Vec3::new(0.0, 0.0, 0.0));
+        assert!((e - 0.01 / 100.0).abs() < 1e-6, "{e}");
+
+        assert!(light.sample(&world, &Vec3::new(0.0, 20.0, 0.0), 0.5, 0.5).is_none());
+    }
+
+    #[test]
+    fn spot_lights_fade_between_their_cones() {
+        let world = World::new();
+        let spot = Light::spot(Vec3::new(0.0, 2.0, 0.0), Vec3::new(0.0, -1.0, 0.0), Vec3::new(8.0, 8.0, 8.0), 20.0, 40.0);
+
+        // Straight below at full strength with the inverse square falloff
+        let s = spot.sample(&world, &Vec3::new(0.0, 0.0, 0.0), 0.5, 0.5).unwrap();
+        assert!((s.dir - Vec3::new(0.0, 1.0, 0.0)).mag() < 1e-6);
+        assert!((s.dist - 2.0).abs() < 1e-6 && (s.radiance.x - 2.0).abs() < 1e-6);
+
+        // Halfway through the fade in cosine the smoothstep is at a half
+        let cos = (20.0f32.to_radians().cos() + 40.0f32.to_radians().cos()) / 2.0;
+        let p = Vec3::new(2.0 * (1.0 - cos * cos).sqrt() / cos, 0.0, 0.0);
+        let s = spot.sample(&world, &p, 0.5, 0.5).unwrap();
+        assert!((s.radiance.x * s.dist * s.dist - 4.0).abs() < 1e-3, "{:?}", s.radiance);
+
+        assert!(spot.sample(&world, &Vec3::new(5.0, 0.0, 0.0), 0.5, 0.5).is_none());
+        assert!(spot.sample(&world, &Vec3::new(0.0, 4.0, 0.0), 0.5, 0.5).is_none());
+    }
+
+    #[test]
+    fn directional_lights_arrive_from_infinitely_far() {
+        let world = World::new();
+        let light = Light::directional(Vec3::new(0.0, -2.0, 0.0), Vec3::new(3.0, 3.0, 3.0));
+
+        for p in [Vec3::new(0.0, 0.0, 0.0), Vec3::new(100.0, -50.0, 7.0)] {
+            let s = light.sample(&world, &p, 0.5, 0.5).unwrap();
+
+            assert!((s.dir - Vec3::new(0.0, 1.0, 0.0)).mag() < 1e-6);
+            assert!(s.dist.is_infinite() && !s.hittable);
+            assert_eq!(s.radiance.x, 3.0);
+        }
+    }
+
+    #[test]
+    fn ies_lights_follow_their_profile() {
+        let profile = crate::ies::parse_ies("TILT=NONE\n1 1000 1 3 1 1 2 0 0 0\n1 1 100\n0 45 90\n0\n50 25 0\n").unwrap();
+        let light = Light::Ies {
+            pos: Vec3::new(0.0, 1.0, 0.0),
+            color: Vec3::new(4.0, 4.0, 4.0),
+            axis: down(),
+            path: PathBuf::from("round.ies"),
+            profile
+        };
+        let world = World::new();
+
+        let below = light.sample(&world, &Vec3::new(0.0, 0.0, 0.0), 0.5, 0.5).unwrap();
+        assert!((below.radiance.x - 4.0).abs() < 1e-5);
+
+        // 45 degrees off the axis at a distance of root 2
+        let off = light.sample(&world, &Vec3::new(1.0, 0.0, 0.0), 0.5, 0.5).unwrap();
+        assert!((off.radiance.x - 4.0 * 0.5 / 2.0).abs() < 1e-4);
+
+        assert!(light.sample(&world, &Vec3::new(0.0, 2.0, 0.0), 0.5, 0.5).is_none());
+        assert!((light.power(&world) - 4.0 * PI * 0.5 * Vec3::new(4.0, 4.0, 4.0).luminance()).abs() < 1e-3);
+    }
+}
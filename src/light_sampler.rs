@@ -0,0 +1,414 @@
+use crate::geometry::{AABB, Axis};
+use crate::light::Light;
+use crate::sampling::Distribution1D;
+use crate::vector::Vec3;
+use crate::world::World;
+
+use serde::{Serialize, Deserialize};
+
+use rand::Rng;
+
+use std::collections::HashMap;
+
+/// How lights are picked for direct lighting at each hit
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+pub enum LightSampling {
+    /// Sample every light at every hit
+    #[default]
+    All,
+    /// Sample one light, picked in proportion to its power
+    Power,
+    /// Sample one light, picked by walking a tree that favours bright lights
+    /// close to the hit
+    Tree
+}
+
+/// A node in the flattened tree, the first child of a branch directly follows it
+#[derive(Debug, Serialize, Deserialize)]
+struct LightNode {
+    aabb: AABB,
+    power: f32,
+    /// Index of the second child for branches, of the light for leaves
+    offset: usize,
+    leaf: bool
+}
+
+impl LightNode {
+    /// How much light the node's lights might send to p, too close to tell
+    /// them apart counts as being the size of the node away
+    fn importance(&self, p: &Vec3<f32>) -> f32 {
+        let center = (self.aabb.min + self.aabb.max) * 0.5;
+        let d = center - *p;
+        let extent = self.aabb.max - self.aabb.min;
+
+        self.power / d.dot(&d).max(extent.dot(&extent) / 4.0).max(f32::MIN_POSITIVE)
+    }
+}
+
+/// Bounding hierarchy over the lights that have a position
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LightTree {
+    nodes: Vec<LightNode>,
+    /// Parent of every node, the root is its own
+    parents: Vec<usize>,
+    /// Leaf node of each light in the tree by light index
+    leaves: HashMap<usize, usize>
+}
+
+impl LightTree {
+    /// Build over (light index, bounds, power) entries, there must be at least one
+    fn build(lights: &mut [(usize, AABB, f32)]) -> Self {
+        let mut tree = LightTree {
+            nodes: Vec::with_capacity(2 * lights.len()),
+            parents: Vec::with_capacity(2 * lights.len()),
+            leaves: HashMap::new()
+        };
+
+        tree.build_h(lights, 0);
+        tree
+    }
+
+    /// Build the node covering lights, returning its index
+    fn build_h(&mut self, lights: &mut [(usize, AABB, f32)], parent: usize) -> usize {
+        let index = self.nodes.len();
+        let aabb = lights.iter().skip(1).fold(lights[0].1, |a, l| a.union(l.1));
+        let power = lights.iter().map(|l| l.2).sum();
+
+        self.nodes.push(LightNode { aabb, power, offset: lights[0].0, leaf: lights.len() == 1 });
+        self.parents.push(parent);
+
+        if lights.len() == 1 {
+            self.leaves.insert(lights[0].0, index);
+            return index;
+        }
+
+        // Split at the median along the axis the light centers spread the most
+        let center = |l: &(usize, AABB, f32)| (l.1.min + l.1.max) * 0.5;
+        let cbounds = lights.iter().fold(
+            AABB { min: center(&lights[0]), max: center(&lights[0]) },
+            |a, l| a.union(AABB { min: center(l), max: center(l) })
+        );
+        let extent = cbounds.max - cbounds.min;
+        let axis = Axis::ALL.into_iter()
+            .max_by(|a, b| a.of(&extent).total_cmp(&b.of(&extent)))
+            .unwrap();
+
+        let mid = lights.len() / 2;
+        lights.select_nth_unstable_by(mid, |a, b| axis.of(&center(a)).total_cmp(&axis.of(&center(b))));
+        let (left, right) = lights.split_at_mut(mid);
+
+        self.build_h(left, index);
+        let second = self.build_h(right, index);
+        self.nodes[index].offset = second;
+
+        index
+    }
+
+    /// Probability of stepping from a branch into its child
+    fn child_pmf(&self, branch: usize, child: usize, p: &Vec3<f32>) -> f32 {
+        let left = self.nodes[branch + 1].importance(p);
+        let right = self.nodes[self.nodes[branch].offset].importance(p);
+        let total = left + right;
+
+        if total <= 0.0 || !total.is_finite() {
+            0.5
+        } else if child == branch + 1 {
+            left / total
+        } else {
+            right / total
+        }
+    }
+
+    /// Walk down from the root choosing children by importance, returning the light and its probability
+    fn sample<R: Rng + ?Sized>(&self, p: &Vec3<f32>, rng: &mut R) -> (usize, f32) {
+        let mut node = 0;
+        let mut pmf = 1.0;
+
+        while !self.nodes[node].leaf {
+            let left = self.child_pmf(node, node + 1, p);
+
+            if rng.gen::<f32>() < left {
+                node += 1;
+                pmf *= left;
+            } else {
+                node = self.nodes[node].offset;
+                pmf *= 1.0 - left;
+            }
+        }
+
+        (self.nodes[node].offset, pmf)
+    }
+
+    /// Probability that sample picks the light, zero for lights not in the tree
+    fn pmf(&self, p: &Vec3<f32>, light: usize) -> f32 {
+        let mut node = match self.leaves.get(&light) {
+            Some(&n) => n,
+            None => return 0.0
+        };
+
+        let mut pmf = 1.0;
+        while node != 0 {
+            let parent = self.parents[node];
+            pmf *= self.child_pmf(parent, node, p);
+            node = parent;
+        }
+
+        pmf
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+enum Strategy {
+    #[default]
+    All,
+    Power(Distribution1D),
+    Tree {
+        tree: Option<LightTree>,
+        /// Lights without a position, picked by power
+        infinite: Vec<usize>,
+        /// Where each of the infinite lights is in infinite, by light index
+        slots: HashMap<usize, usize>,
+        distribution: Distribution1D,
+        /// Chance of picking from the tree rather than the infinite lights
+        tree_pmf: f32
+    }
+}
+
+/// Picks which of the world's lights to sample at a point
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LightSampler {
+    strategy: Strategy,
+    /// Light index of every emissive geometry id
+    emitters: HashMap<usize, usize>,
+    environment: Option<usize>
+}
+
+impl LightSampler {
+    pub fn build(world: &World, method: LightSampling) -> Self {
+        let lights = &world.lights;
+        if lights.is_empty() {
+            return Self::default();
+        }
+
+        let power: Vec<f32> = lights.iter().map(|l| l.power(world).max(0.0)).collect();
+
+        let strategy = match method {
+            LightSampling::All => Strategy::All,
+            LightSampling::Power => Strategy::Power(Distribution1D::new(power)),
+            LightSampling::Tree => {
+                let mut bounded = Vec::new();
+                let mut infinite = Vec::new();
+
+                for (i, l) in lights.iter().enumerate() {
+                    match l.bounds(world) {
+                        Some(b) => bounded.push((i, b, power[i])),
+                        None => infinite.push(i)
+                    }
+                }
+
+                // Split between the two groups by power, or by count when nothing gives any off
+                let tree_power: f32 = bounded.iter().map(|l| l.2).sum();
+                let infinite_power: f32 = infinite.iter().map(|&i| power[i]).sum();
+                let tree_pmf = if tree_power + infinite_power > 0.0 {
+                    tree_power / (tree_power + infinite_power)
+                } else {
+                    bounded.len() as f32 / lights.len() as f32
+                };
+
+                Strategy::Tree {
+                    tree: (!bounded.is_empty()).then(|| LightTree::build(&mut bounded)),
+                    distribution: Distribution1D::new(infinite.iter().map(|&i| power[i]).collect()),
+                    slots: infinite.iter().enumerate().map(|(slot, &i)| (i, slot)).collect(),
+                    infinite,
+                    tree_pmf
+                }
+            }
+        };
+
+        let emitters = lights.iter()
+            .enumerate()
+            .filter_map(|(i, l)| match l {
+                Light::Emitter(id) => Some((*id, i)),
+                _ => None
+            })
+            .collect();
+
+        Self {
+            strategy,
+            emitters,
+            environment: lights.iter().position(|l| matches!(l, Light::Environment))
+        }
+    }
+
+    /// Pick a light to sample from p, returning its index and the
+    /// probability it was picked with. None means every light is sampled.
+    pub fn sample<R: Rng + ?Sized>(&self, p: &Vec3<f32>, rng: &mut R) -> Option<(usize, f32)> {
+        match &self.strategy {
+            Strategy::All => None,
+            Strategy::Power(d) => {
+                let (_, _, i) = d.sample(rng.gen());
+                Some((i, d.pmf(i)))
+            }
+            Strategy::Tree { tree, infinite, distribution, tree_pmf, .. } => {
+                match tree {
+                    Some(t) if rng.gen::<f32>() < *tree_pmf => {
+                        let (i, pmf) = t.sample(p, rng);
+                        Some((i, pmf * tree_pmf))
+                    }
+                    _ => {
+                        let (_, _, i) = distribution.sample(rng.gen());
+                        Some((infinite[i], distribution.pmf(i) * (1.0 - tree_pmf)))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Probability that sample picks the light from p
+    pub fn pmf(&self, p: &Vec3<f32>, light: usize) -> f32 {
+        match &self.strategy {
+            Strategy::All => 1.0,
+            Strategy::Power(d) => d.pmf(light),
+            Strategy::Tree { tree, slots, distribution, tree_pmf, .. } => {
+                match slots.get(&light) {
+                    Some(&slot) => distribution.pmf(slot) * (1.0 - tree_pmf),
+                    None => tree.as_ref().map_or(0.0, |t| t.pmf(p, light) * tree_pmf)
+                }
+            }
+        }
+    }
+
+    /// Probability that sample picks the light made by emissive geometry id
+    pub fn emitter_pmf(&self, p: &Vec3<f32>, id: usize) -> f32 {
+        match (&self.strategy, self.emitters.get(&id)) {
+            (Strategy::All, _) => 1.0,
+            (_, Some(&light)) => self.pmf(p, light),
+            (_, None) => 0.0
+        }
+    }
+
+    /// Probability that sample picks the environment
+    pub fn environment_pmf(&self, p: &Vec3<f32>) -> f32 {
+        match (&self.strategy, self.environment) {
+            (Strategy::All, _) => 1.0,
+            (_, Some(light)) => self.pmf(p, light),
+            (_, None) => 0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::Environment;
+    use crate::geometry::Geometry;
+    use crate::material::Material;
+    use crate::bsdf::Emissive;
+    use crate::sky::Sky;
+
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    /// Point lights in a row of growing power, an emissive sphere over the ground, a sun and a sky
+    fn world() -> World {
+        let mut w = World::new();
+
+        for i in 0..12 {
+            let power = (i + 1) as f32;
+            w.add_light(Light::point(Vec3::new(i as f32 * 3.0, 2.0, 0.0), Vec3::new(power, power, power)));
+        }
+        w.add_light(Light::directional(Vec3::new(0.0, -1.0, 0.2), Vec3::new(0.5, 0.5, 0.5)));
+
+        let ground = w.add_material(Material::Normal);
+        let glow = w.add_material(Material::Emissive(Emissive::new(Vec3::new(4.0, 4.0, 4.0))));
+        w.add_entity(Geometry::new_sphere(Vec3::new(10.0, -100.0, 0.0), 99.0), ground);
+        w.add_entity(Geometry::new_sphere(Vec3::new(-5.0, 1.0, 3.0), 0.5), glow);
+
+        w.set_environment(Environment::Sky(Sky::new(Vec3::new(1.0, 1.0, 0.0), 3.0)));
+        w
+    }
+
+    fn points() -> [Vec3<f32>; 3] {
+        [Vec3::new(0.0, 0.0, 0.0), Vec3::new(30.0, 1.0, -2.0), Vec3::new(-5.0, 1.0, 2.0)]
+    }
+
+    #[test]
+    fn picking_every_light_has_no_probability() {
+        let w = world();
+        let sampler = LightSampler::build(&w, LightSampling::All);
+
+        assert!(sampler.sample(&Vec3::new(0.0, 0.0, 0.0), &mut StdRng::seed_from_u64(0)).is_none());
+        assert_eq!(sampler.emitter_pmf(&Vec3::new(0.0, 0.0, 0.0), 0), 1.0);
+        assert_eq!(sampler.environment_pmf(&Vec3::new(0.0, 0.0, 0.0)), 1.0);
+    }
+
+    #[test]
+    fn pmfs_sum_to_one_and_match_the_samples() {
+        let w = world();
+
+        for method in [LightSampling::Power, LightSampling::Tree] {
+            let sampler = LightSampler::build(&w, method);
+            let mut rng = StdRng::seed_from_u64(11);
+
+            for p in points() {
+                let total: f32 = (0..w.lights.len()).map(|i| sampler.pmf(&p, i)).sum();
+                assert!((total - 1.0).abs() < 1e-4, "{method:?} {total}");
+
+                let n = 20000;
+                let mut counts = vec![0; w.lights.len()];
+                for _ in 0..n {
+                    let (i, pmf) = sampler.sample(&p, &mut rng).unwrap();
+                    assert!((pmf - sampler.pmf(&p, i)).abs() < 1e-5 * pmf.max(1.0));
+                    counts[i] += 1;
+                }
+
+                for (i, &c) in counts.iter().enumerate() {
+                    let expected = sampler.pmf(&p, i);
+                    assert!((c as f32 / n as f32 - expected).abs() < 0.01, "{method:?} light {i}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn power_sampling_follows_power() {
+        let w = world();
+        let sampler = LightSampler::build(&w, LightSampling::Power);
+        let p = Vec3::new(0.0, 0.0, 0.0);
+
+        // The point lights are 1 through 12 times as bright
+        assert!((sampler.pmf(&p, 5) / sampler.pmf(&p, 0) - 6.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn tree_sampling_favours_close_lights() {
+        let w = world();
+        let sampler = LightSampler::build(&w, LightSampling::Tree);
+
+        // Next to the dimmest point light it beats the brightest one far down the row
+        let p = Vec3::new(0.0, 1.5, 0.0);
+        assert!(sampler.pmf(&p, 0) > sampler.pmf(&p, 11));
+
+        let p = Vec3::new(33.0, 1.5, 0.0);
+        assert!(sampler.pmf(&p, 0) < sampler.pmf(&p, 11));
+    }
+
+    #[test]
+    fn emitters_and_the_environment_are_found_by_id() {
+        let w = world();
+        let p = Vec3::new(1.0, 0.0, 0.0);
+
+        for method in [LightSampling::Power, LightSampling::Tree] {
+            let sampler = LightSampler::build(&w, method);
+
+            assert!(matches!(w.lights[13], Light::Emitter(1)));
+            assert_eq!(sampler.emitter_pmf(&p, 1), sampler.pmf(&p, 13));
+            assert!(sampler.emitter_pmf(&p, 1) > 0.0);
+            assert_eq!(sampler.emitter_pmf(&p, 0), 0.0);
+
+            assert!(matches!(w.lights[14], Light::Environment));
+            assert_eq!(sampler.environment_pmf(&p), sampler.pmf(&p, 14));
+            assert!(sampler.environment_pmf(&p) > 0.0);
+        }
+    }
+}
@@ -1,117 +1,371 @@
-use gi_tracer::world::World;
 use gi_tracer::vector::Vec3;
-use gi_tracer::geometry::Geometry;
-use gi_tracer::camera::Camera;
-use gi_tracer::material::{Material, Color, Light, Texture};
-use gi_tracer::kdtree::build_kdtree;
+use gi_tracer::material::Color;
+use gi_tracer::scene::{load_scene, RenderSettings};
+use gi_tracer::integrator::Integrator;
 use gi_tracer::tone_map::{tone_map, Algorithm};
+use gi_tracer::image::{self, Format};
+use gi_tracer::accel::AccelMethod;
+use gi_tracer::kdtree::SplitMethod;
+use gi_tracer::light_sampler::LightSampling;
+
+use clap::{Parser, ValueEnum};
+use clap::builder::RangedU64ValueParser;
 
 use rayon::prelude::*;
 
-use rand::thread_rng;
-use rand::Rng;
+use rand::{thread_rng, Rng, SeedableRng};
+use rand::rngs::StdRng;
 
 use indicatif::ProgressBar;
 
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum FormatArg {
+    /// binary P6 ppm
+    Ppm,
+    /// 8 bit png
+    Png,
+    /// 16 bit png
+    Png16,
+    /// radiance rgbe of the linear image, not tone mapped
+    Hdr,
+    /// portable float map of the linear image, not tone mapped
+    Pfm,
+    /// openexr of the linear image, not tone mapped
+    Exr
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum IntegratorArg {
+    Whitted,
+    Path
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum AccelArg {
+    /// test every primitive
+    Linear,
+    /// kdtree with surface area heuristic splits
+    Kdtree,
+    /// kdtree split at the middle of each node
+    KdtreeMidpoint,
+    /// bounding volume hierarchy
+    Bvh
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum LightSamplingArg {
+    /// sample every light at every hit
+    All,
+    /// sample one light picked by power
+    Power,
+    /// sample one light picked from a tree by power and distance
+    Tree
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum ToneMapArg {
+    Ward,
+    Reinhard,
+    Alm
+}
+
+/// Render a scene file, options given here override the scene's render settings
+#[derive(Parser)]
+struct Args {
+    /// Scene file to render
+    #[arg(default_value = "scenes/spheres.toml")]
+    scene: PathBuf,
+
+    /// Where to write the image, stdout if not given
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Image format to write, guessed from the output extension if not given
+    #[arg(short, long, value_enum)]
+    format: Option<FormatArg>,
+
+    /// Image width in pixels
+    #[arg(long, value_parser = at_least_one())]
+    width: Option<usize>,
+
+    /// Image height in pixels
+    #[arg(long, value_parser = at_least_one())]
+    height: Option<usize>,
+
+    /// Samples per pixel
+    #[arg(short, long, value_parser = at_least_one())]
+    samples: Option<usize>,
+
+    /// Light transport algorithm
+    #[arg(short, long, value_enum)]
+    integrator: Option<IntegratorArg>,
+
+    /// Safety limit on path length for the path tracer, 64 if not given
+    #[arg(long)]
+    max_depth: Option<usize>,
+
+    /// Bounces before russian roulette starts for the path tracer, 3 if not given
+    #[arg(long)]
+    rr_depth: Option<usize>,
+
+    /// Tone mapping operator
+    #[arg(short, long, value_enum)]
+    tone_map: Option<ToneMapArg>,
 
-const WIDTH: usize = 800;
-const HEIGHT: usize = 800;
-const SAMPLES: usize = 100;
-
-fn output_ppm(img: &Vec<Vec3<f32>>, w: usize, h: usize) {
-    // Header
-    println!("P3");
-    println!("{w} {h}");
-    println!("255");
-
-    // Now print data
-    for row in 0..h {
-        for col in 0..w {
-            let color = img[row*w+col] * 255.0;
-            println!("{} {} {}", color.x as u8, color.y as u8, color.z as u8);
+    /// Bias for the adaptive logarithmic mapping, 0.85 if not given
+    #[arg(long)]
+    bias: Option<f32>,
+
+    /// Acceleration structure to trace rays with
+    #[arg(short, long, value_enum)]
+    accel: Option<AccelArg>,
+
+    /// How lights are picked for direct lighting
+    #[arg(long, value_enum)]
+    light_sampling: Option<LightSamplingArg>,
+
+    /// Number of render threads, all cores if not given
+    #[arg(short = 'j', long)]
+    threads: Option<usize>,
+
+    /// Seed for the random numbers, renders with the same seed are identical
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+/// Parser for counts that can't be zero
+fn at_least_one() -> RangedU64ValueParser<usize> {
+    RangedU64ValueParser::new().range(1..)
+}
+
+/// Apply the options that change render settings over the scene's own
+fn override_settings(args: &Args, settings: &mut RenderSettings) -> Result<(), &'static str> {
+    settings.samples = args.samples.unwrap_or(settings.samples);
+
+    if let Some(i) = args.integrator {
+        settings.integrator = match i {
+            IntegratorArg::Whitted => Integrator::Whitted,
+            IntegratorArg::Path => Integrator::path()
+        };
+    }
+
+    // Path length options tune whichever path tracer ends up being used, from here or the scene
+    if args.max_depth.is_some() || args.rr_depth.is_some() {
+        match &mut settings.integrator {
+            Integrator::Path { rr_depth, max_depth } => {
+                *rr_depth = args.rr_depth.unwrap_or(*rr_depth);
+                *max_depth = args.max_depth.unwrap_or(*max_depth);
+            }
+            Integrator::Whitted => return Err("--max-depth and --rr-depth only apply to the path integrator")
+        }
+    }
+
+    if let Some(t) = args.tone_map {
+        settings.tone_map = match t {
+            ToneMapArg::Ward => Algorithm::Ward,
+            ToneMapArg::Reinhard => Algorithm::Reinhard,
+            ToneMapArg::Alm => Algorithm::ALM(0.85)
+        };
+    }
+
+    if let Some(b) = args.bias {
+        match &mut settings.tone_map {
+            Algorithm::ALM(bias) => *bias = b,
+            _ => return Err("--bias only applies to the alm tone map")
         }
     }
+
+    if let Some(a) = args.accel {
+        settings.accel = match a {
+            AccelArg::Linear => AccelMethod::Linear,
+            AccelArg::Kdtree => AccelMethod::KdTree(SplitMethod::default()),
+            AccelArg::KdtreeMidpoint => AccelMethod::KdTree(SplitMethod::Midpoint),
+            AccelArg::Bvh => AccelMethod::Bvh
+        };
+    }
+
+    if let Some(l) = args.light_sampling {
+        settings.light_sampling = match l {
+            LightSamplingArg::All => LightSampling::All,
+            LightSamplingArg::Power => LightSampling::Power,
+            LightSamplingArg::Tree => LightSampling::Tree
+        };
+    }
+
+    Ok(())
+}
+
+/// Format to write, from --format or else the output's extension
+fn output_format(args: &Args) -> Format {
+    match args.format {
+        Some(FormatArg::Ppm) => Format::Ppm,
+        Some(FormatArg::Png) => Format::Png8,
+        Some(FormatArg::Png16) => Format::Png16,
+        Some(FormatArg::Hdr) => Format::Hdr,
+        Some(FormatArg::Pfm) => Format::Pfm,
+        Some(FormatArg::Exr) => Format::Exr,
+        None => args.output.as_ref()
+            .and_then(Format::from_path)
+            .unwrap_or(Format::Ppm)
+    }
 }
 
 fn main() {
-    let cam = Camera::new(
-        Vec3::new(0.0, 0.0, 0.0),
-        Vec3::new(0.0, 0.0, 1.0),
-        Vec3::new(0.0, 1.0, 0.0),
-        45.0,
-        WIDTH as f32 / HEIGHT as f32,
-        0.5
-    );
+    let args = Args::parse();
 
-    let mut world = World::new();
+    let mut scene = match load_scene(&args.scene) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
 
-    let mat1 = world.add_material(
-        Material::Phong(
-            Texture::Checker(
-                Color::RGB(255, 0, 0),
-                Color::RGB(0, 0, 255),
-            )
-            , 0.8, 0.9, 0.0, 0.0, 0.0, 0.0)
+    // Command line options take precedence over the scene file
+    scene.resize(
+        args.width.unwrap_or(scene.settings.width),
+        args.height.unwrap_or(scene.settings.height)
     );
 
-    let mat2 = world.add_material(Material::Phong(Texture::Solid(Color::RGB(22, 22, 22)), 0.2, 0.7, 12.0, 0.0, 0.9, 0.95));
-    let mat3 = world.add_material(Material::Phong(Texture::Solid(Color::RGB(22, 22, 22)), 0.2, 0.7, 3.0, 0.90, 0.0, 0.0));
-
-    world.add_floor(
-        Vec3::new(-5.0, -1.8, -1.0),
-        7.75,
-        56.0,
-        mat1
-    );
+    if let Err(e) = override_settings(&args, &mut scene.settings) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
 
-    world.add_entity(
-        Geometry::new_sphere(Vec3::new(0.0, 0.0, 10.0), 1.0),
-        mat2
-    );
+    if args.accel.is_some() {
+        let start = Instant::now();
+        scene.world.build_accel(scene.settings.accel);
+        eprintln!("built {:?} in {} seconds", scene.settings.accel, start.elapsed().as_secs_f32());
+    }
 
-    world.add_entity(
-        Geometry::new_sphere(
-            Vec3::new(-1.25, -0.6, 11.5),
-            0.8
-        ),
-        mat3
-    );
+    if args.light_sampling.is_some() {
+        scene.world.build_light_sampler(scene.settings.light_sampling);
+    }
 
-    world.lights.push(Light {
-        pos: Vec3::new(1.0, 8.0, 1.0),
-        color: Vec3::new(0.5, 0.5, 0.5)
-    });
+    if let Some(n) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(n)
+            .build_global()
+            .unwrap();
+    }
 
-    world.kdtree = Some(build_kdtree(&world.geometry));
+    let (world, cam, settings) = (&scene.world, &scene.camera, &scene.settings);
+    let (width, height, samples) = (settings.width, settings.height, settings.samples);
+    let seed = args.seed.unwrap_or_else(|| thread_rng().gen());
 
-    let x_jitter = 1.0 / WIDTH as f32 / 2.0;
-    let y_jitter = 1.0 / WIDTH as f32 / 2.0;
+    let x_jitter = 1.0 / width as f32 / 2.0;
+    let y_jitter = 1.0 / height as f32 / 2.0;
 
-    let bar = Arc::new(Mutex::new(ProgressBar::new((WIDTH*HEIGHT) as u64)));
+    let bar = Arc::new(Mutex::new(ProgressBar::new((width*height) as u64)));
 
-    let mut img = vec![Color::RGB(0,0,0); WIDTH*HEIGHT];
+    let mut img = vec![Color::RGB(0,0,0); width*height];
     img.par_iter_mut()
         .enumerate()
         .for_each(|(i, p)| {
-            let y = i / WIDTH;
-            let x = i % WIDTH;
+            let y = i / width;
+            let x = i % width;
 
-            *p = (0..SAMPLES).map(|_| {
-                let cx = x as f32 / WIDTH as f32 + thread_rng().gen_range(-x_jitter..x_jitter);
-                let cy = 1.0 - y as f32 / HEIGHT as f32 + thread_rng().gen_range(-y_jitter..y_jitter);
+            // Every pixel gets its own generator so the image doesn't depend on scheduling
+            let mut rng = StdRng::seed_from_u64(seed ^ i as u64);
 
-                let ray = cam.get_ray(cx, cy);
-                world.fire(&ray, 0)
-            }).sum::<Vec3<f32>>() / SAMPLES as f32;
+            *p = (0..samples).map(|_| {
+                let cx = x as f32 / width as f32 + rng.gen_range(-x_jitter..x_jitter);
+                let cy = 1.0 - y as f32 / height as f32 + rng.gen_range(-y_jitter..y_jitter);
+
+                let ray = cam.get_ray(cx, cy, rng.gen(), rng.gen());
+                settings.integrator.radiance(world, &ray, &mut rng)
+            }).sum::<Vec3<f32>>() / samples as f32;
 
             if i%10 == 0 {
                 bar.lock().unwrap().inc(10);
             }
         });
 
-    //tone_map(&mut img, Algorithm::ALM(0.85));
-    tone_map(&mut img, Algorithm::Ward);
-    output_ppm(&img, WIDTH, HEIGHT);
+    let format = output_format(&args);
+
+    // High dynamic range formats keep the linear radiance
+    if !format.is_hdr() {
+        tone_map(&mut img, settings.tone_map);
+    }
+
+    let res = match &args.output {
+        Some(path) => image::save(path, &img, width, height, format),
+        None => image::write(&mut std::io::stdout().lock(), &img, width, height, format)
+    };
+
+    if let Err(e) = res {
+        eprintln!("failed to write image: {e}");
+        std::process::exit(1);
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::CommandFactory;
+
+    fn args(argv: &[&str]) -> Args {
+        Args::try_parse_from(std::iter::once("gi_tracer").chain(argv.iter().copied())).unwrap()
+    }
+
+    fn path_settings() -> RenderSettings {
+        RenderSettings { integrator: Integrator::path(), ..Default::default() }
+    }
+
+    #[test]
+    fn options_are_consistent() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn options_override_the_scene() {
+        let mut settings = RenderSettings::default();
+        override_settings(&args(&["-s", "7", "-i", "path", "-t", "alm", "--bias", "0.5", "-a", "bvh"]), &mut settings).unwrap();
+
+        assert_eq!(settings.samples, 7);
+        assert!(matches!(settings.integrator, Integrator::Path { rr_depth: 3, max_depth: 64 }));
+        assert!(matches!(settings.tone_map, Algorithm::ALM(b) if b == 0.5));
+        assert!(matches!(settings.accel, AccelMethod::Bvh));
+    }
+
+    #[test]
+    fn depths_tune_the_scenes_path_tracer() {
+        let mut settings = path_settings();
+        override_settings(&args(&["--max-depth", "5"]), &mut settings).unwrap();
+        assert!(matches!(settings.integrator, Integrator::Path { rr_depth: 3, max_depth: 5 }));
+
+        let mut settings = RenderSettings::default();
+        assert!(override_settings(&args(&["--rr-depth", "1"]), &mut settings).is_err());
+    }
+
+    #[test]
+    fn bias_needs_the_alm_tone_map() {
+        let mut settings = RenderSettings { tone_map: Algorithm::ALM(0.85), ..Default::default() };
+        override_settings(&args(&["--bias", "0.7"]), &mut settings).unwrap();
+        assert!(matches!(settings.tone_map, Algorithm::ALM(b) if b == 0.7));
+
+        let mut settings = RenderSettings::default();
+        assert!(override_settings(&args(&["--bias", "0.7"]), &mut settings).is_err());
+    }
+
+    #[test]
+    fn image_sizes_and_samples_cant_be_zero() {
+        for option in ["--width", "--height", "--samples"] {
+            assert!(Args::try_parse_from(["gi_tracer", option, "0"]).is_err());
+            assert!(Args::try_parse_from(["gi_tracer", option, "1"]).is_ok());
+        }
+    }
+
+    #[test]
+    fn format_follows_the_output_extension() {
+        assert!(matches!(output_format(&args(&["-o", "out.png"])), Format::Png8));
+        assert!(matches!(output_format(&args(&["-o", "out.exr"])), Format::Exr));
+        assert!(matches!(output_format(&args(&["-o", "out.png", "-f", "pfm"])), Format::Pfm));
+        assert!(matches!(output_format(&args(&[])), Format::Ppm));
+    }
+}
@@ -1,12 +1,14 @@
 use crate::vector::Vec3;
-use crate::geometry::Ray;
-use crate::world::World;
+use crate::geometry::HitRecord;
+use crate::image::Image;
+use crate::noise::Noise;
+use crate::bsdf::{Bsdf, Normal, Uv, Distance, Phong, CookTorrance, Emissive};
 
 use serde::{Serialize, Deserialize};
 
-const KA: f32 = 0.9;
-
-const MAX_RECUR: usize = 7;
+use std::f32::consts::PI;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Color;
@@ -21,221 +23,364 @@ impl Color {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Light {
-    pub pos: Vec3<f32>,
-    pub color: Vec3<f32>
+/// How texture coordinates outside of [0, 1] are brought back onto the image
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+pub enum Wrap {
+    #[default]
+    Repeat,
+    /// Repeat with every other copy flipped, so the edges always line up
+    Mirror,
+    /// Stretch the edge pixels outwards
+    Clamp
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+impl Wrap {
+    /// Bring pixel index i back into an image n pixels wide
+    fn apply(&self, i: i64, n: usize) -> usize {
+        let n = n as i64;
+
+        let i = match self {
+            Wrap::Repeat => i.rem_euclid(n),
+            Wrap::Mirror => {
+                let m = i.rem_euclid(2 * n);
+                if m < n { m } else { 2 * n - 1 - m }
+            }
+            Wrap::Clamp => i.clamp(0, n - 1)
+        };
+
+        i as usize
+    }
+}
+
+/// How an image is read between pixel centers
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+pub enum Filter {
+    /// The closest pixel
+    Nearest,
+    /// Blend of the four closest pixels
+    #[default]
+    Bilinear
+}
+
+fn default_scale() -> (f32, f32) {
+    (1.0, 1.0)
+}
+
+/// An image wrapped over the surface by its uv coordinates, v = 0 is the bottom row
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImageTexture {
+    /// Image file, scenes look it up relative to the scene file
+    pub path: PathBuf,
+    #[serde(default)]
+    pub wrap: Wrap,
+    #[serde(default)]
+    pub filter: Filter,
+    /// Number of times the image repeats across the uv square in u and v
+    #[serde(default = "default_scale")]
+    pub scale: (f32, f32),
+    /// Linear pixels of the image, filled in when the scene is loaded
+    #[serde(default)]
+    pub image: Arc<Image>
+}
+
+impl ImageTexture {
+    pub fn new(path: PathBuf, image: Arc<Image>) -> Self {
+        Self { path, wrap: Wrap::default(), filter: Filter::default(), scale: default_scale(), image }
+    }
+
+    fn pixel(&self, x: i64, y: i64) -> Vec3<f32> {
+        let img = &self.image;
+        img.pixels[self.wrap.apply(y, img.height) * img.width + self.wrap.apply(x, img.width)]
+    }
+
+    pub fn sample(&self, (u, v): (f32, f32)) -> Vec3<f32> {
+        let img = &self.image;
+        if img.width == 0 || img.height == 0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+
+        // Continuous pixel coordinates, rows run top to bottom
+        let x = u * self.scale.0 * img.width as f32;
+        let y = (1.0 - v * self.scale.1) * img.height as f32;
+
+        match self.filter {
+            Filter::Nearest => self.pixel(x.floor() as i64, y.floor() as i64),
+            Filter::Bilinear => {
+                // Pixel centers sit at half integers
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (tx, ty) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as i64, y0 as i64);
+
+                let top = self.pixel(x0, y0) * (1.0 - tx) + self.pixel(x0 + 1, y0) * tx;
+                let bottom = self.pixel(x0, y0 + 1) * (1.0 - tx) + self.pixel(x0 + 1, y0 + 1) * tx;
+
+                top * (1.0 - ty) + bottom * ty
+            }
+        }
+    }
+}
+
+/// Coordinates a pattern is laid out in
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+pub enum TextureSpace {
+    /// World positions, so patterns run continuously across objects
+    #[default]
+    World,
+    /// Positions on the geometry before an instance's transform, so patterns move with it
+    Object,
+    /// The surface's uv coordinates as x and y
+    Uv
+}
+
+impl TextureSpace {
+    fn point(&self, hit: &HitRecord) -> Vec3<f32> {
+        match self {
+            TextureSpace::World => hit.p,
+            TextureSpace::Object => hit.local,
+            TextureSpace::Uv => Vec3::new(hit.uv.0, hit.uv.1, 0.0)
+        }
+    }
+}
+
+fn default_check_size() -> f32 {
+    0.5
+}
+
+/// Colors at increasing positions in [0, 1], blended linearly in between
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColorRamp(pub Vec<(f32, Vec3<f32>)>);
+
+impl ColorRamp {
+    pub fn color(&self, t: f32) -> Vec3<f32> {
+        let stops = &self.0;
+
+        let i = stops.partition_point(|&(pos, _)| pos <= t);
+        if i == 0 {
+            return stops.first().map(|&(_, c)| c).unwrap_or(Vec3::new(0.0, 0.0, 0.0));
+        }
+        if i == stops.len() {
+            return stops[i-1].1;
+        }
+
+        let (p0, c0) = stops[i-1];
+        let (p1, c1) = stops[i];
+        let f = if p1 > p0 { (t - p0) / (p1 - p0) } else { 0.0 };
+
+        c0 * (1.0 - f) + c1 * f
+    }
+}
+
+impl Default for ColorRamp {
+    fn default() -> Self {
+        Self(vec![(0.0, Vec3::new(0.0, 0.0, 0.0)), (1.0, Vec3::new(1.0, 1.0, 1.0))])
+    }
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+fn default_octaves() -> u32 {
+    6
+}
+
+/// Settings shared by the noise based textures
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Procedural {
+    /// Colors the pattern's value is looked up in
+    #[serde(default)]
+    pub ramp: ColorRamp,
+    /// Seed of the noise
+    #[serde(default)]
+    pub noise: Noise,
+    /// Frequency of the pattern, features are about 1 / scale apart
+    #[serde(default = "one")]
+    pub scale: f32,
+    #[serde(default = "default_octaves")]
+    pub octaves: u32,
+    /// How far noise pushes marble veins and wood rings out of line
+    #[serde(default = "one")]
+    pub distortion: f32,
+    #[serde(default)]
+    pub space: TextureSpace
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Texture {
     Solid(Vec3<f32>),
-    Checker(Vec3<f32>, Vec3<f32>)
+    /// Alternating checks of a and b, scale wide in the units of space.
+    /// World and object checks tile the x/z plane, uv checks the uv square
+    Checker {
+        a: Vec3<f32>,
+        b: Vec3<f32>,
+        #[serde(default = "default_check_size")]
+        scale: f32,
+        #[serde(default)]
+        space: TextureSpace
+    },
+    Image(ImageTexture),
+    /// Fractal noise through the ramp
+    Fbm(Procedural),
+    /// Summed absolute noise through the ramp, billowy with dark creases
+    Turbulence(Procedural),
+    /// Veins running across x, bent by turbulence
+    Marble(Procedural),
+    /// Rings around the y axis, warped by noise
+    Wood(Procedural),
+    /// The vertex colors of a ply mesh, white on surfaces without them
+    VertexColor
 }
 
 impl Texture {
-    fn get_color(&self, p: &Vec3<f32>) -> Vec3<f32> {
+    pub fn get_color(&self, hit: &HitRecord) -> Vec3<f32> {
         match self {
             Self::Solid(c) => *c,
-            Self::Checker(a, b) => {
-                let x = (p.x / 0.5).floor() as i32;
-                let z = (p.z / 0.5).floor() as i32;
-
-                match ((x%2).abs(), (z%2).abs()) {
-                    (0, 0) => *b,
-                    (0, 1) => *a,
-                    (1, 0) => *a,
-                    (1, 1) => *b,
-                    _ => unreachable!()
-                }
+            Self::Checker { a, b, scale, space } => {
+                let p = space.point(hit);
+                let (s, t) = match space {
+                    TextureSpace::Uv => (p.x, p.y),
+                    _ => (p.x, p.z)
+                };
+
+                let x = (s / scale).floor() as i64;
+                let y = (t / scale).floor() as i64;
+
+                if (x + y).rem_euclid(2) == 0 { *b } else { *a }
+            }
+            Self::Image(img) => img.sample(hit.uv),
+            Self::Fbm(t) => {
+                let v = t.noise.fbm(t.space.point(hit) * t.scale, t.octaves);
+                t.ramp.color(0.5 + 0.5 * v)
+            }
+            Self::Turbulence(t) => {
+                let v = t.noise.turbulence(t.space.point(hit) * t.scale, t.octaves);
+                t.ramp.color(v)
+            }
+            Self::Marble(t) => {
+                let p = t.space.point(hit) * t.scale;
+                let phase = p.x + t.distortion * t.noise.turbulence(p, t.octaves);
+                t.ramp.color(0.5 + 0.5 * (PI * phase).sin())
             }
+            Self::Wood(t) => {
+                let p = t.space.point(hit) * t.scale;
+                let r = (p.x * p.x + p.z * p.z).sqrt() + t.distortion * t.noise.fbm(p, t.octaves);
+                t.ramp.color(r.rem_euclid(1.0))
+            }
+            Self::VertexColor => hit.color.unwrap_or(Vec3::new(1.0, 1.0, 1.0))
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Material {
     Normal,
+    Uv,
     Distance,
-    Phong(Texture, f32, f32, f32, f32, f32, f32),    // color, kd, ks, ke, kr, kt, eta
-    CookTorrance(Texture, f32, f32, f32),   // color, f0, roughness, k
+    Phong(Phong),
+    CookTorrance(CookTorrance),
+    Emissive(Emissive),
 }
 
 impl Material {
-    pub fn shade(
-        &self,
-        vin: &Ray,
-        dist: f32,
-        normal: &Vec3<f32>,
-        world: &World,
-        depth: usize
-    ) -> Vec3<f32> {
+    /// The texture coloring the surface, for materials that have one
+    pub fn texture_mut(&mut self) -> Option<&mut Texture> {
         match self {
-            Material::Normal => {
-                *normal
-            }
-            Material::Distance => {
-                Vec3::new(dist*10.0, 0.0, 0.0)
-            }
-            Material::Phong(tex, kd, ks, ke, kr, kt, eta) => {
-                let v = vin.origin + vin.dir*dist;
-
-                let o_color = tex.get_color(&v);
-                // Ambient
-                let mut color = o_color * KA;
-
-
-                for l in world.lights.iter() {
-                    let s = l.pos-v;
-                    let s_norm = s.normalized();
-
-                    // Check if light is visible
-                    let ray = Ray::new(
-                        v,
-                        s
-                    );
-
-                    // If we collide with something first don't add this light
-                    if let Some(p_to_o) = world.intersect(&ray) {
-                        let p_to_l = s.mag();
-                        if p_to_o.1 < p_to_l {
-                            continue;
-                        }
-                    }
-
-                    // Diffuse Light
-                    color += l.color * o_color * s_norm.dot(normal).max(0.0) * *kd;
-
-                    // Specular Light
-                    let r = (v-l.pos).normalized().reflect(normal);
-                    let v = -vin.dir;
-                    let spec_angle = r.dot(&v).max(0.0);
-                    let specular = spec_angle.powf(*ke);
-                    color += l.color * specular * *ks;
-                }
-
-                // If at max depth just return local illumination color
-                if depth == MAX_RECUR {
-                    return color;
-                }
-
-                // Now apply reflection and transmission
-                if *kr > 0.0 {
-                    let r = Ray::new(
-                        v,
-                        vin.dir.reflect(normal)
-                    );
-
-                    color += world.fire(&r, depth+1) * *kr;
-                }
-
-                if *kt > 0.0 {
-                    let (ni, nt) = if vin.inside {
-                        (*eta, 1.0)
-                    } else {
-                        (1.0, *eta)
-                    };
-                    let nit = ni / nt;
-
-                    let n = -*normal;
-
-                    let neg_d_n = -vin.dir.dot(&n);
-                    let determ = 1.0 + nit.powi(2) * (neg_d_n.powi(2) - 1.0);
-
-                    let r = if determ < 0.0 {
-                        Ray::new(
-                            v,
-                            vin.dir.reflect(normal)
-                        )
-                    } else {
-                        let beta = neg_d_n * nit - determ.sqrt();
-
-                        let t = vin.dir * nit + n * beta;
-                        if vin.inside {
-                            Ray::new(
-                                v,
-                                t
-                            )
-                        } else {
-                            let r = Ray::inside(
-                                v,
-                                t
-                            );
-
-                            r
-                        }
-                    };
-
-                    color += world.fire(&r, depth+1) * *kt;
-                }
-
-                color
-            }
-            Material::CookTorrance(tex, f0, roughness, k) => {
-                let v = vin.origin + vin.dir*dist;
-                let o_color = tex.get_color(&v);
+            Material::Phong(p) => Some(&mut p.texture),
+            Material::CookTorrance(c) => Some(&mut c.texture),
+            _ => None
+        }
+    }
+
+    pub fn bsdf(&self) -> &dyn Bsdf {
+        match self {
+            Material::Normal => &Normal,
+            Material::Uv => &Uv,
+            Material::Distance => &Distance,
+            Material::Phong(p) => p,
+            Material::CookTorrance(c) => c,
+            Material::Emissive(e) => e,
+        }
+    }
+}
 
-                // Ambient
-                let mut color = o_color * KA;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    /// A 2 by 2 texture, red and green on the top row, blue and white below
+    fn texture(wrap: Wrap, filter: Filter) -> ImageTexture {
+        let image = Image {
+            width: 2,
+            height: 2,
+            pixels: vec![Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 1.0), Vec3::new(1.0, 1.0, 1.0)]
+        };
 
-                for l in world.lights.iter() {
-                    let s = l.pos-v;
-                    let s_norm = s.normalized();
+        ImageTexture { wrap, filter, ..ImageTexture::new(PathBuf::from("test.png"), Arc::new(image)) }
+    }
 
-                    let n_dot_l = normal.dot(&s_norm).max(0.0);
-                    if n_dot_l <= 0.0 {
-                        continue;
-                    }
+    fn close(a: Vec3<f32>, b: Vec3<f32>) -> bool {
+        (a - b).mag() < 1e-5
+    }
 
-                    // Check if light is visible
-                    let ray = Ray::new(
-                        v,
-                        s
-                    );
+    #[test]
+    fn wrap_modes_fold_indices_back_in() {
+        let fold = |w: Wrap| [-3, -1, 0, 2, 3, 5].map(|i| w.apply(i, 3));
 
-                    if let Some(p_to_o) = world.intersect(&ray) {
-                        let p_to_l = s.mag();
-                        if p_to_o.1 < p_to_l {
-                            continue;
-                        }
-                    }
+        assert_eq!(fold(Wrap::Repeat), [0, 2, 0, 2, 0, 2]);
+        assert_eq!(fold(Wrap::Mirror), [2, 0, 0, 2, 2, 0]);
+        assert_eq!(fold(Wrap::Clamp), [0, 0, 0, 2, 2, 2]);
+    }
 
-                    let v = -vin.dir;
+    #[test]
+    fn nearest_sampling_puts_v_zero_at_the_bottom() {
+        let t = texture(Wrap::Repeat, Filter::Nearest);
 
-                    let h = (s_norm+v).normalized();
-                    let n_dot_h = normal.dot(&h).max(0.0);
-                    let n_dot_v = normal.dot(&v).max(0.0);
-                    let v_dot_h = v.dot(&h).max(0.0);
+        assert!(close(t.sample((0.25, 0.75)), Vec3::new(1.0, 0.0, 0.0)));
+        assert!(close(t.sample((0.75, 0.75)), Vec3::new(0.0, 1.0, 0.0)));
+        assert!(close(t.sample((0.25, 0.25)), Vec3::new(0.0, 0.0, 1.0)));
 
-                    if n_dot_v <= 0.0 {continue;}
+        // One whole repeat over and scaled to repeat twice across the square
+        assert!(close(t.sample((1.25, -0.25)), Vec3::new(1.0, 0.0, 0.0)));
+        let twice = ImageTexture { scale: (2.0, 2.0), ..t };
+        assert!(close(twice.sample((0.625, 0.375)), Vec3::new(1.0, 0.0, 0.0)));
+    }
 
-                    // Fresnel reflectance
-                    let f = (1.0-v_dot_h).powi(5) * (1.0 - f0) + f0;
+    #[test]
+    fn bilinear_sampling_blends_between_pixel_centers() {
+        let t = texture(Wrap::Clamp, Filter::Bilinear);
 
-                    // microfacet distribution by beckman
-                    let m_squared = roughness * roughness;
-                    let r1 = 1.0 / (4.0 * m_squared * n_dot_h.powi(4));
-                    let r2 = (n_dot_h * n_dot_h -1.0) / (m_squared * n_dot_h * n_dot_h);
-                    let d = r1 * r2.exp();
+        assert!(close(t.sample((0.25, 0.75)), Vec3::new(1.0, 0.0, 0.0)));
+        assert!(close(t.sample((0.5, 0.75)), Vec3::new(0.5, 0.5, 0.0)));
+        assert!(close(t.sample((0.5, 0.5)), Vec3::new(0.5, 0.5, 0.5)));
 
-                    // geometric shadowing
-                    let two_n_dot_h = 2.0 * n_dot_h;
-                    let g1 = (two_n_dot_h * n_dot_v) / v_dot_h;
-                    let g2 = (two_n_dot_h * n_dot_l) / v_dot_h;
-                    let g = 1.0f32.min(g1).min(g2);
+        // Past the last pixel center clamping holds the edge while repeating blends in the far side
+        assert!(close(t.sample((0.0, 0.75)), Vec3::new(1.0, 0.0, 0.0)));
+        let repeat = texture(Wrap::Repeat, Filter::Bilinear);
+        assert!(close(repeat.sample((0.0, 0.75)), Vec3::new(0.5, 0.5, 0.0)));
+    }
 
-                    let ks = (f*d*g) / (4.0*n_dot_v*n_dot_l);
+    #[test]
+    fn empty_images_sample_black() {
+        let t = ImageTexture::new(PathBuf::from("missing.png"), Arc::new(Image::default()));
+        assert!(close(t.sample((0.5, 0.5)), Vec3::new(0.0, 0.0, 0.0)));
+    }
 
-                    // Diffuse Lighting
-                    color += o_color * l.color * n_dot_l;
+    #[test]
+    fn color_ramps_blend_between_stops() {
+        let ramp = ColorRamp(vec![
+            (0.2, Vec3::new(1.0, 0.0, 0.0)),
+            (0.6, Vec3::new(0.0, 1.0, 0.0)),
+            (0.6, Vec3::new(0.0, 0.0, 1.0))
+        ]);
 
-                    // Specular Highlight
-                    color += l.color * n_dot_l * (k + ks * (1.0-k));
-                }
+        assert!(close(ramp.color(-1.0), Vec3::new(1.0, 0.0, 0.0)));
+        assert!(close(ramp.color(0.4), Vec3::new(0.5, 0.5, 0.0)));
+        assert!(close(ramp.color(2.0), Vec3::new(0.0, 0.0, 1.0)));
 
-                color
-            }
-        }
+        assert!(close(ColorRamp::default().color(0.25), Vec3::new(0.25, 0.25, 0.25)));
+        assert!(close(ColorRamp(Vec::new()).color(0.5), Vec3::new(0.0, 0.0, 0.0)));
     }
 }
@@ -0,0 +1,169 @@
+use crate::vector::Vec3;
+
+use rand::SeedableRng;
+use rand::seq::SliceRandom;
+use rand::rngs::StdRng;
+
+use serde::{Serialize, Deserialize};
+
+/// Perlin's improved gradient noise, the permutation is shuffled from a seed
+/// so differently seeded textures don't line up. Only the seed is serialized.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "u64", into = "u64")]
+pub struct Noise {
+    seed: u64,
+    perm: Vec<u8>
+}
+
+impl Noise {
+    pub fn new(seed: u64) -> Self {
+        let mut perm: Vec<u8> = (0..=255).collect();
+        perm.shuffle(&mut StdRng::seed_from_u64(seed));
+
+        Self { seed, perm }
+    }
+
+    fn hash(&self, x: i64, y: i64, z: i64) -> u8 {
+        let p = |i: i64| self.perm[(i & 255) as usize] as i64;
+        p(p(p(x) + y) + z) as u8
+    }
+
+    /// Noise at a point, smooth and roughly in [-1, 1] with features about a unit apart
+    pub fn noise(&self, p: Vec3<f32>) -> f32 {
+        let (x0, y0, z0) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (x, y, z) = (p.x - x0, p.y - y0, p.z - z0);
+        let (xi, yi, zi) = (x0 as i64, y0 as i64, z0 as i64);
+
+        // Dot product of the offset with one of 12 edge directions picked by the hash
+        let grad = |dx: i64, dy: i64, dz: i64| {
+            let h = self.hash(xi + dx, yi + dy, zi + dz) & 15;
+            let (x, y, z) = (x - dx as f32, y - dy as f32, z - dz as f32);
+
+            let u = if h < 8 { x } else { y };
+            let v = if h < 4 { y } else if h == 12 || h == 14 { x } else { z };
+
+            (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+        };
+
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+
+        lerp(w,
+            lerp(v,
+                lerp(u, grad(0, 0, 0), grad(1, 0, 0)),
+                lerp(u, grad(0, 1, 0), grad(1, 1, 0))),
+            lerp(v,
+                lerp(u, grad(0, 0, 1), grad(1, 0, 1)),
+                lerp(u, grad(0, 1, 1), grad(1, 1, 1))))
+    }
+
+    /// Fractal brownian motion, octaves of noise each twice the frequency and
+    /// half the amplitude of the last, normalized to roughly [-1, 1]
+    pub fn fbm(&self, p: Vec3<f32>, octaves: u32) -> f32 {
+        self.octaves(p, octaves, |n| n)
+    }
+
+    /// Like fbm but summing the absolute value of each octave, in [0, 1]
+    /// with sharp creases where the noise crosses zero
+    pub fn turbulence(&self, p: Vec3<f32>, octaves: u32) -> f32 {
+        self.octaves(p, octaves, f32::abs)
+    }
+
+    fn octaves<F: Fn(f32) -> f32>(&self, p: Vec3<f32>, octaves: u32, f: F) -> f32 {
+        let mut sum = 0.0;
+        let mut total = 0.0;
+        let mut amplitude = 1.0;
+        let mut p = p;
+
+        for _ in 0..octaves.max(1) {
+            sum += f(self.noise(p)) * amplitude;
+            total += amplitude;
+            amplitude *= 0.5;
+            p = p * 2.0;
+        }
+
+        sum / total
+    }
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl From<u64> for Noise {
+    fn from(seed: u64) -> Self {
+        Self::new(seed)
+    }
+}
+
+impl From<Noise> for u64 {
+    fn from(noise: Noise) -> Self {
+        noise.seed
+    }
+}
+
+/// Smoothstep with zero first and second derivatives at 0 and 1
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Points spread through a few cells, none of them on the lattice
+    fn points() -> impl Iterator<Item = Vec3<f32>> {
+        (0..1000).map(|i| Vec3::new(i as f32 * 0.0137, (i % 37) as f32 * 0.311, (i % 11) as f32 * -0.77) + 0.05)
+    }
+
+    #[test]
+    fn noise_vanishes_on_the_lattice() {
+        let noise = Noise::new(3);
+        for (x, y, z) in [(0.0, 0.0, 0.0), (1.0, -2.0, 5.0), (-7.0, 3.0, -1.0)] {
+            assert_eq!(noise.noise(Vec3::new(x, y, z)), 0.0);
+        }
+    }
+
+    #[test]
+    fn noise_is_bounded_and_smooth() {
+        let noise = Noise::new(4);
+
+        for p in points() {
+            let n = noise.noise(p);
+            assert!(n.abs() < 1.1, "{n}");
+
+            // The gradients are at most 2 long, so a small step can only change it a little
+            let step = noise.noise(p + Vec3::new(1e-3, 1e-3, 1e-3));
+            assert!((step - n).abs() < 1e-2);
+
+            assert!((0.0..1.1).contains(&noise.turbulence(p, 6)));
+        }
+    }
+
+    #[test]
+    fn seeds_decide_the_pattern() {
+        let (a, b, c) = (Noise::new(5), Noise::from(5), Noise::new(6));
+
+        assert!(points().all(|p| a.noise(p) == b.noise(p)));
+        assert!(points().any(|p| a.noise(p) != c.noise(p)));
+        assert_eq!(u64::from(c), 6);
+    }
+
+    #[test]
+    fn octaves_add_finer_detail() {
+        let noise = Noise::new(7);
+
+        for p in points().take(50) {
+            assert_eq!(noise.fbm(p, 1), noise.noise(p));
+            assert_eq!(noise.fbm(p, 0), noise.noise(p));
+
+            let two = (noise.noise(p) + 0.5 * noise.noise(p * 2.0)) / 1.5;
+            assert!((noise.fbm(p, 2) - two).abs() < 1e-6);
+        }
+    }
+}
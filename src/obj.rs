@@ -0,0 +1,519 @@
+use crate::geometry::{Mesh, vertex_normals};
+use crate::vector::Vec3;
+use crate::material::{Material, Texture};
+use crate::bsdf::{Phong, CookTorrance, Emissive};
+
+use std::io::{BufReader, BufRead};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(PathBuf, std::io::Error),
+    /// Malformed statement in an obj or mtl file
+    Parse { path: PathBuf, line: usize, message: String }
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ObjError::Parse { path, line, message } => write!(f, "{}:{}: {}", path.display(), line, message)
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+/// A triangle of an obj file, uvs and normals index into the mesh's arrays
+#[derive(Debug, Copy, Clone)]
+pub struct ObjTriangle {
+    pub positions: [usize; 3],
+    pub uvs: Option<[usize; 3]>,
+    pub normals: Option<[usize; 3]>,
+    /// Index into the mesh's materials, None before any usemtl
+    pub material: Option<usize>
+}
+
+/// Triangulated contents of an obj file along with its mtl materials
+#[derive(Debug, Default)]
+pub struct ObjMesh {
+    pub positions: Vec<Vec3<f32>>,
+    pub uvs: Vec<(f32, f32)>,
+    pub normals: Vec<Vec3<f32>>,
+    pub triangles: Vec<ObjTriangle>,
+    pub materials: Vec<(String, Material)>
+}
+
+/// Where a mesh vertex takes its shading normal from
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Shading {
+    /// A vn of the file
+    Given(usize),
+    /// Averaged from the faces around the position
+    Averaged,
+    /// The normal of a face without vertex normals, so its corners aren't shared
+    Flat(usize),
+    /// Nothing in the mesh has normals
+    None
+}
+
+impl ObjMesh {
+    /// Build a mesh for each material the faces use, tagged with its index into
+    /// materials or None for faces before any usemtl. Faces with vertex normals
+    /// are smooth shaded, with smooth set the rest are too using averaged
+    /// normals. Faces with texture coordinates carry them over for image textures
+    pub fn meshes(&self, smooth: bool) -> Vec<(Option<usize>, Mesh)> {
+        let averaged = if smooth {
+            let triangles: Vec<[usize; 3]> = self.triangles.iter().map(|t| t.positions).collect();
+            vertex_normals(&self.positions, &triangles)
+        } else {
+            Vec::new()
+        };
+
+        let mut groups: Vec<(Option<usize>, Vec<usize>)> = Vec::new();
+        for (i, t) in self.triangles.iter().enumerate() {
+            match groups.iter_mut().find(|(m, _)| *m == t.material) {
+                Some((_, faces)) => faces.push(i),
+                None => groups.push((t.material, vec![i]))
+            }
+        }
+
+        groups.into_iter().map(|(material, faces)| {
+            let shaded = smooth || faces.iter().any(|&i| self.triangles[i].normals.is_some());
+            let textured = faces.iter().any(|&i| self.triangles[i].uvs.is_some());
+
+            // Obj corners index positions, uvs and normals separately, a mesh
+            // vertex is made for each combination that is used
+            let mut vertices: HashMap<(usize, Option<usize>, Shading), usize> = HashMap::new();
+            let mut positions = Vec::new();
+            let mut normals = Vec::new();
+            let mut uvs = Vec::new();
+
+            let triangles = faces.iter().map(|&i| {
+                let t = &self.triangles[i];
+
+                [0, 1, 2].map(|k| {
+                    let p = t.positions[k];
+                    let uv = t.uvs.map(|uvs| uvs[k]);
+                    let shading = match t.normals {
+                        Some(ns) => Shading::Given(ns[k]),
+                        None if smooth => Shading::Averaged,
+                        None if shaded => Shading::Flat(i),
+                        None => Shading::None
+                    };
+
+                    *vertices.entry((p, uv, shading)).or_insert_with(|| {
+                        positions.push(self.positions[p]);
+                        uvs.push(uv.map(|uv| self.uvs[uv]).unwrap_or((0.0, 0.0)));
+                        normals.push(match shading {
+                            Shading::Given(n) => self.normals[n],
+                            Shading::Averaged => averaged[p],
+                            Shading::Flat(_) => {
+                                let [a, b, c] = t.positions.map(|p| self.positions[p]);
+                                (b - a).cross(&(c - a)).normalized()
+                            }
+                            Shading::None => Vec3::new(0.0, 0.0, 0.0)
+                        });
+                        positions.len() - 1
+                    })
+                })
+            }).collect();
+
+            let mut mesh = Mesh::new(positions, triangles);
+            if shaded {
+                mesh = mesh.with_normals(normals);
+            }
+            if textured {
+                mesh = mesh.with_uvs(uvs);
+            }
+
+            (material, mesh)
+        }).collect()
+    }
+}
+
+/// The subset of an mtl material that maps onto our materials
+#[derive(Debug)]
+struct MtlDesc {
+    name: String,
+    kd: Vec3<f32>,
+    ks: Vec3<f32>,
+    ke: Vec3<f32>,
+    ns: f32,
+    d: f32,
+    ni: f32,
+    illum: u32,
+    roughness: Option<f32>,
+    metallic: Option<f32>
+}
+
+impl MtlDesc {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            kd: Vec3::new(0.8, 0.8, 0.8),
+            ks: Vec3::new(0.0, 0.0, 0.0),
+            ke: Vec3::new(0.0, 0.0, 0.0),
+            ns: 10.0,
+            d: 1.0,
+            ni: 1.0,
+            illum: 2,
+            roughness: None,
+            metallic: None
+        }
+    }
+
+    fn material(&self) -> Material {
+        if !self.ke.is_black() {
+            return Material::Emissive(Emissive::new(self.ke));
+        }
+
+        // Physically based extension, roughness and metallic
+        if self.roughness.is_some() || self.metallic.is_some() {
+            let metallic = self.metallic.unwrap_or(0.0);
+            let f0 = 0.04 * (1.0 - metallic) + self.ks.max_component().max(metallic) * metallic;
+
+            return Material::CookTorrance(CookTorrance::new(
+                Texture::Solid(self.kd),
+                f0,
+                self.roughness.unwrap_or(0.5).max(0.01),
+                1.0 - metallic
+            ));
+        }
+
+        // illum 3 and up turn on ray traced reflections
+        let kr = if self.illum >= 3 { self.ks.max_component() } else { 0.0 };
+
+        Material::Phong(Phong::new(
+            Texture::Solid(self.kd),
+            1.0 - kr,
+            self.ks.max_component(),
+            self.ns,
+            kr,
+            1.0 - self.d,
+            self.ni
+        ))
+    }
+}
+
+fn parse_err(path: &Path, line: usize, message: String) -> ObjError {
+    ObjError::Parse { path: path.to_path_buf(), line, message }
+}
+
+fn floats<'a, I: Iterator<Item = &'a str>>(args: I, n: usize, path: &Path, line: usize) -> Result<Vec<f32>, ObjError> {
+    let vs = args.take(n)
+        .map(|a| a.parse::<f32>().map_err(|_| parse_err(path, line, format!("`{a}` is not a number"))))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if vs.len() < n {
+        return Err(parse_err(path, line, format!("expected {n} numbers")));
+    }
+
+    Ok(vs)
+}
+
+fn read_lines(path: &Path) -> Result<Vec<String>, ObjError> {
+    let f = File::open(path).map_err(|e| ObjError::Io(path.to_path_buf(), e))?;
+
+    BufReader::new(f).lines()
+        .collect::<Result<_, _>>()
+        .map_err(|e| ObjError::Io(path.to_path_buf(), e))
+}
+
+fn load_mtl(path: &Path) -> Result<Vec<MtlDesc>, ObjError> {
+    let mut mtls: Vec<MtlDesc> = Vec::new();
+
+    for (i, line) in read_lines(path)?.iter().enumerate() {
+        let line_no = i + 1;
+        let mut words = line.split_ascii_whitespace();
+
+        let key = match words.next() {
+            Some(k) if !k.starts_with('#') => k,
+            _ => continue
+        };
+
+        if key == "newmtl" {
+            let name = words.next().ok_or_else(|| parse_err(path, line_no, "newmtl without a name".to_string()))?;
+            mtls.push(MtlDesc::new(name));
+            continue;
+        }
+
+        let m = match mtls.last_mut() {
+            Some(m) => m,
+            None => return Err(parse_err(path, line_no, format!("`{key}` before newmtl")))
+        };
+
+        let color = |ws| floats(ws, 3, path, line_no).map(|v| Vec3::new(v[0], v[1], v[2]));
+        let scalar = |ws| floats(ws, 1, path, line_no).map(|v| v[0]);
+
+        match key {
+            "Kd" => m.kd = color(words)?,
+            "Ks" => m.ks = color(words)?,
+            "Ke" => m.ke = color(words)?,
+            "Ns" => m.ns = scalar(words)?,
+            "d" => m.d = scalar(words)?,
+            "Tr" => m.d = 1.0 - scalar(words)?,
+            "Ni" => m.ni = scalar(words)?,
+            "Pr" => m.roughness = Some(scalar(words)?),
+            "Pm" => m.metallic = Some(scalar(words)?),
+            "illum" => m.illum = scalar(words)? as u32,
+            // Anything else, like texture maps, isn't supported
+            _ => {}
+        }
+    }
+
+    Ok(mtls)
+}
+
+/// Resolve a 1 based, possibly negative, obj index into an array of length len
+fn index(s: &str, len: usize, path: &Path, line: usize) -> Result<usize, ObjError> {
+    let i: i64 = s.parse().map_err(|_| parse_err(path, line, format!("`{s}` is not an index")))?;
+
+    let res = if i < 0 { len as i64 + i } else { i - 1 };
+    if res < 0 || res >= len as i64 {
+        return Err(parse_err(path, line, format!("index {i} out of range")));
+    }
+
+    Ok(res as usize)
+}
+
+/// Attribute indices for a triangle if every corner has one
+fn all(corners: [Option<usize>; 3]) -> Option<[usize; 3]> {
+    match corners {
+        [Some(a), Some(b), Some(c)] => Some([a, b, c]),
+        _ => None
+    }
+}
+
+/// Read an obj file and every mtl library it uses, polygons are triangulated
+pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<ObjMesh, ObjError> {
+    let path = path.as_ref();
+    let base = path.parent().unwrap_or(Path::new("."));
+
+    let mut mesh = ObjMesh::default();
+    let mut library: Vec<MtlDesc> = Vec::new();
+    let mut current = None;
+
+    for (i, line) in read_lines(path)?.iter().enumerate() {
+        let line_no = i + 1;
+        let mut words = line.split_ascii_whitespace();
+
+        let key = match words.next() {
+            Some(k) if !k.starts_with('#') => k,
+            _ => continue
+        };
+
+        match key {
+            "v" => {
+                let v = floats(words, 3, path, line_no)?;
+                mesh.positions.push(Vec3::new(v[0], v[1], v[2]));
+            }
+            "vn" => {
+                let v = floats(words, 3, path, line_no)?;
+                let n = Vec3::new(v[0], v[1], v[2]);
+                if n.mag() == 0.0 {
+                    return Err(parse_err(path, line_no, "zero length normal".to_string()));
+                }
+                mesh.normals.push(n.normalized());
+            }
+            "vt" => {
+                let v = floats(words, 2, path, line_no)?;
+                mesh.uvs.push((v[0], v[1]));
+            }
+            "f" => {
+                let mut corners = Vec::new();
+
+                for w in words {
+                    let mut parts = w.split('/');
+                    let v = index(parts.next().unwrap_or(""), mesh.positions.len(), path, line_no)?;
+                    let vt = match parts.next() {
+                        Some(s) if !s.is_empty() => Some(index(s, mesh.uvs.len(), path, line_no)?),
+                        _ => None
+                    };
+                    let vn = match parts.next() {
+                        Some(s) if !s.is_empty() => Some(index(s, mesh.normals.len(), path, line_no)?),
+                        _ => None
+                    };
+
+                    corners.push((v, vt, vn));
+                }
+
+                if corners.len() < 3 {
+                    return Err(parse_err(path, line_no, "face with fewer than 3 vertices".to_string()));
+                }
+
+                // Fan triangulation
+                for k in 2..corners.len() {
+                    let tri = [corners[0], corners[k-1], corners[k]];
+
+                    mesh.triangles.push(ObjTriangle {
+                        positions: tri.map(|c| c.0),
+                        uvs: all(tri.map(|c| c.1)),
+                        normals: all(tri.map(|c| c.2)),
+                        material: current
+                    });
+                }
+            }
+            "mtllib" => {
+                for name in words {
+                    library.extend(load_mtl(&base.join(name))?);
+                }
+            }
+            "usemtl" => {
+                let name = words.next().unwrap_or("");
+
+                // Materials are added to the mesh the first time they are used
+                current = match mesh.materials.iter().position(|(n, _)| n == name) {
+                    Some(i) => Some(i),
+                    None => {
+                        let m = library.iter()
+                            .find(|m| m.name == name)
+                            .ok_or_else(|| parse_err(path, line_no, format!("unknown material `{name}`")))?;
+
+                        mesh.materials.push((m.name.clone(), m.material()));
+                        Some(mesh.materials.len() - 1)
+                    }
+                };
+            }
+            // Groups, objects, smoothing groups and curves are ignored
+            _ => {}
+        }
+    }
+
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Geometry, Ray};
+
+    /// Write files into a directory of their own under the temp directory,
+    /// returning the path of the first
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gi_tracer_obj_{}_{test}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for (name, src) in files {
+            std::fs::write(dir.join(name), src).unwrap();
+        }
+        dir.join(files[0].0)
+    }
+
+    fn parse_error(test: &str, files: &[(&str, &str)]) -> (usize, String) {
+        match load_obj(write_files(test, files)) {
+            Err(ObjError::Parse { line, message, .. }) => (line, message),
+            other => panic!("expected a parse error, got {other:?}")
+        }
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_end() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvt 0 0\nvt 1 1\nvn 0 0 2\nf -4/1/1 -3/2/1 -2/2/-1 -1/1/-1\n";
+        let mesh = load_obj(write_files("negative", &[("quad.obj", obj)])).unwrap();
+
+        let positions: Vec<[usize; 3]> = mesh.triangles.iter().map(|t| t.positions).collect();
+        assert_eq!(positions, vec![[0, 1, 2], [0, 2, 3]]);
+        assert_eq!(mesh.triangles[0].uvs, Some([0, 1, 1]));
+        assert_eq!(mesh.triangles[1].normals, Some([0, 0, 0]));
+
+        // Normals are normalized as they are read
+        assert_eq!(mesh.normals[0].z, 1.0);
+    }
+
+    #[test]
+    fn bad_faces_report_their_line() {
+        assert_eq!(
+            parse_error("range", &[("a.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\n\nf 1 2 4\n")]),
+            (5, "index 4 out of range".to_string())
+        );
+        assert_eq!(
+            parse_error("zero", &[("a.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 0 1 2\n")]),
+            (4, "index 0 out of range".to_string())
+        );
+        assert_eq!(
+            parse_error("short", &[("a.obj", "v 0 0 0\nv 1 0 0\nf 1 2\n")]),
+            (3, "face with fewer than 3 vertices".to_string())
+        );
+        assert_eq!(
+            parse_error("word", &[("a.obj", "v 0 0 0\nf a b c\n")]),
+            (2, "`a` is not an index".to_string())
+        );
+    }
+
+    #[test]
+    fn zero_normals_report_their_line() {
+        assert_eq!(
+            parse_error("normal", &[("a.obj", "v 0 0 0\nvn 0 1 0\nvn 0 0 0\n")]),
+            (3, "zero length normal".to_string())
+        );
+    }
+
+    #[test]
+    fn mtl_materials_are_mapped() {
+        let mtl = "newmtl lamp\nKe 4 4 4\n\nnewmtl metal\nKd 0.5 0.5 0.5\nPr 0.2\nPm 1\n\nnewmtl plastic\nKd 1 0 0\nKs 0.5 0.5 0.5\nNs 50\nd 0.75\n";
+        let obj = "mtllib a.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl metal\nf 1 2 3\nusemtl lamp\nf 1 2 3\nusemtl plastic\nf 1 2 3\nusemtl metal\nf 1 2 3\n";
+        let mesh = load_obj(write_files("mtl", &[("a.obj", obj), ("a.mtl", mtl)])).unwrap();
+
+        // Only the materials in use are kept, in the order they are first used
+        let names: Vec<&str> = mesh.materials.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["metal", "lamp", "plastic"]);
+        assert_eq!(mesh.triangles[3].material, Some(0));
+
+        assert!(matches!(&mesh.materials[0].1, Material::CookTorrance(c) if c.k == 0.0 && c.f0 == 1.0 && c.roughness == 0.2));
+        assert!(matches!(&mesh.materials[1].1, Material::Emissive(_)));
+        assert!(matches!(&mesh.materials[2].1, Material::Phong(p) if p.ks == 0.5 && p.ke == 50.0 && p.kt == 0.25));
+
+        assert_eq!(
+            parse_error("unknown", &[("a.obj", "mtllib a.mtl\nusemtl wood\n"), ("a.mtl", mtl)]),
+            (2, "unknown material `wood`".to_string())
+        );
+    }
+
+    #[test]
+    fn faces_are_grouped_into_a_mesh_per_material() {
+        let mtl = "newmtl red\nKd 1 0 0\n";
+        let obj = "mtllib a.mtl\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1 2 3\nusemtl red\nf 1 3 4\nf 3 2 1\n";
+        let mesh = load_obj(write_files("groups", &[("a.obj", obj), ("a.mtl", mtl)])).unwrap();
+
+        let meshes = mesh.meshes(false);
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes[0].0, None);
+        assert_eq!(meshes[1].0, Some(0));
+        assert!((meshes[0].1.area() - 0.5).abs() < 1e-6);
+        assert!((meshes[1].1.area() - 1.0).abs() < 1e-6);
+        assert!(!meshes[0].1.has_normals());
+    }
+
+    #[test]
+    fn corners_keep_their_own_normals() {
+        // Two faces of a crease share an edge but not its normals, and a third
+        // face without normals stays flat
+        let obj = "v 0 0 0\nv 0 1 0\nv 1 0 0\nv -1 0 1\nv 0 0 -1\nvn 0 0 1\nvn 1 0 1\n\
+                   f 1//1 3//1 2//1\nf 1//2 2//2 4//2\nf 1 5 2\n";
+        let mesh = load_obj(write_files("normals", &[("a.obj", obj)])).unwrap();
+
+        let (_, m) = mesh.meshes(false).pop().unwrap();
+        assert!(m.has_normals());
+        let g = Geometry::Mesh(m);
+
+        // Shade the middle of a face from the side its normals are on
+        let shade = |center: Vec3<f32>, side: Vec3<f32>| {
+            let ray = Ray::new(center + side, -side);
+            g.hit_record(&ray, &g.hit(&ray).unwrap()).n
+        };
+        let third = 1.0 / 3.0;
+
+        let n = shade(Vec3::new(third, third, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        assert!((n - Vec3::new(0.0, 0.0, 1.0)).mag() < 1e-5);
+
+        let n = shade(Vec3::new(-third, third, third), Vec3::new(1.0, 0.0, 1.0));
+        assert!((n - Vec3::new(1.0, 0.0, 1.0).normalized()).mag() < 1e-5);
+
+        let n = shade(Vec3::new(0.0, third, -third), Vec3::new(1.0, 0.0, 0.0));
+        assert!(n.x.abs() > 0.999);
+    }
+}
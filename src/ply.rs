@@ -1,61 +1,467 @@
-use crate::geometry::Geometry;
+use crate::geometry::Mesh;
 use crate::vector::Vec3;
 
-use std::io::{BufReader, BufRead};
+use std::io::{BufReader, BufRead, Read};
 use std::fs::File;
+use std::fmt;
 
-pub fn load_ply(path: &str) -> Vec<Geometry> {
-    let r = BufReader::new(File::open(path).unwrap());
+#[derive(Debug)]
+pub enum PlyError {
+    Io(std::io::Error),
+    /// Malformed header, with the line it is on
+    Header(usize, String),
+    /// Malformed element data
+    Data(String)
+}
 
-    let mut lines = r.lines()
-        .filter_map(|l| l.ok());
+impl fmt::Display for PlyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlyError::Io(e) => write!(f, "{e}"),
+            PlyError::Header(line, msg) => write!(f, "header line {line}: {msg}"),
+            PlyError::Data(msg) => write!(f, "{msg}")
+        }
+    }
+}
 
-    // Find the number of vertices
-    let vcount = lines.find(|s| {
-        s.starts_with("element vertex")
-    }).map(|s| {
-        s.split(" ").nth(2).unwrap().parse::<usize>().unwrap()
-    }).unwrap();
+impl std::error::Error for PlyError {}
 
-    // Find the number of faces
-    let fcount = lines.find(|s| {
-        s.starts_with("element face")
-    }).map(|s| {
-        s.split(" ").nth(2).unwrap().parse::<usize>().unwrap()
-    }).unwrap();
+impl From<std::io::Error> for PlyError {
+    fn from(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            PlyError::Data("file ended before all elements were read".to_string())
+        } else {
+            PlyError::Io(e)
+        }
+    }
+}
 
-    // Consume the rest of the header
-    lines.find(|s| s.starts_with("end_header")).unwrap();
+/// Triangulated contents of a ply file, attributes are per vertex when present
+#[derive(Debug, Default)]
+pub struct PlyMesh {
+    pub positions: Vec<Vec3<f32>>,
+    pub normals: Option<Vec<Vec3<f32>>>,
+    pub colors: Option<Vec<Vec3<f32>>>,
+    pub uvs: Option<Vec<(f32, f32)>>,
+    pub triangles: Vec<[usize; 3]>
+}
 
-    // Read in the list of all the vertices
-    let mut vs = Vec::with_capacity(vcount);
+impl PlyMesh {
+    /// A mesh sharing the vertex positions between faces, smooth shaded if the
+    /// file has normals, textured by its uvs and colored by its vertex colors
+    /// if it has them
+    pub fn mesh(&self) -> Mesh {
+        let mesh = Mesh::new(self.positions.clone(), self.triangles.clone());
+
+        let mesh = match &self.normals {
+            Some(ns) => mesh.with_normals(ns.clone()),
+            None => mesh
+        };
+
+        let mesh = match &self.uvs {
+            Some(uvs) => mesh.with_uvs(uvs.clone()),
+            None => mesh
+        };
+
+        match &self.colors {
+            Some(cs) => mesh.with_colors(cs.clone()),
+            None => mesh
+        }
+    }
+}
 
-    for _ in 0..vcount {
-        let s = lines.next().unwrap();
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Format { Ascii, BinaryLittleEndian, BinaryBigEndian }
 
-        let mut ps = s.split_ascii_whitespace();
-        let x = ps.next().unwrap().parse::<f32>().unwrap();
-        let y = ps.next().unwrap().parse::<f32>().unwrap();
-        let z = ps.next().unwrap().parse::<f32>().unwrap();
+#[derive(Debug, Copy, Clone)]
+enum Scalar { I8, U8, I16, U16, I32, U32, F32, F64 }
 
-        vs.push(Vec3{ x, y, z });
+impl Scalar {
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => return None
+        })
     }
 
-    // Now go through the faces and push all the triangles
-    let mut ts = Vec::with_capacity(fcount);
+    fn size(&self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8
+        }
+    }
 
-    for _ in 0..fcount {
-        let s = lines.next().unwrap();
-        
-        let mut ps = s.split_ascii_whitespace();
-        ps.next().unwrap();
+    /// Scale that maps the type's range to [0, 1] for color channels
+    fn color_scale(&self) -> f64 {
+        match self {
+            Scalar::U8 => 1.0 / 255.0,
+            Scalar::U16 => 1.0 / 65535.0,
+            _ => 1.0
+        }
+    }
+}
 
-        let x = ps.next().unwrap().parse::<usize>().unwrap();
-        let y = ps.next().unwrap().parse::<usize>().unwrap();
-        let z = ps.next().unwrap().parse::<usize>().unwrap();
+#[derive(Debug)]
+enum Property {
+    Scalar(String, Scalar),
+    List(String, Scalar, Scalar)  // name, count type, item type
+}
 
-        ts.push(Geometry::new_triangle(vs[x], vs[y], vs[z]));
+impl Property {
+    fn name(&self) -> &str {
+        match self {
+            Property::Scalar(n, _) | Property::List(n, _, _) => n
+        }
     }
+}
+
+#[derive(Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    props: Vec<Property>
+}
+
+/// Source of element values for either the ascii or binary encodings
+enum Body<R: Read> {
+    Ascii(std::vec::IntoIter<String>),
+    Binary(R, bool)     // reader, big endian
+}
+
+impl<R: Read> Body<R> {
+    fn next(&mut self, ty: Scalar) -> Result<f64, PlyError> {
+        match self {
+            Body::Ascii(tokens) => {
+                let t = tokens.next()
+                    .ok_or_else(|| PlyError::Data("file ended before all elements were read".to_string()))?;
+
+                t.parse::<f64>()
+                    .map_err(|_| PlyError::Data(format!("`{t}` is not a number")))
+            }
+            Body::Binary(r, big) => {
+                let mut buf = [0u8; 8];
+                let buf = &mut buf[..ty.size()];
+                r.read_exact(buf)?;
+
+                macro_rules! num {
+                    ($t:ty) => {{
+                        let b = buf.try_into().unwrap();
+                        (if *big { <$t>::from_be_bytes(b) } else { <$t>::from_le_bytes(b) }) as f64
+                    }}
+                }
+
+                Ok(match ty {
+                    Scalar::I8 => num!(i8),
+                    Scalar::U8 => num!(u8),
+                    Scalar::I16 => num!(i16),
+                    Scalar::U16 => num!(u16),
+                    Scalar::I32 => num!(i32),
+                    Scalar::U32 => num!(u32),
+                    Scalar::F32 => num!(f32),
+                    Scalar::F64 => num!(f64),
+                })
+            }
+        }
+    }
+}
+
+/// A list length or vertex index, which has to be a whole number that isn't negative
+fn whole(v: f64, what: &str) -> Result<usize, PlyError> {
+    if v >= 0.0 && v.fract() == 0.0 && v <= u32::MAX as f64 {
+        Ok(v as usize)
+    } else {
+        Err(PlyError::Data(format!("`{v}` is not a valid {what}")))
+    }
+}
+
+fn read_header<R: BufRead>(r: &mut R) -> Result<(Format, Vec<Element>), PlyError> {
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut line_no = 0;
+
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line)? == 0 {
+            return Err(PlyError::Header(line_no, "missing end_header".to_string()));
+        }
+        line_no += 1;
+
+        let err = |msg: &str| PlyError::Header(line_no, msg.to_string());
+        let words: Vec<&str> = line.split_ascii_whitespace().collect();
+
+        if line_no == 1 {
+            if words != ["ply"] {
+                return Err(err("not a ply file"));
+            }
+            continue;
+        }
+
+        match words.as_slice() {
+            [] | ["comment", ..] | ["obj_info", ..] => {}
+            ["format", f, _version] => {
+                format = Some(match *f {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    "binary_big_endian" => Format::BinaryBigEndian,
+                    _ => return Err(err(&format!("unknown format `{f}`")))
+                });
+            }
+            ["element", name, count] => {
+                elements.push(Element {
+                    name: name.to_string(),
+                    count: count.parse().map_err(|_| err("element count is not a number"))?,
+                    props: Vec::new()
+                });
+            }
+            ["property", "list", count, item, name] => {
+                let count = Scalar::parse(count).ok_or_else(|| err(&format!("unknown type `{count}`")))?;
+                let item = Scalar::parse(item).ok_or_else(|| err(&format!("unknown type `{item}`")))?;
+
+                elements.last_mut()
+                    .ok_or_else(|| err("property outside of an element"))?
+                    .props.push(Property::List(name.to_string(), count, item));
+            }
+            ["property", ty, name] => {
+                let ty = Scalar::parse(ty).ok_or_else(|| err(&format!("unknown type `{ty}`")))?;
+
+                elements.last_mut()
+                    .ok_or_else(|| err("property outside of an element"))?
+                    .props.push(Property::Scalar(name.to_string(), ty));
+            }
+            ["end_header"] => break,
+            _ => return Err(err(&format!("unexpected `{}`", line.trim())))
+        }
+    }
+
+    let format = format.ok_or_else(|| PlyError::Header(line_no, "missing format".to_string()))?;
+    Ok((format, elements))
+}
+
+/// Read a ply mesh, faces with more than three vertices are triangulated
+pub fn read_ply<R: BufRead>(mut r: R) -> Result<PlyMesh, PlyError> {
+    let (format, elements) = read_header(&mut r)?;
+
+    let mut body = match format {
+        Format::Ascii => {
+            let mut s = String::new();
+            r.read_to_string(&mut s)?;
+
+            let tokens: Vec<String> = s.split_ascii_whitespace().map(String::from).collect();
+            Body::Ascii(tokens.into_iter())
+        }
+        Format::BinaryLittleEndian => Body::Binary(r, false),
+        Format::BinaryBigEndian => Body::Binary(r, true)
+    };
+
+    let mut mesh = PlyMesh::default();
 
-    ts
+    for e in elements.iter() {
+        let find = |names: &[&str]| e.props.iter().position(|p| names.contains(&p.name()));
+
+        let pos = [find(&["x"]), find(&["y"]), find(&["z"])];
+        let norm = [find(&["nx"]), find(&["ny"]), find(&["nz"])];
+        let color = [find(&["red", "r"]), find(&["green", "g"]), find(&["blue", "b"])];
+        let uv = [find(&["u", "s", "texture_u", "texture_s"]), find(&["v", "t", "texture_v", "texture_t"])];
+        let indices = find(&["vertex_indices", "vertex_index"]);
+
+        let is_vertex = e.name == "vertex";
+        if is_vertex {
+            if pos.iter().any(|p| p.is_none()) {
+                return Err(PlyError::Data("vertex element is missing x, y or z".to_string()));
+            }
+
+            // Counts come from the header and can't be trusted to size anything up front
+            if norm.iter().all(|p| p.is_some()) {
+                mesh.normals = Some(Vec::new());
+            }
+            if color.iter().all(|p| p.is_some()) {
+                mesh.colors = Some(Vec::new());
+            }
+            if uv.iter().all(|p| p.is_some()) {
+                mesh.uvs = Some(Vec::new());
+            }
+        }
+
+        let is_face = e.name == "face" && indices.is_some();
+
+        let mut values = vec![0.0f64; e.props.len()];
+        let mut scales = vec![1.0f64; e.props.len()];
+        let mut list = Vec::new();
+
+        // Elements without properties take up no room, however many the header claims
+        let count = if e.props.is_empty() { 0 } else { e.count };
+
+        for _ in 0..count {
+            for (i, p) in e.props.iter().enumerate() {
+                match p {
+                    Property::Scalar(_, ty) => {
+                        values[i] = body.next(*ty)?;
+                        scales[i] = ty.color_scale();
+                    }
+                    Property::List(_, count_ty, item_ty) => {
+                        let n = whole(body.next(*count_ty)?, "list length")?;
+                        let keep = Some(i) == indices;
+                        if keep {
+                            list.clear();
+                        }
+
+                        for _ in 0..n {
+                            let v = body.next(*item_ty)?;
+                            if keep {
+                                list.push(whole(v, "vertex index")?);
+                            }
+                        }
+                    }
+                }
+            }
+
+            if is_vertex {
+                let get = |i: Option<usize>| values[i.unwrap()] as f32;
+                let vec = |ps: [Option<usize>; 3]| Vec3::new(get(ps[0]), get(ps[1]), get(ps[2]));
+
+                mesh.positions.push(vec(pos));
+
+                if let Some(ns) = mesh.normals.as_mut() {
+                    ns.push(vec(norm));
+                }
+
+                if let Some(cs) = mesh.colors.as_mut() {
+                    let c = |i: Option<usize>| (values[i.unwrap()] * scales[i.unwrap()]) as f32;
+                    cs.push(Vec3::new(c(color[0]), c(color[1]), c(color[2])));
+                }
+
+                if let Some(uvs) = mesh.uvs.as_mut() {
+                    uvs.push((get(uv[0]), get(uv[1])));
+                }
+            } else if is_face {
+                // Fan triangulation
+                for k in 2..list.len() {
+                    mesh.triangles.push([list[0], list[k-1], list[k]]);
+                }
+            }
+        }
+    }
+
+    let n = mesh.positions.len();
+    if let Some(bad) = mesh.triangles.iter().flatten().find(|&&i| i >= n) {
+        return Err(PlyError::Data(format!("face refers to vertex {bad} but there are only {n}")));
+    }
+
+    Ok(mesh)
+}
+
+pub fn load_ply(path: &str) -> Result<PlyMesh, PlyError> {
+    read_ply(BufReader::new(File::open(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUAD: &str = "ply
+format ascii 1.0
+comment a unit square
+element vertex 4
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 255 0 0
+1 0 0 0 255 0
+1 1 0 0 0 255
+0 1 0 255 255 255
+4 0 1 2 3
+";
+
+    fn read(src: &[u8]) -> Result<PlyMesh, PlyError> {
+        read_ply(src)
+    }
+
+    fn data_error(src: &[u8]) -> String {
+        match read(src) {
+            Err(PlyError::Data(msg)) => msg,
+            other => panic!("expected a data error, got {other:?}")
+        }
+    }
+
+    #[test]
+    fn ascii_quads_are_fanned_into_triangles() {
+        let mesh = read(QUAD.as_bytes()).unwrap();
+
+        assert_eq!(mesh.positions.len(), 4);
+        assert_eq!(mesh.triangles, vec![[0, 1, 2], [0, 2, 3]]);
+        assert!(mesh.normals.is_none() && mesh.uvs.is_none());
+
+        // 8 bit colors are scaled to [0, 1]
+        let colors = mesh.colors.unwrap();
+        assert_eq!((colors[1].x, colors[1].y, colors[1].z), (0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn binary_files_match_ascii() {
+        for big_endian in [false, true] {
+            let mut src = format!(
+                "ply\nformat {} 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\n\
+                 property float nx\nproperty float ny\nproperty float nz\nproperty float s\nproperty float t\n\
+                 element face 1\nproperty list uchar uint vertex_indices\nend_header\n",
+                if big_endian { "binary_big_endian" } else { "binary_little_endian" }
+            ).into_bytes();
+
+            let vertices = [[0.0f32, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0], [2.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0], [0.0, 3.0, 0.0, 0.0, 0.0, 1.0, 0.0, 1.0]];
+            for v in vertices.iter().flatten() {
+                src.extend(if big_endian { v.to_be_bytes() } else { v.to_le_bytes() });
+            }
+            src.push(3);
+            for i in [0u32, 1, 2] {
+                src.extend(if big_endian { i.to_be_bytes() } else { i.to_le_bytes() });
+            }
+
+            let mesh = read(&src).unwrap();
+            assert_eq!(mesh.positions[2].y, 3.0);
+            assert_eq!(mesh.normals.unwrap()[1].z, 1.0);
+            assert_eq!(mesh.uvs.unwrap()[1], (1.0, 0.0));
+            assert_eq!(mesh.triangles, vec![[0, 1, 2]]);
+        }
+    }
+
+    #[test]
+    fn headers_are_checked() {
+        assert!(matches!(read(b"obj\n"), Err(PlyError::Header(1, _))));
+        assert!(matches!(read(b"ply\nformat ascii 1.0\nelement vertex 1\n"), Err(PlyError::Header(3, _))));
+        assert!(matches!(read(b"ply\nformat ascii 1.0\nproperty float x\nend_header\n"), Err(PlyError::Header(3, _))));
+        assert!(matches!(read(b"ply\nelement vertex 0\nend_header\n"), Err(PlyError::Header(3, _))));
+    }
+
+    #[test]
+    fn bad_indices_are_rejected() {
+        let quad = |face: &str| QUAD.replace("4 0 1 2 3", face);
+
+        assert!(data_error(quad("3 0 1 -1").as_bytes()).contains("`-1` is not a valid vertex index"));
+        assert!(data_error(quad("3 0 1 1.5").as_bytes()).contains("`1.5` is not a valid vertex index"));
+        assert!(data_error(quad("-3 0 1 2").as_bytes()).contains("not a valid list length"));
+        assert!(data_error(quad("3 0 1 4").as_bytes()).contains("refers to vertex 4"));
+    }
+
+    #[test]
+    fn short_bodies_are_errors_whatever_the_header_claims() {
+        let huge = QUAD.replace("element vertex 4", "element vertex 99999999999999");
+        assert_eq!(data_error(huge.as_bytes()), "file ended before all elements were read");
+
+        let truncated = "ply\nformat binary_little_endian 1.0\nelement vertex 2\nproperty float x\nproperty float y\nproperty float z\nend_header\n\0\0\0\0";
+        assert_eq!(data_error(truncated.as_bytes()), "file ended before all elements were read");
+    }
 }
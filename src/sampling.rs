@@ -0,0 +1,425 @@
+use crate::vector::Vec3;
+
+use serde::{Serialize, Deserialize};
+
+use std::f32::consts::PI;
+
+/// Build an orthonormal basis (t, b) around the unit vector n
+pub fn basis(n: &Vec3<f32>) -> (Vec3<f32>, Vec3<f32>) {
+    let sign = 1.0f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+
+    (
+        Vec3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        Vec3::new(b, sign + n.y * n.y * a, -n.y)
+    )
+}
+
+/// Rotate a vector given in the local frame where z = n into world space
+pub fn to_world(v: &Vec3<f32>, n: &Vec3<f32>) -> Vec3<f32> {
+    let (t, b) = basis(n);
+    t * v.x + b * v.y + *n * v.z
+}
+
+pub fn uniform_disc(u: f32, v: f32) -> (f32, f32) {
+    let r = u.sqrt();
+    let phi = 2.0 * PI * v;
+
+    (r * phi.cos(), r * phi.sin())
+}
+
+/// Point in a regular polygon with the given number of sides inscribed in the
+/// unit circle, with a corner on +y
+pub fn uniform_polygon(u: f32, v: f32, sides: u32) -> (f32, f32) {
+    // Pick one of the triangles fanning out from the center, then a point in it
+    let n = sides as f32;
+    let i = (u * n).floor().min(n - 1.0);
+    let u = u * n - i;
+
+    let corner = |k: f32| {
+        let a = PI / 2.0 + 2.0 * PI * k / n;
+        (a.cos(), a.sin())
+    };
+    let (a, b) = (corner(i), corner(i + 1.0));
+
+    let r = u.sqrt();
+    (r * (a.0 * (1.0 - v) + b.0 * v), r * (a.1 * (1.0 - v) + b.1 * v))
+}
+
+/// Cosine weighted direction about +z, pdf = cos(theta) / pi
+pub fn cosine_hemisphere(u: f32, v: f32) -> Vec3<f32> {
+    let (x, y) = uniform_disc(u, v);
+    let z = (1.0 - x*x - y*y).max(0.0).sqrt();
+
+    Vec3::new(x, y, z)
+}
+
+pub fn cosine_hemisphere_pdf(cos_theta: f32) -> f32 {
+    cos_theta.max(0.0) / PI
+}
+
+/// Direction about +z distributed as cos(theta)^e, pdf = (e+1)/2pi cos(theta)^e
+pub fn phong_lobe(u: f32, v: f32, e: f32) -> Vec3<f32> {
+    let cos_theta = u.powf(1.0 / (e + 1.0));
+    let sin_theta = (1.0 - cos_theta*cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+pub fn phong_lobe_pdf(cos_theta: f32, e: f32) -> f32 {
+    if cos_theta <= 0.0 {
+        0.0
+    } else {
+        (e + 1.0) / (2.0 * PI) * cos_theta.powf(e)
+    }
+}
+
+/// Microfacet normal about +z distributed by the beckmann distribution, pdf = D(h) cos(theta_h)
+pub fn beckmann(u: f32, v: f32, roughness: f32) -> Vec3<f32> {
+    let tan2 = -roughness * roughness * (1.0 - u).ln();
+    let cos_theta = 1.0 / (1.0 + tan2).sqrt();
+    let sin_theta = (1.0 - cos_theta*cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// Multiple importance sampling weight for a sample drawn from f
+pub fn power_heuristic(f_pdf: f32, g_pdf: f32) -> f32 {
+    // Divide through by the larger density so huge ones can't overflow
+    if f_pdf >= g_pdf && f_pdf > 0.0 {
+        1.0 / (1.0 + (g_pdf / f_pdf).powi(2))
+    } else if g_pdf > 0.0 {
+        let r = (f_pdf / g_pdf).powi(2);
+        r / (1.0 + r)
+    } else {
+        0.0
+    }
+}
+
+pub fn beckmann_d(cos_theta: f32, roughness: f32) -> f32 {
+    if cos_theta <= 0.0 {
+        return 0.0;
+    }
+
+    let m2 = roughness * roughness;
+    let cos2 = cos_theta * cos_theta;
+    let tan2 = (1.0 - cos2) / cos2;
+
+    (-tan2 / m2).exp() / (PI * m2 * cos2 * cos2)
+}
+
+/// Piecewise constant density over [0, 1) made of equal width steps proportional to func
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Distribution1D {
+    func: Vec<f32>,
+    /// Running total of func normalized to end at 1, one longer than func
+    cdf: Vec<f32>,
+    /// Integral of func over [0, 1), zero makes the density uniform
+    integral: f32
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f32>) -> Self {
+        let n = func.len() as f32;
+
+        let mut cdf = Vec::with_capacity(func.len() + 1);
+        cdf.push(0.0);
+        for f in func.iter() {
+            cdf.push(cdf[cdf.len()-1] + f.max(0.0) / n);
+        }
+
+        let integral = cdf[cdf.len()-1];
+        for (i, c) in cdf.iter_mut().enumerate() {
+            *c = if integral > 0.0 { *c / integral } else { i as f32 / n };
+        }
+
+        Self { func, cdf, integral }
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    fn step_pdf(&self, i: usize) -> f32 {
+        if self.integral > 0.0 { self.func[i].max(0.0) / self.integral } else { 1.0 }
+    }
+
+    /// Pick a point in [0, 1) with its density and the step it is in, empty
+    /// distributions give zero for all three
+    pub fn sample(&self, u: f32) -> (f32, f32, usize) {
+        let n = self.func.len();
+        if n == 0 {
+            return (0.0, 0.0, 0);
+        }
+
+        let i = self.cdf.partition_point(|&c| c <= u).clamp(1, n) - 1;
+
+        let width = self.cdf[i+1] - self.cdf[i];
+        let du = if width > 0.0 { ((u - self.cdf[i]) / width).clamp(0.0, 1.0) } else { 0.0 };
+        let x = ((i as f32 + du) / n as f32).min(1.0 - f32::EPSILON);
+
+        (x, self.step_pdf(i), i)
+    }
+
+    /// Density with which sample picks x
+    pub fn pdf(&self, x: f32) -> f32 {
+        let n = self.func.len();
+        if n == 0 {
+            return 0.0;
+        }
+
+        self.step_pdf(((x * n as f32) as usize).min(n - 1))
+    }
+
+    /// Probability that sample lands in step i, for picking from a discrete set
+    pub fn pmf(&self, i: usize) -> f32 {
+        if self.func.is_empty() {
+            return 0.0;
+        }
+
+        self.step_pdf(i) / self.func.len() as f32
+    }
+}
+
+/// Piecewise constant density over [0, 1)^2 from a grid of values stored row by row
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Distribution2D {
+    rows: Vec<Distribution1D>,
+    /// Picks the row, in proportion to each row's integral
+    marginal: Distribution1D
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f32], width: usize, height: usize) -> Self {
+        // An empty grid has nothing to pick, leave it with a zero integral
+        if width == 0 || height == 0 {
+            return Self::default();
+        }
+
+        let rows: Vec<Distribution1D> = func.chunks_exact(width)
+            .take(height)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+
+        let marginal = Distribution1D::new(rows.iter().map(|r| r.integral()).collect());
+
+        Self { rows, marginal }
+    }
+
+    /// Pick a point, returning it with its density, which is zero for an empty grid
+    pub fn sample(&self, u: f32, v: f32) -> ((f32, f32), f32) {
+        if self.rows.is_empty() {
+            return ((0.0, 0.0), 0.0);
+        }
+
+        let (y, pdf_y, row) = self.marginal.sample(v);
+        let (x, pdf_x, _) = self.rows[row].sample(u);
+
+        ((x, y), pdf_x * pdf_y)
+    }
+
+    /// Integral of the grid's values over [0, 1)^2
+    pub fn integral(&self) -> f32 {
+        self.marginal.integral()
+    }
+
+    /// Density with which sample picks (x, y)
+    pub fn pdf(&self, (x, y): (f32, f32)) -> f32 {
+        let h = self.rows.len();
+        if h == 0 {
+            return 0.0;
+        }
+
+        let row = ((y * h as f32) as usize).min(h - 1);
+
+        self.marginal.pdf(y) * self.rows[row].pdf(x)
+    }
+}
+
+/// Midpoints of an n by n grid over [0, 1)^2, shared by the tests of every sampler
+#[cfg(test)]
+pub(crate) fn grid(n: usize) -> impl Iterator<Item = (f32, f32)> {
+    (0..n * n).map(move |i| (((i % n) as f32 + 0.5) / n as f32, ((i / n) as f32 + 0.5) / n as f32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Integrate f over the hemisphere about +z using cosine weighted samples
+    fn hemisphere_integral(f: impl Fn(&Vec3<f32>) -> f32) -> f32 {
+        let n = 256;
+        grid(n)
+            .map(|(u, v)| {
+                let d = cosine_hemisphere(u, v);
+                f(&d) / cosine_hemisphere_pdf(d.z)
+            })
+            .sum::<f32>() / (n * n) as f32
+    }
+
+    #[test]
+    fn basis_is_orthonormal() {
+        for n in [Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(1.0, 2.0, -3.0).normalized()] {
+            let (t, b) = basis(&n);
+
+            for (x, y) in [(t, b), (t, n), (b, n)] {
+                assert!(x.dot(&y).abs() < 1e-5);
+            }
+            for x in [t, b] {
+                assert!((x.mag() - 1.0).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn to_world_takes_z_to_n() {
+        let n = Vec3::new(-2.0, 1.0, 0.5).normalized();
+        let z = to_world(&Vec3::new(0.0, 0.0, 1.0), &n);
+
+        assert!((z - n).mag() < 1e-5);
+    }
+
+    #[test]
+    fn cosine_hemisphere_matches_its_pdf() {
+        for (u, v) in grid(16) {
+            let d = cosine_hemisphere(u, v);
+            assert!(d.z >= 0.0 && (d.mag() - 1.0).abs() < 1e-4);
+        }
+
+        // The average of cos(theta) under a cos(theta) / pi density is 2/3
+        let n = 256;
+        let mean = grid(n).map(|(u, v)| cosine_hemisphere(u, v).z).sum::<f32>() / (n * n) as f32;
+        assert!((mean - 2.0 / 3.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn lobe_pdfs_integrate_to_one() {
+        let phong = hemisphere_integral(|d| phong_lobe_pdf(d.z, 8.0));
+        assert!((phong - 1.0).abs() < 1e-2, "{phong}");
+
+        // Microfacet normals are distributed as D(h) cos(theta_h)
+        let beckmann = hemisphere_integral(|d| beckmann_d(d.z, 0.5) * d.z);
+        assert!((beckmann - 1.0).abs() < 1e-2, "{beckmann}");
+    }
+
+    #[test]
+    fn power_heuristic_weights_sum_to_one() {
+        for (f, g) in [(1.0, 1.0), (0.5, 2.0), (3.0, 0.1), (1e30, 2e30), (0.0, 1.0)] {
+            let sum = power_heuristic(f, g) + power_heuristic(g, f);
+            assert!((sum - 1.0).abs() < 1e-6);
+        }
+
+        assert_eq!(power_heuristic(1.0, 1.0), 0.5);
+        assert_eq!(power_heuristic(0.0, 0.0), 0.0);
+        assert_eq!(power_heuristic(f32::INFINITY, 1.0), 1.0);
+    }
+
+    #[test]
+    fn phong_lobe_concentrates_with_the_exponent() {
+        let mean_cos = |e: f32| grid(64).map(|(u, v)| phong_lobe(u, v, e).z).sum::<f32>() / 4096.0;
+
+        // The mean of cos(theta) under the lobe is (e + 1) / (e + 2)
+        for e in [0.0, 10.0, 100.0] {
+            assert!((mean_cos(e) - (e + 1.0) / (e + 2.0)).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn distribution_1d_samples_in_proportion() {
+        let d = Distribution1D::new(vec![1.0, 0.0, 3.0, -2.0]);
+        assert!((d.integral() - 1.0).abs() < 1e-6);
+
+        let total: f32 = (0..4).map(|i| d.pmf(i)).sum();
+        assert!((total - 1.0).abs() < 1e-6);
+        assert_eq!(d.pmf(3), 0.0);
+
+        let n = 1000;
+        let mut counts = [0; 4];
+        for i in 0..n {
+            let (x, pdf, step) = d.sample((i as f32 + 0.5) / n as f32);
+            assert_eq!(pdf, d.pdf(x));
+            assert_eq!(step, (x * 4.0) as usize);
+            counts[step] += 1;
+        }
+        assert_eq!(counts, [250, 0, 750, 0]);
+    }
+
+    #[test]
+    fn distribution_1d_is_uniform_without_weight() {
+        let d = Distribution1D::new(vec![0.0; 5]);
+
+        for i in 0..5 {
+            assert!((d.pmf(i) - 0.2).abs() < 1e-6);
+        }
+        let (x, pdf, _) = d.sample(0.7);
+        assert!((x - 0.7).abs() < 1e-6 && pdf == 1.0);
+    }
+
+    #[test]
+    fn distribution_2d_sample_matches_pdf() {
+        let func = [0.0, 1.0, 2.0, 3.0, 0.5, 0.0, 4.0, 1.0, 0.0];
+        let d = Distribution2D::new(&func, 3, 3);
+
+        // A whole number of grid points per cell, so averaging the density is exact
+        let n = 48;
+        let mut total = 0.0;
+        for (u, v) in grid(n) {
+            let ((x, y), pdf) = d.sample(u, v);
+            assert!((pdf - d.pdf((x, y))).abs() < 1e-5);
+
+            // No samples land where the function is zero
+            let cell = (y * 3.0) as usize * 3 + (x * 3.0) as usize;
+            assert!(func[cell] > 0.0);
+
+            total += d.pdf((u, v));
+        }
+
+        assert!((total / (n * n) as f32 - 1.0).abs() < 1e-5);
+        assert!((d.integral() - func.iter().sum::<f32>() / 9.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn empty_distributions_have_no_weight() {
+        for d in [Distribution1D::default(), Distribution1D::new(Vec::new())] {
+            assert_eq!(d.integral(), 0.0);
+            assert_eq!(d.sample(0.5), (0.0, 0.0, 0));
+            assert_eq!(d.pdf(0.5), 0.0);
+            assert_eq!(d.pmf(0), 0.0);
+        }
+
+        for d in [Distribution2D::default(), Distribution2D::new(&[], 0, 4)] {
+            assert_eq!(d.integral(), 0.0);
+            assert_eq!(d.sample(0.5, 0.5), ((0.0, 0.0), 0.0));
+            assert_eq!(d.pdf((0.5, 0.5)), 0.0);
+        }
+    }
+
+    #[test]
+    fn polygon_samples_fill_the_polygon_evenly() {
+        for sides in [3, 5, 8] {
+            let n = sides as f32;
+            let apothem = (PI / n).cos();
+            let mut counts = vec![0usize; sides as usize];
+
+            for (u, v) in grid(40) {
+                let (x, y) = uniform_polygon(u, v, sides);
+
+                // Inside every edge, which are all apothem away from the center
+                for k in 0..sides {
+                    let a = PI / 2.0 + 2.0 * PI * (k as f32 + 0.5) / n;
+                    assert!(x * a.cos() + y * a.sin() <= apothem + 1e-5);
+                }
+
+                let a = (y.atan2(x) - PI / 2.0).rem_euclid(2.0 * PI);
+                counts[((a / (2.0 * PI) * n) as usize).min(sides as usize - 1)] += 1;
+            }
+
+            // Every triangle of the fan covers the same area so gets the same share
+            let share = 1600 / sides as usize;
+            assert!(counts.iter().all(|&c| c.abs_diff(share) <= share / 10), "{counts:?}");
+        }
+    }
+}
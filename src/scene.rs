@@ -0,0 +1,763 @@
+use crate::world::World;
+use crate::vector::Vec3;
+use crate::geometry::{Geometry, Instance, Triangle};
+use crate::shapes::{Plane, Disc, Cuboid, OrientedBox, Cylinder, Cone, Torus};
+use crate::transform::Transform;
+use crate::camera::Camera;
+use crate::material::Material;
+use crate::light::Light;
+use crate::environment::Environment;
+use crate::integrator::Integrator;
+use crate::tone_map::Algorithm;
+use crate::accel::AccelMethod;
+use crate::light_sampler::LightSampling;
+use crate::ply::load_ply;
+use crate::obj::load_obj;
+use crate::ies::load_ies;
+use crate::bsdf::Phong;
+use crate::material::Texture;
+use crate::image::{self, Image};
+
+use serde::{Serialize, Deserialize};
+use toml::Spanned;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    pub samples: usize,
+    pub integrator: Integrator,
+    pub tone_map: Algorithm,
+    pub accel: AccelMethod,
+    pub light_sampling: LightSampling
+}
+
+impl RenderSettings {
+    /// Catch settings that would leave nothing to render
+    fn check(&self) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 {
+            return Err("width and height must be at least one pixel".to_string());
+        }
+        if self.samples == 0 {
+            return Err("samples must be at least one".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            width: 800,
+            height: 800,
+            samples: 100,
+            integrator: Integrator::Whitted,
+            tone_map: Algorithm::Ward,
+            accel: AccelMethod::default(),
+            light_sampling: LightSampling::default()
+        }
+    }
+}
+
+/// A world ready to render along with how to render it
+pub struct Scene {
+    pub world: World,
+    pub camera: Camera,
+    pub settings: RenderSettings,
+    view: CameraDesc
+}
+
+impl Scene {
+    /// Change the output resolution, the camera is rebuilt for the new aspect ratio
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.settings.width = width;
+        self.settings.height = height;
+        self.camera = self.view.build(&self.settings);
+    }
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(PathBuf, std::io::Error),
+    /// Error at a line of the scene file
+    Parse { path: PathBuf, line: usize, message: String }
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            SceneError::Parse { path, line, message } => write!(f, "{}:{}: {}", path.display(), line, message)
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+#[derive(Copy, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    look_from: Vec3<f32>,
+    look_at: Vec3<f32>,
+    #[serde(default = "default_up")]
+    up: Vec3<f32>,
+    fov: f32,
+    #[serde(default = "default_focal_length")]
+    focal_length: f32,
+    /// Lens radius, zero keeps everything in focus
+    #[serde(default)]
+    aperture: f32,
+    /// Distance to the plane in focus, look_at by default
+    focus_distance: Option<f32>,
+    /// Sides of a polygonal aperture, round when not given
+    #[serde(default)]
+    blades: u32
+}
+
+impl CameraDesc {
+    fn focus_distance(&self) -> f32 {
+        self.focus_distance.unwrap_or_else(|| (self.look_at - self.look_from).mag())
+    }
+
+    /// Catch lens settings that would leave the camera without an image
+    fn check(&self) -> Result<(), String> {
+        if self.focal_length <= 0.0 {
+            return Err("focal_length must be greater than zero".to_string());
+        }
+        if self.aperture < 0.0 {
+            return Err("aperture can't be negative".to_string());
+        }
+        if self.focus_distance() <= 0.0 {
+            return Err(match self.focus_distance {
+                Some(_) => "focus_distance must be greater than zero".to_string(),
+                None => "look_from and look_at can't be the same point".to_string()
+            });
+        }
+
+        Ok(())
+    }
+
+    fn build(&self, settings: &RenderSettings) -> Camera {
+        Camera::new(
+            self.look_from,
+            self.look_at,
+            self.up,
+            self.fov,
+            settings.width as f32 / settings.height as f32,
+            self.focal_length
+        ).with_lens(
+            self.aperture,
+            self.focus_distance(),
+            self.blades
+        )
+    }
+}
+
+fn default_up() -> Vec3<f32> {
+    Vec3::new(0.0, 1.0, 0.0)
+}
+
+fn default_down() -> Vec3<f32> {
+    Vec3::new(0.0, -1.0, 0.0)
+}
+
+fn default_x_axis() -> Vec3<f32> {
+    Vec3::new(1.0, 0.0, 0.0)
+}
+
+fn default_focal_length() -> f32 {
+    1.0
+}
+
+/// One step of an object's placement, applied in the order they are listed
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum TransformDesc {
+    Translate(Vec3<f32>),
+    Scale(Vec3<f32>),
+    /// Counter clockwise about axis, in degrees
+    Rotate { axis: Vec3<f32>, angle: f32 },
+    /// Turn +z towards to and move the origin to from
+    LookAt {
+        from: Vec3<f32>,
+        to: Vec3<f32>,
+        #[serde(default = "default_up")]
+        up: Vec3<f32>
+    },
+    /// Row major 4x4 matrix
+    Matrix([[f32; 4]; 4])
+}
+
+fn transform(steps: &[TransformDesc]) -> Result<Transform, String> {
+    steps.iter().try_fold(Transform::identity(), |acc, step| {
+        let t = match step {
+            TransformDesc::Translate(v) => Transform::translate(*v),
+            TransformDesc::Scale(v) => Transform::scale(*v),
+            TransformDesc::Rotate { axis, angle } => Transform::rotate(*axis, angle.to_radians()),
+            TransformDesc::LookAt { from, to, up } => Transform::look_at(*from, *to, *up),
+            TransformDesc::Matrix(m) => Transform::from_matrix(*m)
+                .ok_or_else(|| "transform matrix can't be inverted".to_string())?
+        };
+
+        Ok(acc.then(&t))
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum ObjectDesc {
+    Sphere {
+        center: Vec3<f32>,
+        radius: f32,
+        material: String,
+        #[serde(default)]
+        transform: Vec<TransformDesc>
+    },
+    Triangle {
+        v0: Vec3<f32>,
+        v1: Vec3<f32>,
+        v2: Vec3<f32>,
+        /// Texture coordinates of each vertex
+        #[serde(default)]
+        uvs: Option<[(f32, f32); 3]>,
+        material: String,
+        #[serde(default)]
+        transform: Vec<TransformDesc>
+    },
+    Floor {
+        corner: Vec3<f32>,
+        width: f32,
+        height: f32,
+        material: String,
+        #[serde(default)]
+        transform: Vec<TransformDesc>
+    },
+    /// Infinite plane through point
+    Plane {
+        point: Vec3<f32>,
+        normal: Vec3<f32>,
+        material: String,
+        #[serde(default)]
+        transform: Vec<TransformDesc>
+    },
+    Disc {
+        center: Vec3<f32>,
+        normal: Vec3<f32>,
+        radius: f32,
+        material: String,
+        #[serde(default)]
+        transform: Vec<TransformDesc>
+    },
+    /// Axis aligned box between two opposite corners
+    Box {
+        min: Vec3<f32>,
+        max: Vec3<f32>,
+        material: String,
+        #[serde(default)]
+        transform: Vec<TransformDesc>
+    },
+    /// Box of the given size around center with edges along x_axis and y_axis
+    #[serde(rename = "oriented_box")]
+    OrientedBox {
+        center: Vec3<f32>,
+        size: Vec3<f32>,
+        #[serde(default = "default_x_axis")]
+        x_axis: Vec3<f32>,
+        #[serde(default = "default_up")]
+        y_axis: Vec3<f32>,
+        material: String,
+        #[serde(default)]
+        transform: Vec<TransformDesc>
+    },
+    /// Closed cylinder rising from base along axis
+    Cylinder {
+        base: Vec3<f32>,
+        #[serde(default = "default_up")]
+        axis: Vec3<f32>,
+        radius: f32,
+        height: f32,
+        material: String,
+        #[serde(default)]
+        transform: Vec<TransformDesc>
+    },
+    /// Cone on a disc at base, its tip height along axis
+    Cone {
+        base: Vec3<f32>,
+        #[serde(default = "default_up")]
+        axis: Vec3<f32>,
+        radius: f32,
+        height: f32,
+        material: String,
+        #[serde(default)]
+        transform: Vec<TransformDesc>
+    },
+    /// Ring of radius major around axis with a tube of radius minor
+    Torus {
+        center: Vec3<f32>,
+        #[serde(default = "default_up")]
+        axis: Vec3<f32>,
+        major: f32,
+        minor: f32,
+        material: String,
+        #[serde(default)]
+        transform: Vec<TransformDesc>
+    },
+    /// Triangle mesh loaded from a ply file, relative to the scene file. Every
+    /// placement of the same file shares one copy of the mesh. Smooth averages
+    /// vertex normals for files that don't have their own
+    Ply {
+        path: String,
+        material: String,
+        #[serde(default)]
+        smooth: bool,
+        #[serde(default)]
+        transform: Vec<TransformDesc>
+    },
+    /// Obj file with its own mtl materials, faces without one use material
+    Obj {
+        path: String,
+        material: Option<String>,
+        #[serde(default)]
+        smooth: bool,
+        #[serde(default)]
+        transform: Vec<TransformDesc>
+    }
+}
+
+/// The lights a scene file can list, emitters and the environment are added
+/// by the objects and environment that give off the light
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+enum LightDesc {
+    Point { pos: Vec3<f32>, color: Vec3<f32> },
+    Directional { dir: Vec3<f32>, color: Vec3<f32> },
+    Spot {
+        pos: Vec3<f32>,
+        dir: Vec3<f32>,
+        color: Vec3<f32>,
+        #[serde(default)]
+        inner: f32,
+        outer: f32
+    },
+    /// Profile file relative to the scene file
+    Ies {
+        pos: Vec3<f32>,
+        color: Vec3<f32>,
+        #[serde(default = "default_down")]
+        axis: Vec3<f32>,
+        path: PathBuf
+    },
+    Sphere { center: Vec3<f32>, radius: f32, color: Vec3<f32> },
+    Quad { corner: Vec3<f32>, u: Vec3<f32>, v: Vec3<f32>, color: Vec3<f32> }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    #[serde(default)]
+    render: Option<Spanned<RenderSettings>>,
+    camera: Spanned<CameraDesc>,
+    #[serde(default)]
+    materials: BTreeMap<String, Spanned<Material>>,
+    #[serde(default)]
+    lights: Vec<Spanned<LightDesc>>,
+    #[serde(default)]
+    environment: Option<Spanned<Environment>>,
+    #[serde(default)]
+    objects: Vec<Spanned<ObjectDesc>>
+}
+
+/// One material's faces of a loaded obj file, with the world material they use
+/// or None for faces that take the object's material
+type ObjPart = (Option<usize>, Arc<Geometry>);
+
+fn line_of(src: &str, offset: usize) -> usize {
+    src[..offset.min(src.len())].matches('\n').count() + 1
+}
+
+pub fn load_scene<P: AsRef<Path>>(path: P) -> Result<Scene, SceneError> {
+    let path = path.as_ref();
+    let src = std::fs::read_to_string(path)
+        .map_err(|e| SceneError::Io(path.to_path_buf(), e))?;
+
+    let error = |offset: usize, message: String| SceneError::Parse {
+        path: path.to_path_buf(),
+        line: line_of(&src, offset),
+        message
+    };
+
+    let desc: SceneDesc = toml::from_str(&src)
+        .map_err(|e| error(e.span().map(|s| s.start).unwrap_or(0), e.message().to_string()))?;
+
+    let settings = match desc.render {
+        Some(render) => {
+            render.get_ref().check().map_err(|e| error(render.span().start, e))?;
+            render.into_inner()
+        }
+        None => RenderSettings::default()
+    };
+
+    let view = *desc.camera.get_ref();
+    view.check().map_err(|e| error(desc.camera.span().start, e))?;
+
+    let mut world = World::new();
+    let base = path.parent().unwrap_or(Path::new("."));
+
+    // Images already loaded, shared by every texture using them
+    let mut images: HashMap<PathBuf, Arc<Image>> = HashMap::new();
+    let mut load_image = |p: PathBuf, offset: usize| -> Result<Arc<Image>, SceneError> {
+        if let Some(img) = images.get(&p) {
+            return Ok(Arc::clone(img));
+        }
+
+        let img = Arc::new(image::load(&p).map_err(|e| error(offset, format!("{}: {e}", p.display())))?);
+        images.insert(p, Arc::clone(&img));
+        Ok(img)
+    };
+
+    // Register the materials, objects refer to them by name
+    let mut names = HashMap::new();
+    for (name, m) in desc.materials {
+        let offset = m.span().start;
+        let mut m = m.into_inner();
+
+        if let Some(Texture::Image(t)) = m.texture_mut() {
+            t.image = load_image(base.join(&t.path), offset)?;
+        }
+
+        names.insert(name, world.add_material(m));
+    }
+
+    for l in desc.lights {
+        let offset = l.span().start;
+
+        let l = match l.into_inner() {
+            LightDesc::Point { pos, color } => Light::Point { pos, color },
+            LightDesc::Directional { dir, color } => Light::Directional { dir, color },
+            LightDesc::Spot { pos, dir, color, inner, outer } => Light::Spot { pos, dir, color, inner, outer },
+            LightDesc::Ies { pos, color, axis, path: p } => {
+                let full = base.join(&p);
+                let profile = load_ies(&full).map_err(|e| error(offset, format!("{}: {e}", full.display())))?;
+                Light::Ies { pos, color, axis, path: p, profile }
+            }
+            LightDesc::Sphere { center, radius, color } => Light::Sphere { center, radius, color },
+            LightDesc::Quad { corner, u, v, color } => Light::Quad { corner, u, v, color }
+        };
+
+        world.add_light(l);
+    }
+
+    if let Some(environment) = desc.environment {
+        let offset = environment.span().start;
+        let mut environment = environment.into_inner();
+
+        if let Environment::Map(m) = &mut environment {
+            m.set_image(load_image(base.join(&m.path), offset)?);
+        }
+        world.set_environment(environment);
+    }
+
+    // Meshes already loaded, by path and whether they were smoothed
+    let mut meshes: HashMap<(PathBuf, bool), Arc<Geometry>> = HashMap::new();
+    let mut objs: HashMap<(PathBuf, bool), Vec<ObjPart>> = HashMap::new();
+
+    for obj in desc.objects {
+        let offset = obj.span().start;
+        let start = world.geometry.len();
+
+        let material = |name: &String| names.get(name)
+            .copied()
+            .ok_or_else(|| error(offset, format!("unknown material `{name}`")));
+
+        let steps = match obj.into_inner() {
+            ObjectDesc::Sphere { center, radius, material: m, transform } => {
+                world.add_entity(Geometry::new_sphere(center, radius), material(&m)?);
+                transform
+            }
+            ObjectDesc::Triangle { v0, v1, v2, uvs, material: m, transform } => {
+                let t = Triangle::new(v0, v1, v2);
+                let t = match uvs {
+                    Some(uvs) => t.with_uvs(uvs),
+                    None => t
+                };
+
+                world.add_entity(Geometry::Triangle(t), material(&m)?);
+                transform
+            }
+            ObjectDesc::Floor { corner, width, height, material: m, transform } => {
+                world.add_floor(corner, width, height, material(&m)?);
+                transform
+            }
+            ObjectDesc::Plane { point, normal, material: m, transform } => {
+                world.add_entity(Geometry::Plane(Plane::new(point, normal)), material(&m)?);
+                transform
+            }
+            ObjectDesc::Disc { center, normal, radius, material: m, transform } => {
+                world.add_entity(Geometry::Disc(Disc::new(center, normal, radius)), material(&m)?);
+                transform
+            }
+            ObjectDesc::Box { min, max, material: m, transform } => {
+                world.add_entity(Geometry::Cuboid(Cuboid::new(min, max)), material(&m)?);
+                transform
+            }
+            ObjectDesc::OrientedBox { center, size, x_axis, y_axis, material: m, transform } => {
+                world.add_entity(Geometry::OrientedBox(OrientedBox::new(center, size, x_axis, y_axis)), material(&m)?);
+                transform
+            }
+            ObjectDesc::Cylinder { base, axis, radius, height, material: m, transform } => {
+                world.add_entity(Geometry::Cylinder(Cylinder::new(base, axis, radius, height)), material(&m)?);
+                transform
+            }
+            ObjectDesc::Cone { base, axis, radius, height, material: m, transform } => {
+                world.add_entity(Geometry::Cone(Cone::new(base, axis, radius, height)), material(&m)?);
+                transform
+            }
+            ObjectDesc::Torus { center, axis, major, minor, material: m, transform } => {
+                world.add_entity(Geometry::Torus(Torus::new(center, axis, major, minor)), material(&m)?);
+                transform
+            }
+            ObjectDesc::Ply { path: p, material: m, smooth, transform: steps } => {
+                let m = material(&m)?;
+                let p = base.join(p);
+
+                let mesh = match meshes.get(&(p.clone(), smooth)) {
+                    Some(mesh) => Arc::clone(mesh),
+                    None => {
+                        let mesh = load_ply(&p.to_string_lossy())
+                            .map_err(|e| error(offset, format!("{}: {e}", p.display())))?
+                            .mesh();
+
+                        let mesh = if smooth && !mesh.has_normals() { mesh.smooth() } else { mesh };
+                        let mesh = Arc::new(Geometry::Mesh(mesh));
+                        meshes.insert((p, smooth), Arc::clone(&mesh));
+                        mesh
+                    }
+                };
+
+                // Placed at intersection time so the mesh isn't copied
+                let t = transform(&steps).map_err(|e| error(offset, e))?;
+                world.add_entity(Geometry::Instance(Instance::new(mesh, t)), m);
+                Vec::new()
+            }
+            ObjectDesc::Obj { path: p, material: m, smooth, transform: steps } => {
+                let m = match m {
+                    Some(m) => material(&m)?,
+                    None => world.add_material(Material::Phong(Phong::new(
+                        Texture::Solid(Vec3::new(0.8, 0.8, 0.8)), 1.0, 0.0, 1.0, 0.0, 0.0, 1.0
+                    )))
+                };
+                let p = base.join(p);
+
+                let parts = match objs.get(&(p.clone(), smooth)) {
+                    Some(parts) => parts.clone(),
+                    None => {
+                        let file = load_obj(&p).map_err(|e| error(offset, e.to_string()))?;
+                        let ids: Vec<usize> = file.materials.iter()
+                            .map(|(_, m)| world.add_material(m.clone()))
+                            .collect();
+
+                        let parts: Vec<ObjPart> = file.meshes(smooth)
+                            .into_iter()
+                            .map(|(m, mesh)| (m.map(|m| ids[m]), Arc::new(Geometry::Mesh(mesh))))
+                            .collect();
+                        objs.insert((p, smooth), parts.clone());
+                        parts
+                    }
+                };
+
+                // Faces without a usemtl get the object's material
+                let t = transform(&steps).map_err(|e| error(offset, e))?;
+                for (id, mesh) in parts {
+                    world.add_entity(Geometry::Instance(Instance::new(mesh, t)), id.unwrap_or(m));
+                }
+                Vec::new()
+            }
+        };
+
+        // Everything else is moved into place as it is loaded
+        if !steps.is_empty() {
+            let t = transform(&steps).map_err(|e| error(offset, e))?;
+            let placed = world.geometry.split_off(start);
+            world.geometry.extend(placed.into_iter().map(|g| g.transformed(&t)));
+        }
+    }
+
+    world.build_accel(settings.accel);
+    world.build_light_sampler(settings.light_sampling);
+
+    Ok(Scene {
+        world,
+        camera: view.build(&settings),
+        settings,
+        view
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a scene file to the temp directory, returning its path
+    fn write_scene(name: &str, src: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("gi_tracer_scene_{}_{name}.toml", std::process::id()));
+        std::fs::write(&path, src).unwrap();
+        path
+    }
+
+    const HEADER: &str = r#"
+[render]
+width = 40
+height = 20
+samples = 3
+
+[camera]
+look_from = [0.0, 0.0, 5.0]
+look_at = [0.0, 0.0, 0.0]
+fov = 45.0
+
+[materials.red.Phong]
+texture = { Solid = [1.0, 0.0, 0.0] }
+kd = 1.0
+ks = 0.0
+ke = 1.0
+kr = 0.0
+kt = 0.0
+eta = 1.0
+"#;
+
+    fn line_error(name: &str, src: &str) -> (usize, String) {
+        match load_scene(write_scene(name, src)) {
+            Err(SceneError::Parse { line, message, .. }) => (line, message),
+            Err(e) => panic!("unexpected error {e}"),
+            Ok(_) => panic!("scene loaded")
+        }
+    }
+
+    #[test]
+    fn loads_settings_materials_and_objects() {
+        let src = format!("{HEADER}{}", r#"
+[[lights]]
+Point = { pos = [0.0, 5.0, 0.0], color = [1.0, 1.0, 1.0] }
+
+[[objects]]
+type = "sphere"
+center = [0.0, 0.0, 0.0]
+radius = 1.0
+material = "red"
+
+[[objects]]
+type = "floor"
+corner = [-1.0, -1.0, -1.0]
+width = 2.0
+height = 2.0
+material = "red"
+"#);
+        let scene = load_scene(write_scene("ok", &src)).unwrap();
+
+        assert_eq!((scene.settings.width, scene.settings.height, scene.settings.samples), (40, 20, 3));
+        assert_eq!(scene.world.materials.len(), 1);
+        assert_eq!(scene.world.lights.len(), 1);
+        // The floor is two triangles
+        assert_eq!(scene.world.geometry.len(), 3);
+        assert!(scene.world.accel.is_some());
+    }
+
+    #[test]
+    fn unknown_materials_report_their_object() {
+        let src = format!("{HEADER}{}", r#"
+[[objects]]
+type = "sphere"
+center = [0.0, 0.0, 0.0]
+radius = 1.0
+material = "blue"
+"#);
+        let (line, message) = line_error("material", &src);
+
+        assert_eq!(line, 21);
+        assert_eq!(message, "unknown material `blue`");
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected_at_their_object() {
+        let src = format!("{HEADER}{}", r#"
+[[objects]]
+type = "sphere"
+center = [0.0, 0.0, 0.0]
+radius = 1.0
+colour = "red"
+"#);
+        let (line, message) = line_error("field", &src);
+
+        assert_eq!(line, 21);
+        assert!(message.contains("colour"), "{message}");
+    }
+
+    #[test]
+    fn empty_images_report_the_render_settings() {
+        for (setting, zero, expected) in [
+            ("width = 40", "width = 0", "width and height must be at least one pixel"),
+            ("height = 20", "height = 0", "width and height must be at least one pixel"),
+            ("samples = 3", "samples = 0", "samples must be at least one")
+        ] {
+            let src = HEADER.replace(setting, zero);
+            assert_eq!(line_error("render", &src), (2, expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn only_user_lights_can_be_listed() {
+        for light in ["Emitter = 0", "Environment = {}"] {
+            let src = format!("{HEADER}\n[[lights]]\n{light}\n");
+            let (line, message) = line_error("light", &src);
+
+            assert_eq!(line, 22, "{message}");
+            assert!(message.contains("unknown variant"), "{message}");
+        }
+    }
+
+    #[test]
+    fn missing_images_report_their_material() {
+        let src = format!("{HEADER}{}", r#"
+[materials.picture.Phong]
+texture = { Image = { path = "does_not_exist.png" } }
+kd = 1.0
+ks = 0.0
+ke = 1.0
+kr = 0.0
+kt = 0.0
+eta = 1.0
+"#);
+        let (line, message) = line_error("image", &src);
+
+        assert_eq!(line, 21);
+        assert!(message.contains("does_not_exist.png"), "{message}");
+    }
+
+    #[test]
+    fn bad_lens_settings_report_the_camera() {
+        for (setting, expected) in [
+            ("focal_length = 0.0", "focal_length must be greater than zero"),
+            ("aperture = -0.1", "aperture can't be negative"),
+            ("focus_distance = -2.0", "focus_distance must be greater than zero")
+        ] {
+            let src = HEADER.replace("fov = 45.0", &format!("fov = 45.0\n{setting}"));
+            assert_eq!(line_error("lens", &src), (7, expected.to_string()));
+        }
+
+        let src = HEADER.replace("look_at = [0.0, 0.0, 0.0]", "look_at = [0.0, 0.0, 5.0]");
+        assert_eq!(line_error("lens", &src), (7, "look_from and look_at can't be the same point".to_string()));
+    }
+
+    #[test]
+    fn example_scene_loads() {
+        let scene = load_scene("scenes/spheres.toml").unwrap();
+        assert!(!scene.world.geometry.is_empty());
+    }
+}
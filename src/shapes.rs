@@ -0,0 +1,906 @@
+use crate::geometry::{Object, Ray, AABB, Axis, MIN_DIST};
+use crate::vector::Vec3;
+use crate::sampling;
+
+use serde::{Serialize, Deserialize};
+
+use std::f32::consts::PI;
+
+/// Keep the nearer of two candidate distances, ignoring ones behind the ray
+fn nearest(best: Option<f32>, t: f32) -> Option<f32> {
+    if t > MIN_DIST && best.is_none_or(|b| t < b) {
+        Some(t)
+    } else {
+        best
+    }
+}
+
+/// Both roots of a t^2 + b t + c, smallest first
+fn quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    let disc = b*b - 4.0*a*c;
+    if disc < 0.0 || a == 0.0 {
+        return None;
+    }
+
+    // Avoid cancellation by never subtracting numbers of similar size
+    let q = -0.5 * (b + disc.sqrt().copysign(b));
+    let (t1, t2) = (q / a, if q != 0.0 { c / q } else { q / a });
+
+    Some(if t1 < t2 { (t1, t2) } else { (t2, t1) })
+}
+
+/// Largest real root of x^3 + a x^2 + b x + c
+fn cubic_root(a: f64, b: f64, c: f64) -> f64 {
+    let q = (a*a - 3.0*b) / 9.0;
+    let r = (2.0*a*a*a - 9.0*a*b + 27.0*c) / 54.0;
+
+    if r*r < q*q*q {
+        let theta = (r / (q*q*q).sqrt()).acos();
+        let s = -2.0 * q.sqrt();
+
+        [0.0, 2.0, -2.0].iter()
+            .map(|k| s * ((theta + k * std::f64::consts::PI) / 3.0).cos() - a / 3.0)
+            .fold(f64::NEG_INFINITY, f64::max)
+    } else {
+        let big = -(r.abs() + (r*r - q*q*q).sqrt()).cbrt().copysign(r);
+        let small = if big != 0.0 { q / big } else { 0.0 };
+
+        big + small - a / 3.0
+    }
+}
+
+/// Real roots of x^4 + a x^3 + b x^2 + c x + d by Ferrari's method
+fn quartic_roots(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    // Substitute x = y - a/4 to get y^4 + p y^2 + q y + r
+    let a2 = a * a;
+    let p = b - 3.0*a2 / 8.0;
+    let q = c - a*b / 2.0 + a2*a / 8.0;
+    let r = d - a*c / 4.0 + a2*b / 16.0 - 3.0*a2*a2 / 256.0;
+
+    let mut ys = Vec::with_capacity(4);
+    let mut quad = |b: f64, c: f64| {
+        let disc = b*b - 4.0*c;
+        if disc >= 0.0 {
+            let s = disc.sqrt();
+            ys.push((-b - s) / 2.0);
+            ys.push((-b + s) / 2.0);
+        }
+    };
+
+    if q.abs() < 1e-12 {
+        // Quadratic in y^2
+        let disc = p*p - 4.0*r;
+        if disc >= 0.0 {
+            for z in [(-p - disc.sqrt()) / 2.0, (-p + disc.sqrt()) / 2.0] {
+                if z >= 0.0 {
+                    quad(0.0, -z);
+                }
+            }
+        }
+    } else {
+        // Factor into two quadratics through the resolvent cubic
+        let m = cubic_root(p, p*p / 4.0 - r, -q*q / 8.0);
+        if m <= 0.0 {
+            return Vec::new();
+        }
+
+        let s = (2.0 * m).sqrt();
+        quad(s, p / 2.0 + m - q / (2.0 * s));
+        quad(-s, p / 2.0 + m + q / (2.0 * s));
+    }
+
+    // Polish each root against the original polynomial
+    ys.iter().map(|y| {
+        let mut x = y - a / 4.0;
+        for _ in 0..2 {
+            let f = (((x + a) * x + b) * x + c) * x + d;
+            let df = ((4.0 * x + 3.0 * a) * x + 2.0 * b) * x + c;
+            if df != 0.0 {
+                x -= f / df;
+            }
+        }
+        x
+    }).collect()
+}
+
+/// Bounds of a disc, how far it reaches along each axis depends on how much it faces it
+fn disc_bounds(center: Vec3<f32>, normal: &Vec3<f32>, radius: f32) -> AABB {
+    let e = Vec3::new(
+        radius * (1.0 - normal.x*normal.x).max(0.0).sqrt(),
+        radius * (1.0 - normal.y*normal.y).max(0.0).sqrt(),
+        radius * (1.0 - normal.z*normal.z).max(0.0).sqrt()
+    );
+
+    AABB { min: center - e, max: center + e }
+}
+
+fn point_bounds(p: Vec3<f32>) -> AABB {
+    AABB { min: p, max: p }
+}
+
+/// An angle as a fraction of a full turn in [0, 1)
+fn turns(angle: f32) -> f32 {
+    angle.rem_euclid(2.0 * PI) / (2.0 * PI)
+}
+
+/// Map a local point on a disc of radius r facing z into the unit square
+fn disc_uv(p: &Vec3<f32>, r: f32) -> (f32, f32) {
+    ((p.x / r + 1.0) / 2.0, (p.y / r + 1.0) / 2.0)
+}
+
+/// Orthonormal axes placed at an origin, shapes are intersected in these local coordinates
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+struct Frame {
+    origin: Vec3<f32>,
+    x: Vec3<f32>,
+    y: Vec3<f32>,
+    z: Vec3<f32>
+}
+
+impl Frame {
+    /// A frame with z along axis
+    fn new(origin: Vec3<f32>, axis: Vec3<f32>) -> Self {
+        let z = axis.normalized();
+        let (x, y) = sampling::basis(&z);
+
+        Self { origin, x, y, z }
+    }
+
+    fn point(&self, p: &Vec3<f32>) -> Vec3<f32> {
+        self.vector(&(*p - self.origin))
+    }
+
+    fn vector(&self, v: &Vec3<f32>) -> Vec3<f32> {
+        Vec3::new(v.dot(&self.x), v.dot(&self.y), v.dot(&self.z))
+    }
+
+    fn world(&self, v: &Vec3<f32>) -> Vec3<f32> {
+        self.x * v.x + self.y * v.y + self.z * v.z
+    }
+
+    /// The ray in local coordinates, distances along it are unchanged
+    fn ray(&self, ray: &Ray) -> Ray {
+        Ray { origin: self.point(&ray.origin), dir: self.vector(&ray.dir), inside: ray.inside }
+    }
+}
+
+/// An unbounded plane, kept out of the acceleration structures
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Plane {
+    pub point: Vec3<f32>,
+    pub normal: Vec3<f32>
+}
+
+impl Plane {
+    pub fn new(point: Vec3<f32>, normal: Vec3<f32>) -> Self {
+        Self { point, normal: normal.normalized() }
+    }
+
+    pub fn fit(&self) -> AABB {
+        AABB {
+            min: Vec3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+            max: Vec3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY)
+        }
+    }
+}
+
+impl Object for Plane {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        let denom = self.normal.dot(&ray.dir);
+        if denom == 0.0 {
+            return None;
+        }
+
+        nearest(None, (self.point - ray.origin).dot(&self.normal) / denom)
+    }
+
+    fn normal(&self, _point: Vec3<f32>) -> Vec3<f32> {
+        self.normal
+    }
+
+    /// Distances from point along two directions in the plane, unbounded
+    fn uv(&self, point: Vec3<f32>) -> ((f32, f32), Vec3<f32>) {
+        let (x, y) = sampling::basis(&self.normal);
+        let d = point - self.point;
+
+        ((d.dot(&x), d.dot(&y)), x)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Disc {
+    frame: Frame,
+    radius: f32
+}
+
+impl Disc {
+    pub fn new(center: Vec3<f32>, normal: Vec3<f32>, radius: f32) -> Self {
+        Self { frame: Frame::new(center, normal), radius }
+    }
+
+    pub fn area(&self) -> f32 {
+        PI * self.radius * self.radius
+    }
+
+    pub fn fit(&self) -> AABB {
+        disc_bounds(self.frame.origin, &self.frame.z, self.radius)
+    }
+
+    pub fn sample_area(&self, u: f32, v: f32) -> (Vec3<f32>, Vec3<f32>, f32) {
+        let (x, y) = sampling::uniform_disc(u, v);
+        let point = self.frame.origin + self.frame.world(&Vec3::new(x, y, 0.0)) * self.radius;
+
+        (point, self.frame.z, 1.0 / self.area())
+    }
+}
+
+impl Object for Disc {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        let r = self.frame.ray(ray);
+        if r.dir.z == 0.0 {
+            return None;
+        }
+
+        let t = -r.origin.z / r.dir.z;
+        let p = r.origin + r.dir * t;
+        if p.x*p.x + p.y*p.y > self.radius*self.radius {
+            return None;
+        }
+
+        nearest(None, t)
+    }
+
+    fn normal(&self, _point: Vec3<f32>) -> Vec3<f32> {
+        self.frame.z
+    }
+
+    fn uv(&self, point: Vec3<f32>) -> ((f32, f32), Vec3<f32>) {
+        (disc_uv(&self.frame.point(&point), self.radius), self.frame.x)
+    }
+}
+
+/// Pick a face of a box centered on the origin by area, then a point on it
+fn sample_box(half: &Vec3<f32>, u: f32, v: f32) -> (Vec3<f32>, Vec3<f32>, f32) {
+    let size = *half * 2.0;
+    let areas = [size.y * size.z, size.z * size.x, size.x * size.y];
+    let total = 2.0 * (areas[0] + areas[1] + areas[2]);
+
+    // Each axis has two faces, walk along them until u falls inside one
+    let mut target = u * total;
+    let mut face = 0;
+    while face < 5 && target >= areas[face / 2] {
+        target -= areas[face / 2];
+        face += 1;
+    }
+
+    let axis = Axis::ALL[face / 2];
+    let side = if face % 2 == 0 { -1.0 } else { 1.0 };
+    let s = (target / areas[face / 2]).clamp(0.0, 1.0) * 2.0 - 1.0;
+    let t = v * 2.0 - 1.0;
+
+    let (point, normal) = match axis {
+        Axis::X => (Vec3::new(side * half.x, s * half.y, t * half.z), Vec3::new(side, 0.0, 0.0)),
+        Axis::Y => (Vec3::new(t * half.x, side * half.y, s * half.z), Vec3::new(0.0, side, 0.0)),
+        Axis::Z => (Vec3::new(s * half.x, t * half.y, side * half.z), Vec3::new(0.0, 0.0, side))
+    };
+
+    (point, normal, 1.0 / total)
+}
+
+/// The axis and side of the face of a box centered on the origin closest to p
+fn box_face(half: &Vec3<f32>, p: &Vec3<f32>) -> (Axis, f32) {
+    let axis = Axis::ALL.into_iter()
+        .max_by(|&a, &b| (a.of(p) / a.of(half)).abs().total_cmp(&(b.of(p) / b.of(half)).abs()))
+        .unwrap_or(Axis::X);
+
+    (axis, 1.0f32.copysign(axis.of(p)))
+}
+
+fn box_normal(half: &Vec3<f32>, p: &Vec3<f32>) -> Vec3<f32> {
+    match box_face(half, p) {
+        (Axis::X, side) => Vec3::new(side, 0.0, 0.0),
+        (Axis::Y, side) => Vec3::new(0.0, side, 0.0),
+        (Axis::Z, side) => Vec3::new(0.0, 0.0, side)
+    }
+}
+
+/// Each face of a box centered on the origin maps onto the unit square
+fn box_uv(half: &Vec3<f32>, p: &Vec3<f32>) -> ((f32, f32), Vec3<f32>) {
+    let (axis, _) = box_face(half, p);
+    let (u, v) = match axis {
+        Axis::X => (Axis::Z, Axis::Y),
+        Axis::Y => (Axis::X, Axis::Z),
+        Axis::Z => (Axis::X, Axis::Y)
+    };
+
+    let along = |a: Axis| (a.of(p) / a.of(half) + 1.0) / 2.0;
+    let dpdu = match u {
+        Axis::X => Vec3::new(1.0, 0.0, 0.0),
+        Axis::Y => Vec3::new(0.0, 1.0, 0.0),
+        Axis::Z => Vec3::new(0.0, 0.0, 1.0)
+    };
+
+    ((along(u), along(v)), dpdu)
+}
+
+/// Where a ray enters or otherwise leaves a box
+fn box_hit(aabb: &AABB, ray: &Ray) -> Option<f32> {
+    let (tmin, tmax) = aabb.range(ray)?;
+    nearest(nearest(None, tmax), tmin)
+}
+
+/// An axis aligned box
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Cuboid {
+    pub min: Vec3<f32>,
+    pub max: Vec3<f32>
+}
+
+impl Cuboid {
+    pub fn new(a: Vec3<f32>, b: Vec3<f32>) -> Self {
+        let aabb = point_bounds(a).union(point_bounds(b));
+        Self { min: aabb.min, max: aabb.max }
+    }
+
+    fn half(&self) -> Vec3<f32> {
+        (self.max - self.min) * 0.5
+    }
+
+    pub fn area(&self) -> f32 {
+        self.fit().surface_area()
+    }
+
+    pub fn fit(&self) -> AABB {
+        AABB { min: self.min, max: self.max }
+    }
+
+    pub fn sample_area(&self, u: f32, v: f32) -> (Vec3<f32>, Vec3<f32>, f32) {
+        let (p, n, pdf) = sample_box(&self.half(), u, v);
+        (p + (self.min + self.max) * 0.5, n, pdf)
+    }
+}
+
+impl Object for Cuboid {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        box_hit(&self.fit(), ray)
+    }
+
+    fn normal(&self, point: Vec3<f32>) -> Vec3<f32> {
+        box_normal(&self.half(), &(point - (self.min + self.max) * 0.5))
+    }
+
+    fn uv(&self, point: Vec3<f32>) -> ((f32, f32), Vec3<f32>) {
+        box_uv(&self.half(), &(point - (self.min + self.max) * 0.5))
+    }
+}
+
+/// A box rotated to lie along any set of perpendicular axes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrientedBox {
+    frame: Frame,
+    half: Vec3<f32>
+}
+
+impl OrientedBox {
+    /// A box of the given size around center, with its first edge along x
+    /// and its second along the part of y perpendicular to x
+    pub fn new(center: Vec3<f32>, size: Vec3<f32>, x: Vec3<f32>, y: Vec3<f32>) -> Self {
+        let x = x.normalized();
+        let y = (y - x * x.dot(&y)).normalized();
+        let z = x.cross(&y);
+
+        Self { frame: Frame { origin: center, x, y, z }, half: size * 0.5 }
+    }
+
+    pub fn area(&self) -> f32 {
+        self.local().surface_area()
+    }
+
+    fn local(&self) -> AABB {
+        AABB { min: -self.half, max: self.half }
+    }
+
+    pub fn fit(&self) -> AABB {
+        let Frame { origin, x, y, z } = self.frame;
+        let h = self.half;
+        let e = Vec3::new(
+            x.x.abs()*h.x + y.x.abs()*h.y + z.x.abs()*h.z,
+            x.y.abs()*h.x + y.y.abs()*h.y + z.y.abs()*h.z,
+            x.z.abs()*h.x + y.z.abs()*h.y + z.z.abs()*h.z
+        );
+
+        AABB { min: origin - e, max: origin + e }
+    }
+
+    pub fn sample_area(&self, u: f32, v: f32) -> (Vec3<f32>, Vec3<f32>, f32) {
+        let (p, n, pdf) = sample_box(&self.half, u, v);
+        (self.frame.origin + self.frame.world(&p), self.frame.world(&n), pdf)
+    }
+}
+
+impl Object for OrientedBox {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        box_hit(&self.local(), &self.frame.ray(ray))
+    }
+
+    fn normal(&self, point: Vec3<f32>) -> Vec3<f32> {
+        self.frame.world(&box_normal(&self.half, &self.frame.point(&point)))
+    }
+
+    fn uv(&self, point: Vec3<f32>) -> ((f32, f32), Vec3<f32>) {
+        let (uv, dpdu) = box_uv(&self.half, &self.frame.point(&point));
+        (uv, self.frame.world(&dpdu))
+    }
+}
+
+/// A cylinder standing on base along axis, closed at both ends
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Cylinder {
+    frame: Frame,
+    radius: f32,
+    height: f32
+}
+
+impl Cylinder {
+    pub fn new(base: Vec3<f32>, axis: Vec3<f32>, radius: f32, height: f32) -> Self {
+        Self { frame: Frame::new(base, axis), radius, height }
+    }
+
+    fn side_area(&self) -> f32 {
+        2.0 * PI * self.radius * self.height
+    }
+
+    /// Whether a local point is closer to one of the ends than to the side
+    fn on_cap(&self, p: &Vec3<f32>) -> bool {
+        let rho = (p.x*p.x + p.y*p.y).sqrt();
+        let to_cap = p.z.abs().min((self.height - p.z).abs());
+
+        to_cap < (rho - self.radius).abs() || rho == 0.0
+    }
+
+    pub fn area(&self) -> f32 {
+        self.side_area() + 2.0 * PI * self.radius * self.radius
+    }
+
+    pub fn fit(&self) -> AABB {
+        let Frame { origin, z, .. } = self.frame;
+        disc_bounds(origin, &z, self.radius).union(disc_bounds(origin + z * self.height, &z, self.radius))
+    }
+
+    pub fn sample_area(&self, u: f32, v: f32) -> (Vec3<f32>, Vec3<f32>, f32) {
+        let side = self.side_area() / self.area();
+        let cap = (1.0 - side) / 2.0;
+
+        let (local, normal) = if u < side {
+            let phi = 2.0 * PI * v;
+            let (x, y) = (phi.cos(), phi.sin());
+            (Vec3::new(x * self.radius, y * self.radius, u / side * self.height), Vec3::new(x, y, 0.0))
+        } else {
+            let top = u >= side + cap;
+            let u = ((u - side - if top { cap } else { 0.0 }) / cap).clamp(0.0, 1.0);
+            let (x, y) = sampling::uniform_disc(u, v);
+            let (z, n) = if top { (self.height, 1.0) } else { (0.0, -1.0) };
+            (Vec3::new(x * self.radius, y * self.radius, z), Vec3::new(0.0, 0.0, n))
+        };
+
+        (self.frame.origin + self.frame.world(&local), self.frame.world(&normal), 1.0 / self.area())
+    }
+}
+
+impl Object for Cylinder {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        let Ray { origin: o, dir: d, .. } = self.frame.ray(ray);
+        let r2 = self.radius * self.radius;
+        let mut best = None;
+
+        // The open tube, between the two ends
+        if let Some((t1, t2)) = quadratic(d.x*d.x + d.y*d.y, 2.0 * (o.x*d.x + o.y*d.y), o.x*o.x + o.y*o.y - r2) {
+            for t in [t1, t2] {
+                let z = o.z + d.z * t;
+                if (0.0..=self.height).contains(&z) {
+                    best = nearest(best, t);
+                }
+            }
+        }
+
+        // The end caps
+        if d.z != 0.0 {
+            for z in [0.0, self.height] {
+                let t = (z - o.z) / d.z;
+                let p = o + d * t;
+                if p.x*p.x + p.y*p.y <= r2 {
+                    best = nearest(best, t);
+                }
+            }
+        }
+
+        best
+    }
+
+    fn normal(&self, point: Vec3<f32>) -> Vec3<f32> {
+        let p = self.frame.point(&point);
+        let rho = (p.x*p.x + p.y*p.y).sqrt();
+
+        let n = if self.on_cap(&p) {
+            Vec3::new(0.0, 0.0, if p.z * 2.0 < self.height { -1.0 } else { 1.0 })
+        } else {
+            Vec3::new(p.x / rho, p.y / rho, 0.0)
+        };
+
+        self.frame.world(&n)
+    }
+
+    /// Around the side and up the height, the caps are mapped like discs
+    fn uv(&self, point: Vec3<f32>) -> ((f32, f32), Vec3<f32>) {
+        let p = self.frame.point(&point);
+
+        if self.on_cap(&p) {
+            (disc_uv(&p, self.radius), self.frame.x)
+        } else {
+            ((turns(p.y.atan2(p.x)), p.z / self.height), self.frame.world(&Vec3::new(-p.y, p.x, 0.0)))
+        }
+    }
+}
+
+/// A cone standing on a disc at base, closed at the bottom, its tip at base + axis * height
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Cone {
+    frame: Frame,
+    radius: f32,
+    height: f32
+}
+
+impl Cone {
+    pub fn new(base: Vec3<f32>, axis: Vec3<f32>, radius: f32, height: f32) -> Self {
+        Self { frame: Frame::new(base, axis), radius, height }
+    }
+
+    fn side_area(&self) -> f32 {
+        PI * self.radius * (self.radius*self.radius + self.height*self.height).sqrt()
+    }
+
+    /// Whether a local point is closer to the base than to the side
+    fn on_base(&self, p: &Vec3<f32>) -> bool {
+        let rho = (p.x*p.x + p.y*p.y).sqrt();
+        let slant = (self.radius*self.radius + self.height*self.height).sqrt();
+        let to_side = (rho - self.radius * (self.height - p.z) / self.height).abs() * self.height / slant;
+
+        p.z.abs() < to_side
+    }
+
+    pub fn area(&self) -> f32 {
+        self.side_area() + PI * self.radius * self.radius
+    }
+
+    pub fn fit(&self) -> AABB {
+        let Frame { origin, z, .. } = self.frame;
+        disc_bounds(origin, &z, self.radius).union(point_bounds(origin + z * self.height))
+    }
+
+    pub fn sample_area(&self, u: f32, v: f32) -> (Vec3<f32>, Vec3<f32>, f32) {
+        let side = self.side_area() / self.area();
+
+        let (local, normal) = if u < side {
+            // Distance from the tip grows with the square root to stay uniform by area
+            let s = (u / side).sqrt();
+            let phi = 2.0 * PI * v;
+            let (x, y) = (phi.cos(), phi.sin());
+            let n = Vec3::new(x * self.height, y * self.height, self.radius).normalized();
+
+            (Vec3::new(x * self.radius * s, y * self.radius * s, self.height * (1.0 - s)), n)
+        } else {
+            let (x, y) = sampling::uniform_disc(((u - side) / (1.0 - side)).clamp(0.0, 1.0), v);
+            (Vec3::new(x * self.radius, y * self.radius, 0.0), Vec3::new(0.0, 0.0, -1.0))
+        };
+
+        (self.frame.origin + self.frame.world(&local), self.frame.world(&normal), 1.0 / self.area())
+    }
+}
+
+impl Object for Cone {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        let Ray { origin: o, dir: d, .. } = self.frame.ray(ray);
+        let k = self.radius / self.height;
+        let k2 = k * k;
+        let h = self.height - o.z;
+        let mut best = None;
+
+        // x^2 + y^2 = (k (height - z))^2 between the base and the tip
+        let a = d.x*d.x + d.y*d.y - k2*d.z*d.z;
+        let b = 2.0 * (o.x*d.x + o.y*d.y + k2*h*d.z);
+        let c = o.x*o.x + o.y*o.y - k2*h*h;
+
+        let roots = if a.abs() > 1e-9 {
+            quadratic(a, b, c).map(|(t1, t2)| vec![t1, t2])
+        } else if b != 0.0 {
+            // Parallel to the slope, the line crosses the cone once
+            Some(vec![-c / b])
+        } else {
+            None
+        };
+
+        for t in roots.unwrap_or_default() {
+            let z = o.z + d.z * t;
+            if (0.0..=self.height).contains(&z) {
+                best = nearest(best, t);
+            }
+        }
+
+        if d.z != 0.0 {
+            let t = -o.z / d.z;
+            let p = o + d * t;
+            if p.x*p.x + p.y*p.y <= self.radius*self.radius {
+                best = nearest(best, t);
+            }
+        }
+
+        best
+    }
+
+    fn normal(&self, point: Vec3<f32>) -> Vec3<f32> {
+        let p = self.frame.point(&point);
+        let rho = (p.x*p.x + p.y*p.y).sqrt();
+
+        let n = if self.on_base(&p) {
+            Vec3::new(0.0, 0.0, -1.0)
+        } else if rho == 0.0 {
+            Vec3::new(0.0, 0.0, 1.0)
+        } else {
+            Vec3::new(p.x / rho * self.height, p.y / rho * self.height, self.radius).normalized()
+        };
+
+        self.frame.world(&n)
+    }
+
+    /// Around the side and up to the tip, the base is mapped like a disc
+    fn uv(&self, point: Vec3<f32>) -> ((f32, f32), Vec3<f32>) {
+        let p = self.frame.point(&point);
+
+        if self.on_base(&p) {
+            (disc_uv(&p, self.radius), self.frame.x)
+        } else {
+            ((turns(p.y.atan2(p.x)), p.z / self.height), self.frame.world(&Vec3::new(-p.y, p.x, 0.0)))
+        }
+    }
+}
+
+/// A ring of radius major around center in the plane facing axis, with a tube of radius minor
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Torus {
+    frame: Frame,
+    major: f32,
+    minor: f32
+}
+
+impl Torus {
+    pub fn new(center: Vec3<f32>, axis: Vec3<f32>, major: f32, minor: f32) -> Self {
+        Self { frame: Frame::new(center, axis), major, minor }
+    }
+
+    pub fn area(&self) -> f32 {
+        4.0 * PI * PI * self.major * self.minor
+    }
+
+    pub fn fit(&self) -> AABB {
+        let ring = disc_bounds(self.frame.origin, &self.frame.z, self.major);
+        let r = Vec3::new(self.minor, self.minor, self.minor);
+
+        AABB { min: ring.min - r, max: ring.max + r }
+    }
+
+    pub fn sample_area(&self, u: f32, v: f32) -> (Vec3<f32>, Vec3<f32>, f32) {
+        let (big, small) = (self.major, self.minor);
+
+        // The outside of the tube has more area than the inside, invert
+        // (R theta + r sin theta) / 2 pi R = u with a few newton steps
+        let target = 2.0 * PI * big * u;
+        let mut theta = 2.0 * PI * u;
+        for _ in 0..8 {
+            let f = big * theta + small * theta.sin() - target;
+            theta = (theta - f / (big + small * theta.cos())).clamp(0.0, 2.0 * PI);
+        }
+
+        let phi = 2.0 * PI * v;
+        let (st, ct) = theta.sin_cos();
+        let (sp, cp) = phi.sin_cos();
+
+        let normal = Vec3::new(ct * cp, ct * sp, st);
+        let local = Vec3::new(big * cp, big * sp, 0.0) + normal * small;
+
+        (self.frame.origin + self.frame.world(&local), self.frame.world(&normal), 1.0 / self.area())
+    }
+}
+
+impl Object for Torus {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        let r = self.frame.ray(ray);
+
+        // Start from where the ray enters the bounding sphere to keep the quartic well conditioned
+        let bound = self.major + self.minor;
+        let b = r.origin.dot(&r.dir);
+        let c = r.origin.dot(&r.origin) - bound*bound;
+        if b*b - c < 0.0 {
+            return None;
+        }
+        let start = (-b - (b*b - c).sqrt()).max(0.0);
+
+        let o = r.origin + r.dir * start;
+        let (ox, oy, oz) = (o.x as f64, o.y as f64, o.z as f64);
+        let (dx, dy, dz) = (r.dir.x as f64, r.dir.y as f64, r.dir.z as f64);
+        let (big, small) = ((self.major * self.major) as f64, (self.minor * self.minor) as f64);
+
+        // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + y^2) along a unit direction
+        let beta = ox*dx + oy*dy + oz*dz;
+        let gamma = ox*ox + oy*oy + oz*oz + big - small;
+
+        quartic_roots(
+            4.0 * beta,
+            4.0 * beta*beta + 2.0 * gamma - 4.0 * big * (dx*dx + dy*dy),
+            4.0 * beta * gamma - 8.0 * big * (ox*dx + oy*dy),
+            gamma*gamma - 4.0 * big * (ox*ox + oy*oy)
+        ).into_iter().fold(None, |best, t| nearest(best, t as f32 + start))
+    }
+
+    fn normal(&self, point: Vec3<f32>) -> Vec3<f32> {
+        let p = self.frame.point(&point);
+        let rho = (p.x*p.x + p.y*p.y).sqrt();
+
+        // Away from the nearest point on the ring at the middle of the tube
+        let ring = if rho > 0.0 {
+            Vec3::new(p.x / rho, p.y / rho, 0.0) * self.major
+        } else {
+            Vec3::new(self.major, 0.0, 0.0)
+        };
+
+        self.frame.world(&(p - ring).normalized())
+    }
+
+    /// Around the ring and then around the tube
+    fn uv(&self, point: Vec3<f32>) -> ((f32, f32), Vec3<f32>) {
+        let p = self.frame.point(&point);
+        let rho = (p.x*p.x + p.y*p.y).sqrt();
+
+        let u = turns(p.y.atan2(p.x));
+        let v = turns(p.z.atan2(rho - self.major));
+
+        ((u, v), self.frame.world(&Vec3::new(-p.y, p.x, 0.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::grid;
+
+    /// Check that sampled points lie on the shape with the normal it reports there,
+    /// by firing a ray back at each one from just outside
+    fn check_samples<S: Object>(shape: &S, area: f32, sample: impl Fn(f32, f32) -> (Vec3<f32>, Vec3<f32>, f32)) {
+        for (u, v) in grid(32) {
+            let (p, n, pdf) = sample(u, v);
+            assert!((pdf * area - 1.0).abs() < 1e-5);
+
+            let ray = Ray::new(p + n * 0.01, -n);
+            let t = shape.intersect(&ray).unwrap();
+            assert!((t - 0.01).abs() < 1e-3, "{t} at {u} {v}");
+
+            assert!((shape.normal(p) - n).mag() < 1e-3, "at {u} {v}");
+
+            let ((su, sv), _) = shape.uv(p);
+            assert!((0.0..=1.0).contains(&su) && (0.0..=1.0).contains(&sv));
+        }
+    }
+
+    #[test]
+    fn quartic_finds_every_real_root() {
+        let sorted = |mut r: Vec<f64>| {
+            r.sort_by(f64::total_cmp);
+            r
+        };
+
+        // (x - 1)(x - 2)(x - 3)(x - 4)
+        let roots = sorted(quartic_roots(-10.0, 35.0, -50.0, 24.0));
+        assert_eq!(roots.len(), 4);
+        for (r, e) in roots.iter().zip([1.0, 2.0, 3.0, 4.0]) {
+            assert!((r - e).abs() < 1e-9, "{roots:?}");
+        }
+
+        // (x^2 - 1)(x^2 - 4) has no odd terms
+        let roots = sorted(quartic_roots(0.0, -5.0, 0.0, 4.0));
+        assert_eq!(roots.len(), 4);
+        for (r, e) in roots.iter().zip([-2.0, -1.0, 1.0, 2.0]) {
+            assert!((r - e).abs() < 1e-9, "{roots:?}");
+        }
+
+        // (x^2 + 1)(x - 1)(x - 5) has only two
+        let roots = sorted(quartic_roots(-6.0, 6.0, -6.0, 5.0));
+        assert_eq!(roots.len(), 2);
+        assert!((roots[0] - 1.0).abs() < 1e-9 && (roots[1] - 5.0).abs() < 1e-9, "{roots:?}");
+
+        assert!(quartic_roots(0.0, 0.0, 0.0, 1.0).is_empty());
+    }
+
+    #[test]
+    fn quadratic_keeps_small_roots_accurate() {
+        let (t1, t2) = quadratic(1.0, -1e4, 1.0).unwrap();
+        assert!((t1 - 1e-4).abs() < 1e-9 && (t2 - 1e4).abs() < 1e-1);
+
+        assert!(quadratic(1.0, 0.0, 1.0).is_none());
+        assert!(quadratic(0.0, 1.0, 1.0).is_none());
+    }
+
+    #[test]
+    fn torus_hits_the_nearest_side_of_the_tube() {
+        let torus = Torus::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0), 2.0, 0.5);
+
+        // Across the ring through the hole, from outside and from inside the tube
+        let across = Ray::new(Vec3::new(-10.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!((torus.intersect(&across).unwrap() - 7.5).abs() < 1e-4);
+
+        let inside = Ray::new(Vec3::new(-2.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!((torus.intersect(&inside).unwrap() - 0.5).abs() < 1e-4);
+
+        let from_center = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert!((torus.intersect(&from_center).unwrap() - 1.5).abs() < 1e-4);
+
+        // Down through the hole and just past the outside
+        assert!(torus.intersect(&Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0))).is_none());
+        assert!(torus.intersect(&Ray::new(Vec3::new(-10.0, 0.0, 0.51), Vec3::new(1.0, 0.0, 0.0))).is_none());
+
+        // Grazing the top of the tube
+        let top = Ray::new(Vec3::new(-10.0, 0.0, 0.499), Vec3::new(1.0, 0.0, 0.0));
+        assert!((torus.intersect(&top).unwrap() - 8.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn samples_lie_on_the_surface() {
+        let axis = Vec3::new(1.0, 2.0, -0.5);
+        let center = Vec3::new(0.5, -1.0, 2.0);
+
+        let disc = Disc::new(center, axis, 1.5);
+        check_samples(&disc, disc.area(), |u, v| disc.sample_area(u, v));
+
+        let cuboid = Cuboid::new(center, Vec3::new(-1.0, 2.0, 0.0));
+        check_samples(&cuboid, cuboid.area(), |u, v| cuboid.sample_area(u, v));
+
+        let oriented = OrientedBox::new(center, Vec3::new(1.0, 2.0, 3.0), axis, Vec3::new(0.0, 1.0, 1.0));
+        check_samples(&oriented, oriented.area(), |u, v| oriented.sample_area(u, v));
+
+        let cylinder = Cylinder::new(center, axis, 0.7, 2.0);
+        check_samples(&cylinder, cylinder.area(), |u, v| cylinder.sample_area(u, v));
+
+        let cone = Cone::new(center, axis, 1.0, 1.5);
+        check_samples(&cone, cone.area(), |u, v| cone.sample_area(u, v));
+
+        let torus = Torus::new(center, axis, 1.5, 0.4);
+        check_samples(&torus, torus.area(), |u, v| torus.sample_area(u, v));
+    }
+
+    #[test]
+    fn flat_shapes_face_their_normal() {
+        let plane = Plane::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 2.0, 0.0));
+        assert_eq!(plane.intersect(&Ray::new(Vec3::new(3.0, 4.0, 0.0), Vec3::new(0.0, -1.0, 0.0))), Some(3.0));
+        assert!(plane.intersect(&Ray::new(Vec3::new(3.0, 4.0, 0.0), Vec3::new(1.0, 0.0, 0.0))).is_none());
+        assert!(plane.intersect(&Ray::new(Vec3::new(3.0, 4.0, 0.0), Vec3::new(0.0, 1.0, 0.0))).is_none());
+
+        let disc = Disc::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0), 1.0);
+        assert!(disc.intersect(&Ray::new(Vec3::new(0.9, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0))).is_some());
+        assert!(disc.intersect(&Ray::new(Vec3::new(1.1, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0))).is_none());
+    }
+
+    #[test]
+    fn box_normals_point_out_of_the_face_hit() {
+        let cuboid = Cuboid::new(Vec3::new(-1.0, -2.0, -3.0), Vec3::new(1.0, 2.0, 3.0));
+        assert!((cuboid.area() - 88.0).abs() < 1e-4);
+
+        for dir in [
+            Vec3::new(1.0, 0.0, 0.0), Vec3::new(-1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0)
+        ] {
+            let ray = Ray::new(dir * 10.0 + Vec3::new(0.1, 0.2, 0.3), -dir);
+            let t = cuboid.intersect(&ray).unwrap();
+
+            assert!((cuboid.normal(ray.origin + ray.dir * t) - dir).mag() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use crate::vector::Vec3;
+use crate::sampling;
+
+use serde::{Serialize, Deserialize};
+
+use std::f32::consts::PI;
+
+/// Illuminance of the sun above the atmosphere, in kilolux
+const SUN_ILLUMINANCE: f32 = 127.5;
+
+fn default_turbidity() -> f32 {
+    3.0
+}
+
+fn default_sun_size() -> f32 {
+    0.53
+}
+
+fn default_intensity() -> f32 {
+    0.03
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+/// Preetham's analytic daylight sky with the sun as a small bright disc.
+/// Radiance is in kilocandelas per square meter times intensity.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sky {
+    /// Direction towards the sun, +y is straight up
+    pub sun: Vec3<f32>,
+    /// Haziness of the air, 2 is very clear and 10 is hazy
+    #[serde(default = "default_turbidity")]
+    pub turbidity: f32,
+    /// Angular diameter of the sun in degrees
+    #[serde(default = "default_sun_size")]
+    pub sun_size: f32,
+    /// Scale from physical units to scene radiance
+    #[serde(default = "default_intensity")]
+    pub intensity: f32,
+    /// Extra multiplier on the sun alone
+    #[serde(default = "one")]
+    pub sun_intensity: f32
+}
+
+/// Perez's luminance distribution, theta from the zenith and gamma from the sun
+fn perez([a, b, c, d, e]: [f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+/// Linear srgb of a color given as luminance and chromaticity
+fn yxy_to_rgb(lum: f32, x: f32, y: f32) -> Vec3<f32> {
+    if y <= 0.0 {
+        return Vec3::new(0.0, 0.0, 0.0);
+    }
+
+    let cx = x / y * lum;
+    let cz = (1.0 - x - y) / y * lum;
+
+    Vec3::new(
+        (3.2406 * cx - 1.5372 * lum - 0.4986 * cz).max(0.0),
+        (-0.9689 * cx + 1.8758 * lum + 0.0415 * cz).max(0.0),
+        (0.0557 * cx - 0.2040 * lum + 1.0570 * cz).max(0.0)
+    )
+}
+
+impl Sky {
+    pub fn new(sun: Vec3<f32>, turbidity: f32) -> Self {
+        Self {
+            sun,
+            turbidity,
+            sun_size: default_sun_size(),
+            intensity: default_intensity(),
+            sun_intensity: 1.0
+        }
+    }
+
+    fn sun_dir(&self) -> Vec3<f32> {
+        self.sun.normalized()
+    }
+
+    /// One minus the cosine of the sun's angular radius, worked out without
+    /// the cancellation that would lose most of it for such a small angle
+    fn sun_cone(&self) -> f32 {
+        2.0 * (self.sun_size.to_radians() / 4.0).sin().powi(2)
+    }
+
+    /// Solid angle covered by the sun
+    fn sun_solid_angle(&self) -> f32 {
+        2.0 * PI * self.sun_cone()
+    }
+
+    /// Radiance of the sky alone, the horizon is held below it
+    pub fn sky_radiance(&self, dir: &Vec3<f32>) -> Vec3<f32> {
+        let t = self.turbidity;
+        let sun = self.sun_dir();
+
+        // Past the horizon the model falls apart, so the sun stops at it
+        let theta_s = sun.y.clamp(0.0, 1.0).acos().min(PI / 2.0 - 0.001);
+        let cos_theta = dir.y.max(0.01);
+        let gamma = dir.dot(&sun).clamp(-1.0, 1.0).acos();
+
+        let coeffs_y = [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703];
+        let coeffs_x = [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452];
+        let coeffs_cy = [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529];
+
+        // Values straight up, the rest of the sky is relative to them
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+        let (t1, t2, t3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zenith_x = t * t * (0.00166 * t3 - 0.00375 * t2 + 0.00209 * t1)
+            + t * (-0.02903 * t3 + 0.06377 * t2 - 0.03202 * t1 + 0.00394)
+            + (0.11693 * t3 - 0.21196 * t2 + 0.06052 * t1 + 0.25886);
+        let zenith_cy = t * t * (0.00275 * t3 - 0.00610 * t2 + 0.00317 * t1)
+            + t * (-0.04214 * t3 + 0.08970 * t2 - 0.04153 * t1 + 0.00516)
+            + (0.15346 * t3 - 0.26756 * t2 + 0.06670 * t1 + 0.26688);
+
+        let relative = |coeffs| perez(coeffs, cos_theta, gamma) / perez(coeffs, 1.0, theta_s);
+
+        yxy_to_rgb(
+            zenith_y.max(0.0) * relative(coeffs_y),
+            zenith_x * relative(coeffs_x),
+            zenith_cy * relative(coeffs_cy)
+        ) * self.intensity
+    }
+
+    /// Irradiance from the whole sun disc on a surface facing it, after the
+    /// atmosphere has scattered and absorbed some of it on the way down
+    pub fn sun_irradiance(&self) -> Vec3<f32> {
+        let sun = self.sun_dir();
+        if sun.y <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+
+        // Air mass the light passes through relative to straight down
+        let theta = sun.y.acos().to_degrees();
+        let mass = 1.0 / (sun.y + 0.15 * (93.885 - theta).powf(-1.253));
+
+        // Rayleigh scattering and Angstrom's aerosol turbidity at red, green and blue wavelengths in micrometers
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let transmittance = |lambda: f32| {
+            (-0.008735 * lambda.powf(-4.08) * mass).exp() * (-beta * lambda.powf(-1.3) * mass).exp()
+        };
+
+        Vec3::new(transmittance(0.680), transmittance(0.550), transmittance(0.440))
+            * (SUN_ILLUMINANCE * self.intensity * self.sun_intensity)
+    }
+
+    /// Radiance of the sun disc, spread evenly over its solid angle
+    fn sun_radiance(&self) -> Vec3<f32> {
+        self.sun_irradiance() / self.sun_solid_angle()
+    }
+
+    /// Radiance from direction dir, sun included
+    pub fn radiance(&self, dir: &Vec3<f32>) -> Vec3<f32> {
+        let sky = self.sky_radiance(dir);
+
+        if 1.0 - dir.dot(&self.sun_dir()) <= self.sun_cone() {
+            sky + self.sun_radiance()
+        } else {
+            sky
+        }
+    }
+
+    /// Pick a direction towards the sun disc, returning it with its radiance and solid angle density
+    pub fn sample(&self, u: f32, v: f32) -> Option<(Vec3<f32>, Vec3<f32>, f32)> {
+        if self.sun_dir().y <= 0.0 {
+            return None;
+        }
+
+        let cos_theta = 1.0 - u * self.sun_cone();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * v;
+
+        let local = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        let dir = sampling::to_world(&local, &self.sun_dir());
+
+        Some((dir, self.radiance(&dir), 1.0 / self.sun_solid_angle()))
+    }
+
+    /// Density with which sample picks dir
+    pub fn pdf(&self, dir: &Vec3<f32>) -> f32 {
+        let sun = self.sun_dir();
+
+        if sun.y <= 0.0 || 1.0 - dir.dot(&sun) > self.sun_cone() {
+            0.0
+        } else {
+            1.0 / self.sun_solid_angle()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::grid;
+
+    #[test]
+    fn sun_samples_stay_in_its_disc() {
+        let sky = Sky::new(Vec3::new(1.0, 2.0, -0.5), 3.0);
+        let n = 32;
+        let mut sun = Vec3::new(0.0, 0.0, 0.0);
+
+        for (u, v) in grid(n) {
+            let (dir, radiance, pdf) = sky.sample(u, v).unwrap();
+
+            assert!((dir.mag() - 1.0).abs() < 1e-5);
+            assert_eq!(pdf, sky.pdf(&dir));
+            assert!(pdf > 0.0);
+
+            sun += (radiance - sky.sky_radiance(&dir)) / pdf;
+        }
+
+        // Adding up the disc's radiance over its solid angle gives back the irradiance
+        let irradiance = sky.sun_irradiance();
+        assert!((sun / (n * n) as f32 - irradiance).mag() < 1e-3 * irradiance.mag());
+    }
+
+    #[test]
+    fn directions_away_from_the_sun_are_never_sampled() {
+        let sky = Sky::new(Vec3::new(0.0, 1.0, 1.0), 3.0);
+
+        assert_eq!(sky.pdf(&Vec3::new(0.0, 1.0, 0.0)), 0.0);
+        assert!(sky.pdf(&sky.sun_dir()) > 0.0);
+    }
+
+    #[test]
+    fn set_suns_light_nothing() {
+        let sky = Sky::new(Vec3::new(1.0, -0.2, 0.0), 3.0);
+
+        assert!(sky.sample(0.5, 0.5).is_none());
+        assert_eq!(sky.pdf(&sky.sun_dir()), 0.0);
+        assert_eq!(sky.sun_irradiance().mag(), 0.0);
+    }
+
+    #[test]
+    fn low_suns_are_dimmer_and_redder() {
+        let high = Sky::new(Vec3::new(0.0, 1.0, 0.2), 3.0).sun_irradiance();
+        let low = Sky::new(Vec3::new(0.0, 0.1, 1.0), 3.0).sun_irradiance();
+
+        assert!(low.y < high.y);
+        assert!(low.z / low.x < high.z / high.x);
+    }
+
+    #[test]
+    fn sky_is_brightest_around_the_sun() {
+        let sky = Sky::new(Vec3::new(1.0, 1.0, 0.0), 3.0);
+        let near = sky.sky_radiance(&Vec3::new(1.0, 0.9, 0.1).normalized());
+        let away = sky.sky_radiance(&Vec3::new(-1.0, 0.9, 0.1).normalized());
+
+        assert!(near.luminance() > away.luminance());
+
+        // Finite and positive everywhere, even below the horizon
+        for (u, v) in grid(16) {
+            let z = 1.0 - 2.0 * u;
+            let r = (1.0 - z * z).sqrt();
+            let dir = Vec3::new(r * (2.0 * PI * v).cos(), z, r * (2.0 * PI * v).sin());
+            let l = sky.radiance(&dir).luminance();
+
+            assert!(l.is_finite() && l >= 0.0);
+        }
+    }
+}
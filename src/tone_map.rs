@@ -1,16 +1,19 @@
 use crate::vector::Vec3;
 
+use serde::{Serialize, Deserialize};
+
 const LMAX: f32 = 100.0;
 const LDMAX: f32 = 500.0; // nits
 const WARD_DELTA: f32 = 0.001;
 
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum Algorithm {
     Ward,
     Reinhard,
     ALM(f32)    // bias
 }
 
-pub fn tone_map(img: &mut Vec<Vec3<f32>>, algo: Algorithm) {
+pub fn tone_map(img: &mut [Vec3<f32>], algo: Algorithm) {
     // convert [0..1] pixel values to luminances by multiplying by ldmax
     img.iter_mut()
         .for_each(|v| {
@@ -18,7 +21,7 @@ pub fn tone_map(img: &mut Vec<Vec3<f32>>, algo: Algorithm) {
         });
 
     // Calculate absolute luminances
-    let luminances = calc_luminance(&img);
+    let luminances = calc_luminance(img);
 
     // Run compression algorithm
     match algo {
@@ -28,13 +31,13 @@ pub fn tone_map(img: &mut Vec<Vec3<f32>>, algo: Algorithm) {
     }
 }
 
-fn calc_luminance(img: &Vec<Vec3<f32>>) -> Vec<f32> {
+fn calc_luminance(img: &[Vec3<f32>]) -> Vec<f32> {
     img.iter()
         .map(|v| 0.27*v.x+0.67*v.y+0.06*v.z)
         .collect()
 }
 
-fn log_avg_luminance(lum: &Vec<f32>) -> f32 {
+fn log_avg_luminance(lum: &[f32]) -> f32 {
     let mut log_avg = lum.iter()
         .map(|l| l+WARD_DELTA)
         .map(|l| l.ln())
@@ -46,7 +49,7 @@ fn log_avg_luminance(lum: &Vec<f32>) -> f32 {
     log_avg
 }
 
-fn ward(img: &mut Vec<Vec3<f32>>, lum: Vec<f32>) {
+fn ward(img: &mut [Vec3<f32>], lum: Vec<f32>) {
     let la = log_avg_luminance(&lum);
 
     let mut sf = 1.219 + (LDMAX/2.0).powf(0.4);
@@ -58,7 +61,7 @@ fn ward(img: &mut Vec<Vec3<f32>>, lum: Vec<f32>) {
         .for_each(|v| *v = *v * sf);
 }
 
-fn reinhard(img: &mut Vec<Vec3<f32>>, lum: Vec<f32>) {
+fn reinhard(img: &mut [Vec3<f32>], lum: Vec<f32>) {
     let la = log_avg_luminance(&lum);
     let alpha = 0.18;
 
@@ -79,7 +82,7 @@ fn bias(b: f32, t: f32) -> f32 {
     t.powf(b.ln() / 0.5_f32.ln())
 }
 
-fn adaptive_logarithmic_mapping(img: &mut Vec<Vec3<f32>>, lum: Vec<f32>, b: f32) {
+fn adaptive_logarithmic_mapping(img: &mut [Vec3<f32>], lum: Vec<f32>, b: f32) {
     // l_wmax is the max luminance value in the scene
     // l_w is the luminance of the pixel
     // l_w and l_wmax are booth divided by l_wa for adaptive scaling
@@ -87,7 +90,7 @@ fn adaptive_logarithmic_mapping(img: &mut Vec<Vec3<f32>>, lum: Vec<f32>, b: f32)
     // l_d is the output luminance for a single pixel
 
     let l_wa = log_avg_luminance(&lum);
-    ///let l_wmax = lum.iter().max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap() / l_wa;
+    //let l_wmax = lum.iter().max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap() / l_wa;
     let l_wmax = LMAX;
 
     let luminance = |l_w: f32| {
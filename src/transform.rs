@@ -0,0 +1,309 @@
+use crate::vector::Vec3;
+
+use serde::{Serialize, Deserialize};
+
+type Matrix = [[f32; 4]; 4];
+
+const IDENTITY: Matrix = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0]
+];
+
+fn transpose(m: &Matrix) -> Matrix {
+    let mut t = [[0.0; 4]; 4];
+    for (i, row) in t.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = m[j][i];
+        }
+    }
+    t
+}
+
+/// Gauss-Jordan elimination with partial pivoting, None if the matrix is singular
+fn invert(m: &Matrix) -> Option<Matrix> {
+    let mut a = *m;
+    let mut inv = IDENTITY;
+
+    for col in 0..4 {
+        let pivot = (col..4).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+
+        a.swap(col, pivot);
+        inv.swap(col, pivot);
+
+        let p = a[col][col];
+        for j in 0..4 {
+            a[col][j] /= p;
+            inv[col][j] /= p;
+        }
+
+        for row in 0..4 {
+            if row != col {
+                let f = a[row][col];
+                for j in 0..4 {
+                    a[row][j] -= f * a[col][j];
+                    inv[row][j] -= f * inv[col][j];
+                }
+            }
+        }
+    }
+
+    Some(inv)
+}
+
+fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m = [[0.0; 4]; 4];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..4).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+/// Affine transform kept as a 4x4 matrix along with its inverse
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Transform {
+    m: Matrix,
+    inv: Matrix
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Self { m: IDENTITY, inv: IDENTITY }
+    }
+
+    /// A transform from a row major matrix, None if it can't be inverted
+    pub fn from_matrix(m: [[f32; 4]; 4]) -> Option<Self> {
+        Some(Self { m, inv: invert(&m)? })
+    }
+
+    pub fn translate(v: Vec3<f32>) -> Self {
+        let mut m = IDENTITY;
+        let mut inv = IDENTITY;
+        m[0][3] = v.x;
+        m[1][3] = v.y;
+        m[2][3] = v.z;
+        inv[0][3] = -v.x;
+        inv[1][3] = -v.y;
+        inv[2][3] = -v.z;
+
+        Self { m, inv }
+    }
+
+    pub fn scale(v: Vec3<f32>) -> Self {
+        let mut m = IDENTITY;
+        let mut inv = IDENTITY;
+        m[0][0] = v.x;
+        m[1][1] = v.y;
+        m[2][2] = v.z;
+        inv[0][0] = 1.0 / v.x;
+        inv[1][1] = 1.0 / v.y;
+        inv[2][2] = 1.0 / v.z;
+
+        Self { m, inv }
+    }
+
+    /// Rotate counter clockwise about axis by angle radians
+    pub fn rotate(axis: Vec3<f32>, angle: f32) -> Self {
+        let a = axis.normalized();
+        let (s, c) = angle.sin_cos();
+        let t = 1.0 - c;
+
+        let mut m = IDENTITY;
+        m[0][0] = t*a.x*a.x + c;
+        m[0][1] = t*a.x*a.y - s*a.z;
+        m[0][2] = t*a.x*a.z + s*a.y;
+        m[1][0] = t*a.x*a.y + s*a.z;
+        m[1][1] = t*a.y*a.y + c;
+        m[1][2] = t*a.y*a.z - s*a.x;
+        m[2][0] = t*a.x*a.z - s*a.y;
+        m[2][1] = t*a.y*a.z + s*a.x;
+        m[2][2] = t*a.z*a.z + c;
+
+        // Rotations are orthogonal so the inverse is the transpose
+        Self { m, inv: transpose(&m) }
+    }
+
+    /// Place the origin at from with +z pointing towards to and +y as close to up as possible
+    pub fn look_at(from: Vec3<f32>, to: Vec3<f32>, up: Vec3<f32>) -> Self {
+        let z = (to - from).normalized();
+        let x = up.cross(&z).normalized();
+        let y = z.cross(&x);
+
+        let mut m = IDENTITY;
+        for (i, axis) in [x, y, z].iter().enumerate() {
+            m[0][i] = axis.x;
+            m[1][i] = axis.y;
+            m[2][i] = axis.z;
+        }
+
+        Self { m, inv: transpose(&m) }.then(&Self::translate(from))
+    }
+
+    /// This transform followed by other
+    pub fn then(&self, other: &Self) -> Self {
+        Self {
+            m: mul(&other.m, &self.m),
+            inv: mul(&self.inv, &other.inv)
+        }
+    }
+
+    pub fn inverse(&self) -> Self {
+        Self { m: self.inv, inv: self.m }
+    }
+
+    pub fn point(&self, p: &Vec3<f32>) -> Vec3<f32> {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0]*p.x + m[0][1]*p.y + m[0][2]*p.z + m[0][3],
+            m[1][0]*p.x + m[1][1]*p.y + m[1][2]*p.z + m[1][3],
+            m[2][0]*p.x + m[2][1]*p.y + m[2][2]*p.z + m[2][3]
+        )
+    }
+
+    /// Transform a direction, ignoring the translation
+    pub fn vector(&self, v: &Vec3<f32>) -> Vec3<f32> {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0]*v.x + m[0][1]*v.y + m[0][2]*v.z,
+            m[1][0]*v.x + m[1][1]*v.y + m[1][2]*v.z,
+            m[2][0]*v.x + m[2][1]*v.y + m[2][2]*v.z
+        )
+    }
+
+    /// Transform a surface normal by the inverse transpose, the result is not normalized
+    pub fn normal(&self, n: &Vec3<f32>) -> Vec3<f32> {
+        let i = &self.inv;
+        Vec3::new(
+            i[0][0]*n.x + i[1][0]*n.y + i[2][0]*n.z,
+            i[0][1]*n.x + i[1][1]*n.y + i[2][1]*n.z,
+            i[0][2]*n.x + i[1][2]*n.y + i[2][2]*n.z
+        )
+    }
+
+    fn det(&self) -> f32 {
+        let m = &self.m;
+        m[0][0] * (m[1][1]*m[2][2] - m[1][2]*m[2][1])
+            - m[0][1] * (m[1][0]*m[2][2] - m[1][2]*m[2][0])
+            + m[0][2] * (m[1][0]*m[2][1] - m[1][1]*m[2][0])
+    }
+
+    /// How much a small patch of area grows when transformed, given its transformed unit normal
+    pub fn area_scale(&self, n: &Vec3<f32>) -> f32 {
+        let m = &self.m;
+        let t = Vec3::new(
+            m[0][0]*n.x + m[1][0]*n.y + m[2][0]*n.z,
+            m[0][1]*n.x + m[1][1]*n.y + m[2][1]*n.z,
+            m[0][2]*n.x + m[1][2]*n.y + m[2][2]*n.z
+        );
+
+        self.det().abs() / t.mag()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f32::consts::PI;
+
+    fn close(a: Vec3<f32>, b: Vec3<f32>) -> bool {
+        (a - b).mag() < 1e-4
+    }
+
+    /// A shear that none of the constructors make
+    fn sheared() -> Transform {
+        Transform::from_matrix([
+            [1.0, 0.5, 0.0, 1.0],
+            [0.0, 2.0, 0.3, -2.0],
+            [0.2, 0.0, 1.0, 0.5],
+            [0.0, 0.0, 0.0, 1.0]
+        ]).unwrap()
+    }
+
+    fn transforms() -> [Transform; 4] {
+        [
+            Transform::translate(Vec3::new(1.0, -2.0, 3.0)),
+            Transform::rotate(Vec3::new(1.0, 2.0, 3.0), 1.1)
+                .then(&Transform::scale(Vec3::new(2.0, 0.5, -3.0)))
+                .then(&Transform::translate(Vec3::new(-1.0, 0.0, 4.0))),
+            Transform::look_at(Vec3::new(1.0, 2.0, 3.0), Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)),
+            sheared()
+        ]
+    }
+
+    #[test]
+    fn inverses_undo_the_transform() {
+        let p = Vec3::new(0.3, -1.7, 2.2);
+
+        for t in transforms() {
+            assert!(close(t.inverse().point(&t.point(&p)), p));
+            assert!(close(t.point(&t.inverse().point(&p)), p));
+            assert!(close(t.then(&t.inverse()).vector(&p), p));
+        }
+    }
+
+    #[test]
+    fn then_applies_in_order() {
+        let t = Transform::translate(Vec3::new(1.0, 0.0, 0.0)).then(&Transform::scale(Vec3::new(2.0, 2.0, 2.0)));
+        assert!(close(t.point(&Vec3::new(0.0, 0.0, 0.0)), Vec3::new(2.0, 0.0, 0.0)));
+
+        // Directions ignore the translation
+        assert!(close(t.vector(&Vec3::new(0.0, 1.0, 0.0)), Vec3::new(0.0, 2.0, 0.0)));
+    }
+
+    #[test]
+    fn rotations_turn_counter_clockwise() {
+        let t = Transform::rotate(Vec3::new(0.0, 0.0, 1.0), PI / 2.0);
+        assert!(close(t.point(&Vec3::new(1.0, 0.0, 0.0)), Vec3::new(0.0, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn look_at_points_z_at_the_target() {
+        let (from, to) = (Vec3::new(1.0, 2.0, 3.0), Vec3::new(-1.0, 0.0, 2.0));
+        let t = Transform::look_at(from, to, Vec3::new(0.0, 1.0, 0.0));
+
+        assert!(close(t.point(&Vec3::new(0.0, 0.0, 0.0)), from));
+        assert!(close(t.vector(&Vec3::new(0.0, 0.0, 1.0)), (to - from).normalized()));
+        assert!(t.vector(&Vec3::new(0.0, 1.0, 0.0)).y > 0.0);
+    }
+
+    #[test]
+    fn singular_matrices_are_rejected() {
+        let mut flat = IDENTITY;
+        flat[2][2] = 0.0;
+        assert!(Transform::from_matrix(flat).is_none());
+
+        let mut repeated = IDENTITY;
+        repeated[1] = repeated[0];
+        assert!(Transform::from_matrix(repeated).is_none());
+    }
+
+    #[test]
+    fn normals_stay_perpendicular_and_areas_scale() {
+        let (a, b) = (Vec3::new(1.0, 0.2, -0.5), Vec3::new(-0.3, 1.0, 0.4));
+        let n = a.cross(&b);
+
+        for t in transforms() {
+            let (ta, tb) = (t.vector(&a), t.vector(&b));
+            let tn = t.normal(&n);
+
+            assert!(tn.dot(&ta).abs() < 1e-4 && tn.dot(&tb).abs() < 1e-4);
+
+            // The patch spanned by a and b grows by the ratio of the cross products
+            let scale = ta.cross(&tb).mag() / n.mag();
+            assert!((t.area_scale(&tn.normalized()) - scale).abs() < 1e-4 * scale);
+        }
+    }
+}
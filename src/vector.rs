@@ -4,15 +4,29 @@ use num::Float;
 use num::Num;
 use num::Signed;
 
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone)]
 pub struct Vec3<T> {
     pub x: T,
     pub y: T,
     pub z: T
 }
 
+// Vectors are written as [x, y, z] so they stay short in scene files
+impl<T: Serialize> Serialize for Vec3<T> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        (&self.x, &self.y, &self.z).serialize(s)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Vec3<T> {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let (x, y, z) = <(T, T, T)>::deserialize(d)?;
+        Ok(Self { x, y, z })
+    }
+}
+
 impl<T> Vec3<T> {
     pub fn new(x: T, y: T, z: T) -> Self {
         Self { x, y, z }
@@ -51,6 +65,18 @@ impl Vec3<f32> {
     pub fn reflect(&self, n: &Self) -> Self {
         *self - *n * self.dot(n) * 2.0
     }
+
+    pub fn luminance(&self) -> f32 {
+        0.27*self.x + 0.67*self.y + 0.06*self.z
+    }
+
+    pub fn max_component(&self) -> f32 {
+        self.x.max(self.y).max(self.z)
+    }
+
+    pub fn is_black(&self) -> bool {
+        self.x <= 0.0 && self.y <= 0.0 && self.z <= 0.0
+    }
 }
 
 impl<T: Num> Add for Vec3<T> {
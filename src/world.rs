@@ -1,10 +1,16 @@
-use crate::geometry::{Object, Geometry, Ray};
+use crate::geometry::{Geometry, Ray, Intersection, HitRecord, Triangle, AABB};
 use crate::vector::Vec3;
-use crate::material::{Material, Light, Color};
-use crate::kdtree::KDNode;
+use crate::material::Material;
+use crate::light::Light;
+use crate::light_sampler::{LightSampler, LightSampling};
+use crate::environment::Environment;
+use crate::accel::{Accel, AccelMethod};
+use crate::bsdf::Bsdf;
 
 use serde::{Serialize, Deserialize};
 
+use rand::Rng;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct World {
     // Component Vectors
@@ -14,9 +20,19 @@ pub struct World {
     // Global resources
     pub lights: Vec<Light>,
     pub materials: Vec<Material>,
+    pub environment: Environment,
 
     // Indexes
-    pub kdtree: Option<KDNode>
+    pub accel: Option<Accel>,
+    /// Geometry left out of the acceleration structure because it has no bounds
+    pub unbounded: Vec<usize>,
+    pub light_sampler: LightSampler
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl World {
@@ -27,31 +43,40 @@ impl World {
 
             lights: Vec::new(),
             materials: Vec::new(),
-            kdtree: None
+            environment: Environment::default(),
+            accel: None,
+            unbounded: Vec::new(),
+            light_sampler: LightSampler::default()
         }
     }
 
     pub fn add_entity(&mut self, geometry: Geometry, material: usize) {
+        // Emissive geometry also has to be sampled as a light
+        if matches!(self.materials.get(material), Some(Material::Emissive(_))) {
+            self.lights.push(Light::Emitter(self.geometry.len()));
+        }
+
         self.geometry.push(geometry);
         self.material.push(material);
     }
 
+    /// A rectangle on the x/z plane made of two triangles, u runs along x and v along z
     pub fn add_floor(&mut self, corner: Vec3<f32>, width: f32, height: f32, material: usize) {
         self.add_entity(
-            Geometry::new_triangle(
+            Geometry::Triangle(Triangle::new(
                 corner,
                 Vec3::new(corner.x, corner.y, corner.z+height),
                 Vec3::new(corner.x+width, corner.y, corner.z),
-            ),
+            ).with_uvs([(0.0, 0.0), (0.0, 1.0), (1.0, 0.0)])),
             material
         );
 
         self.add_entity(
-            Geometry::new_triangle(
+            Geometry::Triangle(Triangle::new(
                 Vec3::new(corner.x+width, corner.y, corner.z+height),
                 Vec3::new(corner.x+width, corner.y, corner.z),
                 Vec3::new(corner.x, corner.y, corner.z+height),
-            ),
+            ).with_uvs([(1.0, 1.0), (1.0, 0.0), (0.0, 1.0)])),
             material
         );
     }
@@ -60,6 +85,17 @@ impl World {
         self.lights.push(light)
     }
 
+    /// Replace what rays that escape the scene see, environments that light
+    /// the scene are sampled along with the other lights
+    pub fn set_environment(&mut self, environment: Environment) {
+        self.lights.retain(|l| !matches!(l, Light::Environment));
+        if environment.is_light() {
+            self.lights.push(Light::Environment);
+        }
+
+        self.environment = environment;
+    }
+
     pub fn add_material(&mut self, material: Material) -> usize {
         let id = self.materials.len();
 
@@ -68,45 +104,160 @@ impl World {
         id
     }
 
-    pub fn intersect(&self, ray: &Ray) -> Option<(usize, f32)> {
-        if let Some(kdtree) = self.kdtree.as_ref() {
-            kdtree.intersect(ray, &self.geometry)
+    /// Build the acceleration structure, must be called again after adding geometry
+    pub fn build_accel(&mut self, method: AccelMethod) {
+        self.accel = Accel::build(&self.geometry, method);
+        self.unbounded = (0..self.geometry.len()).filter(|&i| !self.geometry[i].is_bounded()).collect();
+    }
+
+    /// Build what picks lights for direct lighting, must be called again after adding lights
+    pub fn build_light_sampler(&mut self, method: LightSampling) {
+        self.light_sampler = LightSampler::build(self, method);
+    }
+
+    /// Lights to sample for direct lighting at p, each with the probability it was picked with
+    pub fn sample_lights<R: Rng + ?Sized>(&self, p: &Vec3<f32>, rng: &mut R) -> impl Iterator<Item = (&Light, f32)> + '_ {
+        let (all, one) = match self.light_sampler.sample(p, rng) {
+            None => (Some(self.lights.iter().map(|l| (l, 1.0))), None),
+            Some((i, pmf)) => (None, Some((&self.lights[i], pmf)))
+        };
+
+        all.into_iter().flatten().chain(one)
+    }
+
+    /// Probability that sample_lights picks emissive geometry id from p
+    pub fn emitter_pmf(&self, p: &Vec3<f32>, id: usize) -> f32 {
+        self.light_sampler.emitter_pmf(p, id)
+    }
+
+    /// Probability that sample_lights picks the environment from p
+    pub fn environment_pmf(&self, p: &Vec3<f32>) -> f32 {
+        self.light_sampler.environment_pmf(p)
+    }
+
+    /// Box around all the bounded geometry
+    pub fn bounds(&self) -> Option<AABB> {
+        self.geometry.iter()
+            .filter(|g| g.is_bounded())
+            .map(Geometry::fit)
+            .reduce(AABB::union)
+    }
+
+    /// Radius of a sphere around the bounded geometry, 1 for an empty world
+    pub fn radius(&self) -> f32 {
+        self.bounds().map_or(1.0, |b| (b.max - b.min).mag() / 2.0)
+    }
+
+    pub fn intersect(&self, ray: &Ray) -> Option<(usize, Intersection)> {
+        if let Some(accel) = self.accel.as_ref() {
+            let hit = accel.accelerator().intersect(ray, &self.geometry);
+
+            self.unbounded.iter()
+                .filter_map(|&i| self.geometry[i].hit(ray).map(|h| (i, h)))
+                .fold(hit, |best, (i, h)| match best {
+                    Some((_, b)) if b.dist <= h.dist => best,
+                    _ => Some((i, h))
+                })
         } else {
             self.geometry.iter()
                 .enumerate()
-                .filter_map(|(i, g)| {
-                    g.intersect(ray).map(|d| (i, d))
-                }).min_by(|a, b| {
-                    let res = a.1.partial_cmp(&b.1);
-                    if res.is_none() {
-                        eprintln!("a: {:?} b: {:?}", self.geometry[a.0], self.geometry[b.0]);
-                        eprintln!("ray: {ray:?}");
-                        eprintln!("a: {a:?} b: {b:?}");
-                    }
+                .filter_map(|(i, g)| g.hit(ray).map(|h| (i, h)))
+                .min_by(|a, b| a.1.dist.total_cmp(&b.1.dist))
+        }
+    }
 
-                    res.unwrap()
-                })
+    /// Whether anything blocks the ray before max_dist, cheaper than intersect for shadow rays
+    pub fn occluded(&self, ray: &Ray, max_dist: f32) -> bool {
+        if let Some(accel) = self.accel.as_ref() {
+            self.unbounded.iter().any(|&i| self.geometry[i].hit(ray).is_some_and(|h| h.dist < max_dist))
+                || accel.accelerator().occluded(ray, &self.geometry, max_dist)
+        } else {
+            self.geometry.iter().any(|g| g.hit(ray).is_some_and(|h| h.dist < max_dist))
+        }
+    }
 
-            }
+    /// Describe the surface of geometry id where the ray hit it
+    pub fn hit_record(&self, id: usize, ray: &Ray, hit: &Intersection) -> HitRecord {
+        self.geometry[id].hit_record(ray, hit)
     }
 
-    pub fn shade(&self, id: usize, ray: &Ray, dist: f32, depth: usize) -> Vec3<f32> {
-        let p = ray.origin + ray.dir*dist;
-        let norm = self.geometry[id].normal(p);
+    /// Whether geometry id is registered as a light
+    pub fn is_emitter(&self, id: usize) -> bool {
+        matches!(self.materials[self.material[id]], Material::Emissive(_))
+    }
+
+    pub fn bsdf(&self, id: usize) -> &dyn Bsdf {
+        self.materials[self.material[id]].bsdf()
+    }
 
-        let mat_id = self.material[id];
-        self.materials[mat_id].shade(
-            ray,
-            dist,
-            &norm,
-            self,
-            depth 
-        )
+    /// Radiance arriving along a ray that escapes the scene
+    pub fn background(&self, ray: &Ray) -> Vec3<f32> {
+        self.environment.radiance(&ray.dir)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kdtree::SplitMethod;
+    use crate::shapes::Plane;
+
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    /// Spheres scattered over a ground plane, which no accelerator holds
+    fn world(method: AccelMethod) -> World {
+        let mut rng = StdRng::seed_from_u64(9);
+        let mut w = World::new();
+
+        for _ in 0..100 {
+            let c = Vec3::new(rng.gen_range(-10.0..10.0), rng.gen_range(0.0..10.0), rng.gen_range(-10.0..10.0));
+            w.add_entity(Geometry::new_sphere(c, rng.gen_range(0.2..1.0)), 0);
+        }
+        w.add_entity(Geometry::Plane(Plane::new(Vec3::new(0.0, -2.0, 0.0), Vec3::new(0.0, 1.0, 0.0))), 0);
+
+        w.build_accel(method);
+        w
+    }
+
+    #[test]
+    fn occlusion_agrees_with_the_closest_hit() {
+        let methods = [
+            AccelMethod::Linear,
+            AccelMethod::KdTree(SplitMethod::Midpoint),
+            AccelMethod::KdTree(SplitMethod::default()),
+            AccelMethod::Bvh
+        ];
+
+        for method in methods {
+            let w = world(method);
+            let mut rng = StdRng::seed_from_u64(10);
+
+            for _ in 0..1000 {
+                let origin = Vec3::new(rng.gen_range(-12.0..12.0), rng.gen_range(-1.0..12.0), rng.gen_range(-12.0..12.0));
+                let dir = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+                let ray = Ray::new(origin, dir);
+
+                match w.intersect(&ray) {
+                    Some((_, h)) => {
+                        assert!(w.occluded(&ray, h.dist * 1.001));
+                        assert!(!w.occluded(&ray, h.dist * 0.999));
+                    }
+                    None => assert!(!w.occluded(&ray, f32::INFINITY))
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unbounded_geometry_blocks_shadow_rays() {
+        let w = world(AccelMethod::Bvh);
+        assert_eq!(w.unbounded, [100]);
 
-    pub fn fire(&self, ray: &Ray, depth: usize) -> Vec3<f32> {
-        self.intersect(&ray)
-            .map(|(i, d)| self.shade(i, &ray, d, depth))
-            .unwrap_or(Color::RGB(31, 176, 255))
+        // Straight down through the gap under the spheres onto the plane
+        let ray = Ray::new(Vec3::new(0.0, -1.5, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(w.occluded(&ray, 1.0));
+        assert!(!w.occluded(&ray, 0.25));
+        assert_eq!(w.intersect(&ray).map(|(i, _)| i), Some(100));
     }
 }